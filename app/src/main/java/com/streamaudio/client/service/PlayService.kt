@@ -9,6 +9,7 @@ import android.support.v4.app.NotificationCompat
 import com.streamaudio.client.R
 import com.streamaudio.client.service.rust.RustWrapper
 import com.streamaudio.client.ui.MainActivity
+import java.io.File
 import java.lang.NullPointerException
 
 class PlayService : Service() {
@@ -36,7 +37,7 @@ class PlayService : Service() {
     private var mBinder = LocalBinder()
 
     override fun onCreate() {
-        mRustWrapper = RustWrapper()
+        mRustWrapper = RustWrapper(File(filesDir, RustWrapper.PAIRINGS_FILE_NAME).path)
     }
 
     override fun onStartCommand(intent: Intent?, flags: Int, startId: Int): Int {
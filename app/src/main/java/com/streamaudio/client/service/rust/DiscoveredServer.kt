@@ -0,0 +1,4 @@
+package com.streamaudio.client.service.rust
+
+/** A server that answered a discovery probe, `addr` can be passed to `RustWrapper.play` as is */
+data class DiscoveredServer(val name: String, val addr: String, val codec: String)
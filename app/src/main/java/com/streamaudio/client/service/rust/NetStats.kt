@@ -0,0 +1,20 @@
+package com.streamaudio.client.service.rust
+
+/**
+ * A snapshot of the receive statistics of the current stream.
+ * `received` includes the duplicates, `late` frames arrived after their turn to be played,
+ * `jitterUs` is the interarrival jitter as in RTP, `overflowed` frames were dropped by the full buffer.
+ */
+data class NetStats(
+    val received: Long,
+    val lost: Long,
+    val duplicated: Long,
+    val reordered: Long,
+    val late: Long,
+    val jitterUs: Long,
+    val recovered: Long,
+    val concealed: Long,
+    val overflowed: Long,
+    val bufferedFrames: Long,
+    val bufferBytes: Long
+)
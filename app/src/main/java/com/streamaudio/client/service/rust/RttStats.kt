@@ -0,0 +1,4 @@
+package com.streamaudio.client.service.rust
+
+/** Round-trip time to the server over the recent `samples` echo replies */
+data class RttStats(val minUs: Long, val avgUs: Long, val p95Us: Long, val samples: Int)
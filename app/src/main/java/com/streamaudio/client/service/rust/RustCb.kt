@@ -7,7 +7,29 @@ class RustCb {
         const val TAG: String = "StreamAudio"
     }
 
+    /** Mirrors `net_client::ConnectionState`, the order must be kept in sync */
+    enum class ConnectionState { RESOLVING, REQUESTING_INFO, STARTING, STREAMING, STALLED, DISCONNECTED }
+
+    /** Mirrors `net_client::PairingState`, the order must be kept in sync */
+    enum class PairingState { EXCHANGING_KEYS, WAITING_FOR_PIN, CONFIRMING, PAIRED, FAILED, CANCELLED }
+
     fun onDelayChangedMs(delay: Long) {
         Log.d(TAG, "Delay: $delay")
     }
+
+    fun onConnectionStateChanged(state: Int) {
+        Log.d(TAG, "Connection state: ${ConnectionState.values()[state]}")
+    }
+
+    fun onPairingStateChanged(state: Int) {
+        Log.d(TAG, "Pairing state: ${PairingState.values()[state]}")
+    }
+
+    fun onServersDiscovered(servers: Array<DiscoveredServer>) {
+        Log.d(TAG, "Discovered servers: ${servers.joinToString()}")
+    }
+
+    fun onError(msg: String) {
+        Log.e(TAG, "Error: $msg")
+    }
 }
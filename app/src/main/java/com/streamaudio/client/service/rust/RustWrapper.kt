@@ -1,7 +1,12 @@
 package com.streamaudio.client.service.rust
 
-class RustWrapper {
+/** @param pairingsPath The file the pairing records are kept in, created on the first pairing */
+class RustWrapper(pairingsPath: String) {
     companion object {
+        /** Any local interface, the port is chosen by the system */
+        const val DEFAULT_BIND_ADDR: String = "0.0.0.0:0"
+        const val PAIRINGS_FILE_NAME: String = "pairings.txt"
+
         init {
             System.loadLibrary("avutil")
             System.loadLibrary("swresample")
@@ -16,7 +21,7 @@ class RustWrapper {
     private var rustCb = RustCb()
 
     init {
-        rustObj = createObjectNative(rustCb)
+        rustObj = createObjectNative(rustCb, pairingsPath)
     }
 
     fun destroy() {
@@ -30,7 +35,37 @@ class RustWrapper {
         }
     }
 
-    fun play(addr: String) = playNative(rustObj, addr)
+    /**
+     * With [psk] the stream is encrypted and authenticated, the server must use the same key.
+     * Without it the key from the pairing with [addr] is used, if there is one.
+     * With [multicastInterface] the audio is received from the multicast group announced by
+     * the server, if any. It's empty for the system default, an IPv4 address,
+     * or an interface name or index for IPv6 groups. It's ignored for an encrypted stream.
+     */
+    fun play(
+        addr: String,
+        bindAddr: String = DEFAULT_BIND_ADDR,
+        psk: ByteArray? = null,
+        multicastInterface: String? = null
+    ) = playNative(rustObj, addr, bindAddr, psk, multicastInterface)
+    /**
+     * Plays plain RTP sent to [bindAddr] from [senderAddr], no handshake is done.
+     * [codec] is "aac" (mpeg4-generic, AAC-hbr), [payloadType] is 0..127, [channels] is 1..8.
+     */
+    fun playRtp(
+        senderAddr: String,
+        bindAddr: String,
+        codec: String,
+        payloadType: Int,
+        clockRate: Int,
+        channels: Int
+    ) = playRtpNative(rustObj, senderAddr, bindAddr, codec, payloadType, clockRate, channels)
+    /**
+     * Plays the RTP session described by [sdp], bound to the port of its audio stream.
+     * Only the packets from the IP of [senderAddr] are accepted, the SDP origin is not
+     * the sender: ffmpeg writes 127.0.0.1 there.
+     */
+    fun playSdp(sdp: String, senderAddr: String) = playSdpNative(rustObj, sdp, senderAddr)
     fun stop() = stopNative(rustObj)
     fun isPlaying(): Boolean = isPlayingNative(rustObj)
 
@@ -42,11 +77,44 @@ class RustWrapper {
     fun fixDelayAt(delayMs: Long) = fixDelayAtNative(rustObj, delayMs)
     fun unfixDelay() = unfixDelayNative(rustObj)
 
+    /** Leaves the multicast group for unicast and back without stopping the playback */
+    fun setMulticastEnabled(isEnabled: Boolean) = setMulticastNative(rustObj, isEnabled)
+
+    /** Null if nothing is playing */
+    fun getStats(): NetStats? = getStatsNative(rustObj)
+    /** Null if nothing is playing, all zeroes until the first echo reply */
+    fun getRtt(): RttStats? = getRttNative(rustObj)
+
+    /** The progress is reported to [RustCb.onPairingStateChanged] */
+    fun startPairing(addr: String) = startPairingNative(rustObj, addr)
+    fun enterPairingPin(pin: String) = enterPairingPinNative(rustObj, pin)
+    fun cancelPairing() = cancelPairingNative(rustObj)
+
+    fun startDiscovery() = startDiscoveryNative(rustObj)
+    fun stopDiscovery() = stopDiscoveryNative(rustObj)
+    fun getDiscoveredServers(): Array<DiscoveredServer> = getDiscoveredServersNative(rustObj)
+
     external fun greeting(pattern: String): String
 
-    private external fun createObjectNative(cb: RustCb): Long
+    private external fun createObjectNative(cb: RustCb, pairingsPath: String): Long
     private external fun destroyObjectNative(rustObj: Long)
-    private external fun playNative(rustObj: Long, addr: String)
+    private external fun playNative(
+        rustObj: Long,
+        addr: String,
+        bindAddr: String,
+        psk: ByteArray?,
+        multicastInterface: String?
+    )
+    private external fun playRtpNative(
+        rustObj: Long,
+        senderAddr: String,
+        bindAddr: String,
+        codec: String,
+        payloadType: Int,
+        clockRate: Int,
+        channels: Int
+    )
+    private external fun playSdpNative(rustObj: Long, sdp: String, senderAddr: String)
     private external fun stopNative(rustObj: Long)
     private external fun isPlayingNative(rustObj: Long): Boolean
     private external fun getDelayMsNative(rustObj: Long): Long
@@ -55,4 +123,13 @@ class RustWrapper {
     private external fun isDelayFixedNative(rustObj: Long): Boolean
     private external fun fixDelayAtNative(rustObj: Long, delayMs: Long)
     private external fun unfixDelayNative(rustObj: Long)
+    private external fun setMulticastNative(rustObj: Long, isEnabled: Boolean)
+    private external fun getStatsNative(rustObj: Long): NetStats?
+    private external fun getRttNative(rustObj: Long): RttStats?
+    private external fun startPairingNative(rustObj: Long, addr: String)
+    private external fun enterPairingPinNative(rustObj: Long, pin: String)
+    private external fun cancelPairingNative(rustObj: Long)
+    private external fun startDiscoveryNative(rustObj: Long)
+    private external fun stopDiscoveryNative(rustObj: Long)
+    private external fun getDiscoveredServersNative(rustObj: Long): Array<DiscoveredServer>
 }
@@ -23,6 +23,11 @@ fn main() {
     let manifest_dir =
         env::var("CARGO_MANIFEST_DIR").expect("Cannot get 'CARGO_MANIFEST_DIR' env var");
 
+    if env::var("CARGO_FEATURE_FUZZING").is_ok() && !target.contains("android") {
+        // Fuzz targets are built for the host and link against the system ffmpeg
+        return;
+    }
+
     let dir_name = get_link_dir_name(&target);
 
     let dir_path: path::PathBuf = [&manifest_dir, "..", "jniLibs", dir_name].iter().collect();
@@ -0,0 +1,6 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    audio_sharing_android::fuzzing::decode_rtp_and_write(data);
+});
@@ -0,0 +1,106 @@
+use super::{PlayState, Settings};
+use crate::error::Error;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+
+/// Stands in for OpenSL ES in the tests, which don't run on Android.
+/// Nothing is played: the enqueued audio is dropped, the tests pull the next buffers through
+/// the play callback
+pub struct Engine;
+
+pub struct OutputMix;
+
+pub struct AudioPlayer {
+    play_state: Cell<PlayState>,
+    settings: Settings,
+    callback: PlayCallback,
+}
+
+/// Shared, so that the callback isn't called under the lock of the player
+#[derive(Clone)]
+pub struct PlayCallback(Arc<Mutex<Option<Callback>>>);
+
+type Callback = Box<dyn FnMut(&mut Vec<u8>) -> Result<usize, Error> + Send>;
+
+impl OutputMix {
+    pub fn realize(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Engine {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Engine)
+    }
+
+    pub fn realize(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn create_output_mix(&self) -> Result<OutputMix, Error> {
+        Ok(OutputMix)
+    }
+
+    pub fn create_buffer_player(
+        &self,
+        _mix: &OutputMix,
+        settings: Settings,
+    ) -> Result<AudioPlayer, Error> {
+        Ok(AudioPlayer {
+            play_state: Cell::new(PlayState::Stopped),
+            settings,
+            callback: PlayCallback(Arc::new(Mutex::new(None))),
+        })
+    }
+}
+
+impl AudioPlayer {
+    pub fn realize(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn set_play_state(&self, state: PlayState) -> Result<(), Error> {
+        self.play_state.set(state);
+        Ok(())
+    }
+
+    pub fn get_play_state(&self) -> Result<PlayState, Error> {
+        Ok(self.play_state.get())
+    }
+
+    pub fn get_settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn enqueue(&self, _buf: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn register_callback<F>(&mut self, cb: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut Vec<u8>) -> Result<usize, Error> + Send + 'static,
+    {
+        *self.callback.0.lock()? = Some(Box::new(cb));
+        Ok(())
+    }
+
+    pub fn get_callback(&self) -> PlayCallback {
+        self.callback.clone()
+    }
+}
+
+impl PlayCallback {
+    /// What OpenSL ES does once the enqueued buffer is played: asks the callback for the next.
+    /// Returns the audio to play, empty if there's nothing
+    pub fn pull(&self) -> Result<Vec<u8>, Error> {
+        let mut callback = self.0.lock()?;
+        let callback = callback
+            .as_mut()
+            .ok_or_else(|| Error::new_wrong_argument("No play callback is registered"))?;
+
+        let mut buf = Vec::new();
+        let n = callback(&mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
@@ -39,7 +39,7 @@ fn thread_reader(fd: i32) {
                 warn!(
                     "libc::read returned: {}/{}. Stopping thread.",
                     res,
-                    *libc::__errno()
+                    std::io::Error::last_os_error()
                 );
                 break;
             }
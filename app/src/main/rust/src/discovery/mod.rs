@@ -0,0 +1,441 @@
+use crate::error::Error;
+use crate::jni_ffi::ToJavaMsg;
+use crate::net_client::{Announcement, Codec, ControlMsg, ControlPkt};
+use crate::util::stopper::{StopHandle, Stopper};
+use log::{info, warn};
+use mio::net::UdpSocket;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const UDP_TOKEN: mio::Token = mio::Token(0);
+const STOP_TOKEN: mio::Token = mio::Token(1);
+
+/// Servers listen for probes on this port
+pub const DISCOVERY_PORT: u16 = 25205;
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+/// A server is forgotten if it hasn't answered this many probes in a row
+const MISSED_PROBES_TO_FORGET: u32 = 3;
+/// Announcements of new servers over this qty are ignored, not to grow without bound
+const MAX_SERVERS: usize = 64;
+const LOG_EVERY_IGNORED: u64 = 1000;
+/// The list is pushed to Java at most this often, the changes in between are batched
+const MIN_PUBLISH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Periodically broadcasts `Probe` over the LAN and collects `Announce` replies.
+/// Every change of the server list is pushed to Java.
+pub struct Discovery {
+    servers: Arc<Mutex<Vec<DiscoveredServer>>>,
+    stop_handle: StopHandle,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub codec: Codec,
+}
+
+impl Discovery {
+    pub fn new(to_java_send: mpsc::Sender<ToJavaMsg>) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+        socket.set_broadcast(true)?;
+
+        let poll = mio::Poll::new()?;
+        let (stopper, stop_handle) = Stopper::new();
+
+        poll.register(
+            &socket,
+            UDP_TOKEN,
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )?;
+        poll.register(
+            &stopper,
+            STOP_TOKEN,
+            mio::Ready::readable(),
+            mio::PollOpt::level(),
+        )?;
+
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let discovery_loop = DiscoveryLoop {
+            poll,
+            socket,
+            stopper,
+            to_java_send,
+            servers: HashMap::new(),
+            ignored: 0,
+            shared_servers: servers.clone(),
+            is_publish_pending: false,
+            next_publish: Instant::now(),
+            next_probe: Instant::now(),
+            next_req_id: 1,
+            send_buf: Vec::new(),
+        };
+        let join_handle = thread::spawn(move || discovery_loop.poll_loop());
+
+        Ok(Self {
+            servers,
+            stop_handle,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn get_servers(&self) -> Vec<DiscoveredServer> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.stop_handle.stop()?;
+        if let Some(join_handle) = self.join_handle.take() {
+            let res = join_handle.join();
+            if let Err(_) = res {
+                warn!("Discovery thread panicked");
+            }
+        }
+
+        Ok(())
+    }
+}
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        let res = self.stop();
+        if let Err(e) = res {
+            warn!("Error stopping discovery thread: {}", e);
+        }
+    }
+}
+
+struct DiscoveryLoop {
+    poll: mio::Poll,
+    socket: UdpSocket,
+    stopper: Stopper,
+    to_java_send: mpsc::Sender<ToJavaMsg>,
+    servers: HashMap<SocketAddr, ServerEntry>,
+    /// Announcements of new servers ignored over `MAX_SERVERS`
+    ignored: u64,
+    shared_servers: Arc<Mutex<Vec<DiscoveredServer>>>,
+    /// The list has changed since it was last pushed to Java
+    is_publish_pending: bool,
+    next_publish: Instant,
+    next_probe: Instant,
+    next_req_id: u32,
+    send_buf: Vec<u8>,
+}
+
+struct ServerEntry {
+    server: DiscoveredServer,
+    last_seen: Instant,
+}
+
+impl DiscoveryLoop {
+    fn poll_loop(mut self) {
+        let mut events = mio::Events::with_capacity(64);
+        let mut buf = vec![0; 65536];
+
+        loop {
+            let wake_at = if self.is_publish_pending {
+                std::cmp::min(self.next_probe, self.next_publish)
+            } else {
+                self.next_probe
+            };
+            let timeout = wake_at.saturating_duration_since(Instant::now());
+            log_err!(self.poll.poll(&mut events, Some(timeout)); "polling discovery socket");
+            for event in &events {
+                match event.token() {
+                    UDP_TOKEN => self.receive_data(&mut buf),
+                    STOP_TOKEN => {
+                        if self.stopper.is_stopped() {
+                            return;
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+
+            if Instant::now() >= self.next_probe {
+                self.next_probe = Instant::now() + PROBE_INTERVAL;
+                self.forget_silent_servers();
+                log_and_ignore_err!(self.send_probe());
+            }
+            self.publish_if_due();
+        }
+    }
+
+    fn receive_data(&mut self, buf: &mut [u8]) {
+        loop {
+            let res = self.socket.recv_from(buf);
+            match res {
+                Ok((n, from)) => self.on_datagram(&buf[..n], from),
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        warn!("Error receiving announcement: {}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send_probe(&mut self) -> Result<(), Error> {
+        let req_id = self.next_req_id;
+        self.next_req_id = self.next_req_id.wrapping_add(1);
+
+        ControlPkt::new(req_id, ControlMsg::Probe).encode(&mut self.send_buf);
+        let broadcast = SocketAddr::new(Ipv4Addr::BROADCAST.into(), DISCOVERY_PORT);
+        self.socket.send_to(&self.send_buf, &broadcast)?;
+        Ok(())
+    }
+
+    fn on_datagram(&mut self, datagram: &[u8], from: SocketAddr) {
+        let res = ControlPkt::decode(datagram);
+        match res {
+            Ok(ControlPkt {
+                msg: ControlMsg::Announce(announcement),
+                ..
+            }) => self.on_announcement(announcement, from),
+            Ok(pkt) => warn!("Unexpected discovery message: {:?}", pkt),
+            Err(e) => warn!("Error parsing announcement from {}: {}", from, e),
+        }
+    }
+
+    fn on_announcement(&mut self, announcement: Announcement, from: SocketAddr) {
+        let ip = announcement.ip.unwrap_or_else(|| from.ip());
+        let server = DiscoveredServer {
+            name: announcement.name,
+            addr: SocketAddr::new(ip, announcement.port),
+            codec: announcement.codec,
+        };
+
+        if self.servers.len() >= MAX_SERVERS && !self.servers.contains_key(&server.addr) {
+            self.ignored += 1;
+            if self.ignored % LOG_EVERY_IGNORED == 1 {
+                warn!(
+                    "Ignored {} announcements over {} servers, the last from {}",
+                    self.ignored, MAX_SERVERS, from
+                );
+            }
+            return;
+        }
+
+        let entry = ServerEntry {
+            server: server.clone(),
+            last_seen: Instant::now(),
+        };
+        let prev = self.servers.insert(server.addr, entry);
+        let is_changed = match prev {
+            Some(prev) => prev.server != server,
+            None => true,
+        };
+
+        if is_changed {
+            info!("Discovered server: {:?}", server);
+            self.is_publish_pending = true;
+        }
+    }
+
+    fn forget_silent_servers(&mut self) {
+        let max_silence = PROBE_INTERVAL * MISSED_PROBES_TO_FORGET;
+        let before = self.servers.len();
+        self.servers
+            .retain(|_, entry| entry.last_seen.elapsed() < max_silence);
+
+        if self.servers.len() != before {
+            info!("{} servers are gone", before - self.servers.len());
+            self.is_publish_pending = true;
+        }
+    }
+
+    fn publish_if_due(&mut self) {
+        let now = Instant::now();
+        if !self.is_publish_pending || now < self.next_publish {
+            return;
+        }
+
+        self.is_publish_pending = false;
+        self.next_publish = now + MIN_PUBLISH_INTERVAL;
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let mut servers: Vec<DiscoveredServer> =
+            self.servers.values().map(|e| e.server.clone()).collect();
+        servers.sort_by(|a, b| a.name.cmp(&b.name).then(a.addr.cmp(&b.addr)));
+
+        *self.shared_servers.lock().unwrap() = servers.clone();
+        log_and_ignore_err!(self
+            .to_java_send
+            .send(ToJavaMsg::ServersDiscovered(servers)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_loop() -> (DiscoveryLoop, mpsc::Receiver<ToJavaMsg>) {
+        let (to_java_send, to_java_recv) = mpsc::channel();
+        let (stopper, _) = Stopper::new();
+        let discovery_loop = DiscoveryLoop {
+            poll: mio::Poll::new().unwrap(),
+            socket: UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
+            stopper,
+            to_java_send,
+            servers: HashMap::new(),
+            ignored: 0,
+            shared_servers: Arc::new(Mutex::new(Vec::new())),
+            is_publish_pending: false,
+            next_publish: Instant::now(),
+            next_probe: Instant::now(),
+            next_req_id: 1,
+            send_buf: Vec::new(),
+        };
+        (discovery_loop, to_java_recv)
+    }
+
+    fn announce(name: &str, ip: Option<&str>, port: u16) -> Vec<u8> {
+        let announcement = Announcement {
+            name: name.to_owned(),
+            ip: ip.map(|ip| ip.parse().unwrap()),
+            port,
+            codec: Codec::Aac,
+        };
+        let mut buf = Vec::new();
+        ControlPkt::new(1, ControlMsg::Announce(announcement)).encode(&mut buf);
+        buf
+    }
+
+    fn published(to_java_recv: &mpsc::Receiver<ToJavaMsg>) -> Option<Vec<DiscoveredServer>> {
+        match to_java_recv.try_recv() {
+            Ok(ToJavaMsg::ServersDiscovered(servers)) => Some(servers),
+            Ok(_) => panic!("Unexpected message to Java"),
+            Err(_) => None,
+        }
+    }
+
+    fn from(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn publishes_announced_servers_sorted_by_name() {
+        let (mut discovery_loop, to_java_recv) = new_loop();
+
+        discovery_loop.on_datagram(&announce("Kitchen", None, 25204), from("192.168.1.5:25205"));
+        discovery_loop.on_datagram(
+            &announce("Bedroom", Some("fe80::1"), 25204),
+            from("192.168.1.6:25205"),
+        );
+        discovery_loop.publish_if_due();
+
+        let servers = published(&to_java_recv).unwrap();
+        let addrs: Vec<SocketAddr> = servers.iter().map(|s| s.addr).collect();
+        assert_eq!(
+            addrs,
+            vec![from("[fe80::1]:25204"), from("192.168.1.5:25204")]
+        );
+        assert_eq!(servers[0].name, "Bedroom");
+        assert_eq!(*discovery_loop.shared_servers.lock().unwrap(), servers);
+    }
+
+    #[test]
+    fn ignores_probes_and_malformed_datagrams() {
+        let (mut discovery_loop, to_java_recv) = new_loop();
+
+        let mut probe = Vec::new();
+        ControlPkt::new(1, ControlMsg::Probe).encode(&mut probe);
+        discovery_loop.on_datagram(&probe, from("192.168.1.5:40000"));
+
+        let announcement = announce("Kitchen", None, 25204);
+        discovery_loop.on_datagram(
+            &announcement[..announcement.len() - 1],
+            from("192.168.1.5:25205"),
+        );
+        discovery_loop.on_datagram(b"garbage", from("192.168.1.5:25205"));
+
+        discovery_loop.publish_if_due();
+        assert!(discovery_loop.servers.is_empty());
+        assert!(published(&to_java_recv).is_none());
+    }
+
+    #[test]
+    fn repeated_announcement_is_not_published_again() {
+        let (mut discovery_loop, to_java_recv) = new_loop();
+        let announcement = announce("Kitchen", None, 25204);
+
+        discovery_loop.on_datagram(&announcement, from("192.168.1.5:25205"));
+        discovery_loop.publish_if_due();
+        assert!(published(&to_java_recv).is_some());
+
+        discovery_loop.next_publish = Instant::now();
+        discovery_loop.on_datagram(&announcement, from("192.168.1.5:25205"));
+        discovery_loop.publish_if_due();
+        assert!(published(&to_java_recv).is_none());
+    }
+
+    #[test]
+    fn changes_are_published_once_per_interval() {
+        let (mut discovery_loop, to_java_recv) = new_loop();
+
+        discovery_loop.on_datagram(&announce("Kitchen", None, 25204), from("192.168.1.5:25205"));
+        discovery_loop.publish_if_due();
+        assert_eq!(published(&to_java_recv).unwrap().len(), 1);
+
+        for port in 1..=10 {
+            discovery_loop.on_datagram(&announce("Other", None, port), from("192.168.1.6:25205"));
+            discovery_loop.publish_if_due();
+        }
+        assert!(published(&to_java_recv).is_none());
+
+        discovery_loop.next_publish = Instant::now();
+        discovery_loop.publish_if_due();
+        assert_eq!(published(&to_java_recv).unwrap().len(), 11);
+        assert!(published(&to_java_recv).is_none());
+    }
+
+    #[test]
+    fn forgets_silent_servers() {
+        let (mut discovery_loop, to_java_recv) = new_loop();
+        discovery_loop.on_datagram(&announce("Kitchen", None, 25204), from("192.168.1.5:25205"));
+        discovery_loop.on_datagram(&announce("Bedroom", None, 25204), from("192.168.1.6:25205"));
+        discovery_loop.publish_if_due();
+        assert_eq!(published(&to_java_recv).unwrap().len(), 2);
+
+        discovery_loop.forget_silent_servers();
+        assert_eq!(discovery_loop.servers.len(), 2);
+
+        let max_silence = PROBE_INTERVAL * MISSED_PROBES_TO_FORGET;
+        let entry = discovery_loop
+            .servers
+            .get_mut(&from("192.168.1.5:25204"))
+            .unwrap();
+        entry.last_seen = Instant::now().checked_sub(max_silence).unwrap();
+        discovery_loop.forget_silent_servers();
+
+        discovery_loop.next_publish = Instant::now();
+        discovery_loop.publish_if_due();
+        let servers = published(&to_java_recv).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].name, "Bedroom");
+    }
+
+    #[test]
+    fn new_servers_over_max_are_ignored() {
+        let (mut discovery_loop, _to_java_recv) = new_loop();
+
+        for port in 0..MAX_SERVERS as u16 + 10 {
+            discovery_loop.on_datagram(&announce("Flood", None, port), from("192.168.1.5:25205"));
+        }
+        assert_eq!(discovery_loop.servers.len(), MAX_SERVERS);
+        assert_eq!(discovery_loop.ignored, 10);
+
+        // The known ones are still updated
+        discovery_loop.on_datagram(&announce("Renamed", None, 0), from("192.168.1.5:25205"));
+        let entry = &discovery_loop.servers[&from("192.168.1.5:0")];
+        assert_eq!(entry.server.name, "Renamed");
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(not(test))]
 use crate::android_audio::SlError;
 use crate::ffmpeg;
 use jni::errors::Error as JniError;
@@ -5,6 +6,7 @@ use std::borrow::Cow;
 use std::fmt;
 use std::net::AddrParseError;
 use std::sync::PoisonError;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Error {
@@ -12,7 +14,6 @@ pub struct Error {
 }
 
 impl Error {
-    #[allow(dead_code)]
     pub fn new_wrong_argument<S: Into<Cow<'static, str>>>(description: S) -> Self {
         Error {
             repr: Box::new(ErrorRepr::WrongArgument(description.into())),
@@ -31,7 +32,6 @@ impl Error {
         }
     }
 
-    #[allow(dead_code)]
     pub fn new_io<S: Into<Cow<'static, str>>>(e: std::io::Error, f_name: S) -> Self {
         Error {
             repr: Box::new(ErrorRepr::Io((e, f_name.into()))),
@@ -46,6 +46,45 @@ impl Error {
             repr: Box::new(ErrorRepr::NetParse((e, addr.into()))),
         }
     }
+
+    pub fn new_resolve<H>(e: std::io::Error, host: H) -> Self
+    where
+        H: Into<Cow<'static, str>>,
+    {
+        Error {
+            repr: Box::new(ErrorRepr::Resolve((e, host.into()))),
+        }
+    }
+
+    pub fn new_malformed_pkt<S: Into<Cow<'static, str>>>(descr: S) -> Self {
+        Error {
+            repr: Box::new(ErrorRepr::MalformedPkt(descr.into())),
+        }
+    }
+
+    pub fn new_unsupported_version(version: u8) -> Self {
+        Error {
+            repr: Box::new(ErrorRepr::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn new_unknown_codec(codec_id: u8) -> Self {
+        Error {
+            repr: Box::new(ErrorRepr::UnknownCodec(codec_id)),
+        }
+    }
+
+    pub fn new_crypto<S: Into<Cow<'static, str>>>(descr: S) -> Self {
+        Error {
+            repr: Box::new(ErrorRepr::Crypto(descr.into())),
+        }
+    }
+
+    pub fn new_wrong_frame_duration(duration: Duration) -> Self {
+        Error {
+            repr: Box::new(ErrorRepr::WrongFrameDuration(duration)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -54,8 +93,16 @@ pub enum ErrorRepr {
     WrongState(Cow<'static, str>),
     NullPointer(Cow<'static, str>),
     Io((std::io::Error, Cow<'static, str>)),
+    #[cfg(not(test))]
     SlError(SlError),
     NetParse((AddrParseError, Cow<'static, str>)),
+    Resolve((std::io::Error, Cow<'static, str>)),
+    MalformedPkt(Cow<'static, str>),
+    UnsupportedVersion(u8),
+    UnknownCodec(u8),
+    Crypto(Cow<'static, str>),
+    /// The audio frames of the stream are too short or too long to be played
+    WrongFrameDuration(Duration),
     LockPoison(String),
     Ffmpeg(ffmpeg::Error),
     Jni(JniError),
@@ -74,8 +121,17 @@ impl fmt::Display for Error {
                 }
                 Ok(())
             }
+            #[cfg(not(test))]
             ErrorRepr::SlError(e) => e.fmt(f),
             ErrorRepr::NetParse((e, addr)) => write!(f, "{} of {}", e, addr),
+            ErrorRepr::Resolve((e, host)) => write!(f, "Cannot resolve {}: {}", host, e),
+            ErrorRepr::MalformedPkt(s) => write!(f, "Malformed packet: {}", s),
+            ErrorRepr::UnsupportedVersion(v) => write!(f, "Unsupported protocol version: {}", v),
+            ErrorRepr::UnknownCodec(id) => write!(f, "Unknown codec id: {}", id),
+            ErrorRepr::Crypto(s) => write!(f, "Crypto error: {}", s),
+            ErrorRepr::WrongFrameDuration(d) => {
+                write!(f, "Unsupported frame duration: {} us", d.as_micros())
+            }
             ErrorRepr::LockPoison(descr) => write!(f, "{}", descr),
             ErrorRepr::Ffmpeg(e) => e.fmt(f),
             ErrorRepr::Jni(e) => e.fmt(f),
@@ -92,6 +148,7 @@ impl From<std::io::Error> for Error {
         }
     }
 }
+#[cfg(not(test))]
 impl From<SlError> for Error {
     fn from(e: SlError) -> Self {
         Self {
@@ -0,0 +1,62 @@
+//! Entry points for the targets in the `fuzz` directory.
+//! Built only with the `fuzzing` feature, run with `cargo fuzz run pkt_decoder` from `fuzz`.
+//! The corpus of each target keeps the captured packets used as its regression tests.
+
+use crate::android_audio;
+use crate::net_client::{PktDecoder, RtpParams, StreamInfo, FEATURE_REDUNDANCY};
+use crate::player::{OutputBuffer, PostWriteAction};
+use std::sync::mpsc;
+
+/// Splits `data` into datagrams, each prefixed by its big-endian u16 length,
+/// and feeds them through `PktDecoder` into `OutputBuffer` the same way `PollLoop` does.
+/// A frame is read after every datagram, as if the player called back at the packet rate,
+/// so the decoder and the recovered frames are exercised too.
+pub fn decode_and_write(data: &[u8]) {
+    let info = StreamInfo {
+        features: FEATURE_REDUNDANCY,
+        ..StreamInfo::default()
+    };
+    let mut pkt_decoder = PktDecoder::new();
+    pkt_decoder.configure(&info);
+
+    write_datagrams(pkt_decoder, &info, data);
+}
+
+/// The same as `decode_and_write` for an mpeg4-generic RTP session with payload type 96,
+/// the way ffmpeg and GStreamer send AAC
+pub fn decode_rtp_and_write(data: &[u8]) {
+    let params = RtpParams::new_aac_hbr(96, 44100, 2);
+    let pkt_decoder = PktDecoder::new_rtp(params.clone()).unwrap();
+
+    write_datagrams(pkt_decoder, &params.stream_info(), data);
+}
+
+fn write_datagrams(mut pkt_decoder: PktDecoder, info: &StreamInfo, data: &[u8]) {
+    let (to_java_send, _to_java_recv) = mpsc::channel();
+    let settings = android_audio::Settings {
+        rate: android_audio::SampleRate::Rate44100,
+        format: android_audio::SampleFormat::S16LE,
+        channels: 2,
+    };
+    let mut buffer = OutputBuffer::new(to_java_send, settings, info).unwrap();
+    let mut pkts = Vec::new();
+    let mut decoded = Vec::new();
+
+    let mut rest = data;
+    while rest.len() >= 2 {
+        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let end = std::cmp::min(2 + len, rest.len());
+        let datagram = &rest[2..end];
+        rest = &rest[end..];
+
+        if let Ok(()) = pkt_decoder.parse(datagram, &mut pkts) {
+            for pkt in &pkts {
+                // `Player::enqueue` reads right away to start the playback
+                if let PostWriteAction::Read = buffer.write(pkt) {
+                    let _ = buffer.read(&mut decoded);
+                }
+            }
+        }
+        let _ = buffer.read(&mut decoded);
+    }
+}
@@ -1,16 +1,19 @@
-use super::to_java::{java_callback_loop, ToJavaMsg};
+use super::to_java::{self, java_callback_loop, ToJavaMsg};
 use crate::android_helper;
+use crate::discovery::Discovery;
 use crate::error::{Error, ErrorRepr};
-use crate::net_client;
+use crate::net_client::{self, Pairing, PairingStore};
 use crate::player::Player;
 use crate::rust_greeting;
-use jni::objects::{JClass, JObject, JString};
-use jni::sys::jstring;
+use jni::objects::{GlobalRef, JClass, JObject, JString};
+use jni::sys::{jbyteArray, jobject, jobjectArray, jstring};
 use jni::{JNIEnv, JavaVM};
 use log::{error, info, trace};
 use std::ffi::c_void;
 use std::mem::drop;
-use std::sync::mpsc;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
@@ -38,6 +41,10 @@ macro_rules! throw_on_err {
 struct RustObj {
     net_client: Option<net_client::NetClient>,
     player: Option<Player>,
+    discovery: Option<Discovery>,
+    pairing: Option<Pairing>,
+    pairings: Arc<Mutex<PairingStore>>,
+    server_cls: GlobalRef,
     java_cb_send: mpsc::Sender<ToJavaMsg>,
     java_cb_thread: Option<JoinHandle<()>>,
 }
@@ -69,11 +76,14 @@ extern "C" fn greeting(env: JNIEnv, _: JClass, java_pattern: JString) -> jstring
     output.into_inner()
 }
 
-extern "C" fn create_object(env: JNIEnv, _: JClass, cb: JObject) -> i64 {
+extern "C" fn create_object(env: JNIEnv, _: JClass, cb: JObject, pairings_path: JString) -> i64 {
     info!("createObject is called");
     println!("STDCOUT");
 
-    let rust_obj = Box::new(throw_on_err!(RustObj::new(&env, cb), env, 0));
+    let pairings_path: String = env.get_string(pairings_path).unwrap().into();
+    let pairings = throw_on_err!(PairingStore::open(PathBuf::from(pairings_path)), env, 0);
+
+    let rust_obj = Box::new(throw_on_err!(RustObj::new(&env, cb, pairings), env, 0));
     RustObj::boxed_into_raw(rust_obj)
 }
 
@@ -82,35 +92,147 @@ extern "C" fn destroy_object(_env: JNIEnv, _: JClass, rust_obj: i64) {
     drop(RustObj::from_raw_box(rust_obj));
 }
 
-extern "C" fn play(env: JNIEnv, _: JClass, rust_obj: i64, remote_addr: JString) {
+/// `multicast_interface` is null to always receive unicast
+extern "C" fn play(
+    env: JNIEnv,
+    _: JClass,
+    rust_obj: i64,
+    remote_addr: JString,
+    bind_addr: JString,
+    psk: jbyteArray,
+    multicast_interface: JString,
+) {
     info!("Play is called");
 
     let remote_addr: String = env.get_string(remote_addr).unwrap().into();
+    let bind_addr: String = env.get_string(bind_addr).unwrap().into();
+    let psk = if psk.is_null() {
+        None
+    } else {
+        Some(throw_on_err!(
+            env.convert_byte_array(psk).map_err(Error::from),
+            env
+        ))
+    };
+
+    let multicast = if multicast_interface.is_null() {
+        None
+    } else {
+        let interface: String = env.get_string(multicast_interface).unwrap().into();
+        Some(throw_on_err!(
+            net_client::MulticastConfig::parse(&interface),
+            env
+        ))
+    };
 
-    let remote_addr: std::net::SocketAddr = throw_on_err!(
-        remote_addr
-            .parse()
-            .map_err(|e| Error::new_net_parse(e, remote_addr)),
+    let bind_addr = throw_on_err!(net_client::parse_socket_addr(&bind_addr), env);
+
+    let rust_obj: &mut RustObj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+    let config = net_client::Config {
+        multicast,
+        ..net_client::Config::default()
+    };
+    throw_on_err!(
+        rust_obj.play(
+            remote_addr,
+            bind_addr,
+            psk.as_ref().map(|psk| psk.as_slice()),
+            config
+        ),
+        env
+    );
+}
+
+/// Receives plain RTP from `sender_addr` on `bind_addr`, `codec` is "aac" (mpeg4-generic, AAC-hbr)
+extern "C" fn play_rtp(
+    env: JNIEnv,
+    _: JClass,
+    rust_obj: i64,
+    sender_addr: JString,
+    bind_addr: JString,
+    codec: JString,
+    payload_type: i32,
+    clock_rate: i32,
+    channels: i32,
+) {
+    info!("Play RTP is called");
+
+    let sender_addr: String = env.get_string(sender_addr).unwrap().into();
+    let bind_addr: String = env.get_string(bind_addr).unwrap().into();
+    let codec: String = env.get_string(codec).unwrap().into();
+
+    let bind_addr = throw_on_err!(net_client::parse_socket_addr(&bind_addr), env);
+    let params = throw_on_err!(
+        new_rtp_params(&codec, payload_type, clock_rate, channels),
         env
     );
 
     let rust_obj: &mut RustObj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+    let config = net_client::Config {
+        rtp: Some(params),
+        ..net_client::Config::default()
+    };
+    throw_on_err!(rust_obj.play(sender_addr, bind_addr, None, config), env);
+}
 
-    rust_obj.net_client.take();
+/// The `jint`s are checked before the casts, a value out of range would silently wrap
+fn new_rtp_params(
+    codec: &str,
+    payload_type: i32,
+    clock_rate: i32,
+    channels: i32,
+) -> Result<net_client::RtpParams, Error> {
+    if codec != "aac" {
+        return Err(Error::new_wrong_argument(format!(
+            "Unknown RTP codec: {}",
+            codec
+        )));
+    }
+    if !(0..=127).contains(&payload_type) {
+        return Err(Error::new_wrong_argument(format!(
+            "Wrong RTP payload type: {}",
+            payload_type
+        )));
+    }
+    if clock_rate <= 0 {
+        return Err(Error::new_wrong_argument(format!(
+            "Wrong RTP clock rate: {}",
+            clock_rate
+        )));
+    }
+    if !(1..=8).contains(&channels) {
+        return Err(Error::new_wrong_argument(format!(
+            "Wrong channel count: {}",
+            channels
+        )));
+    }
 
-    let player = throw_on_err!(Player::new(rust_obj.java_cb_send.clone()), env);
-    let net_client = throw_on_err!(
-        net_client::NetClient::new(
-            remote_addr,
-            "0.0.0.0:25204".parse().unwrap(),
-            player.clone(),
-            rust_obj.java_cb_send.clone()
-        ),
+    Ok(net_client::RtpParams::new_aac_hbr(
+        payload_type as u8,
+        clock_rate as u32,
+        channels as u8,
+    ))
+}
+
+/// `sender_addr` is required, the SDP origin is not the sender
+extern "C" fn play_sdp(env: JNIEnv, _: JClass, rust_obj: i64, sdp: JString, sender_addr: JString) {
+    info!("Play SDP is called");
+
+    let sdp: String = env.get_string(sdp).unwrap().into();
+    let sdp = throw_on_err!(net_client::SessionDescription::parse(&sdp), env);
+    info!("Session description: {:?}", sdp);
+
+    let sender_addr: String = env.get_string(sender_addr).unwrap().into();
+
+    let rust_obj: &mut RustObj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+    let config = net_client::Config {
+        rtp: Some(sdp.rtp.clone()),
+        ..net_client::Config::default()
+    };
+    throw_on_err!(
+        rust_obj.play(sender_addr, sdp.local_addr(), None, config),
         env
     );
-
-    rust_obj.player = Some(player);
-    rust_obj.net_client = Some(net_client);
 }
 
 extern "C" fn stop(env: JNIEnv, _: JClass, rust_obj: i64) {
@@ -177,6 +299,129 @@ extern "C" fn unfix_delay(env: JNIEnv, _: JClass, rust_obj: i64) {
     player.unfix_delay();
 }
 
+/// Switches between the multicast group and unicast while playing
+extern "C" fn set_multicast(env: JNIEnv, _: JClass, rust_obj: i64, is_enabled: bool) {
+    info!("Set multicast is called: {}", is_enabled);
+
+    let rust_obj = throw_on_err!(RustObj::from_raw_ref(rust_obj), env);
+    let net_client = throw_on_err!(
+        rust_obj
+            .net_client
+            .as_ref()
+            .ok_or_else(|| Error::new_wrong_state("Nothing is playing")),
+        env
+    );
+    throw_on_err!(net_client.set_multicast_enabled(is_enabled), env);
+}
+
+/// Returns null if nothing is playing
+extern "C" fn get_stats(env: JNIEnv, _: JClass, rust_obj: i64) -> jobject {
+    let null = JObject::null().into_inner();
+    let rust_obj = throw_on_err!(RustObj::from_raw_ref(rust_obj), env, null);
+
+    let (net_client, player) = match (&rust_obj.net_client, &rust_obj.player) {
+        (Some(net_client), Some(player)) => (net_client, player),
+        _ => {
+            return null;
+        }
+    };
+    throw_on_err!(
+        to_java::new_stats_object(&env, &net_client.get_stats(), &player.get_stats()),
+        env,
+        null
+    )
+}
+
+/// Returns null if nothing is playing
+extern "C" fn get_rtt(env: JNIEnv, _: JClass, rust_obj: i64) -> jobject {
+    let null = JObject::null().into_inner();
+    let rust_obj = throw_on_err!(RustObj::from_raw_ref(rust_obj), env, null);
+
+    let net_client = match &rust_obj.net_client {
+        Some(net_client) => net_client,
+        None => {
+            return null;
+        }
+    };
+    throw_on_err!(
+        to_java::new_rtt_object(&env, &net_client.get_rtt()),
+        env,
+        null
+    )
+}
+
+extern "C" fn start_pairing(env: JNIEnv, _: JClass, rust_obj: i64, server: JString) {
+    info!("Start pairing is called");
+
+    let server: String = env.get_string(server).unwrap().into();
+    let rust_obj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+
+    // Cancels the previous pairing if any
+    rust_obj.pairing.take();
+    rust_obj.pairing = Some(Pairing::start(
+        server,
+        rust_obj.pairings.clone(),
+        rust_obj.java_cb_send.clone(),
+    ));
+}
+
+extern "C" fn enter_pairing_pin(env: JNIEnv, _: JClass, rust_obj: i64, pin: JString) {
+    let pin: String = env.get_string(pin).unwrap().into();
+    let rust_obj = throw_on_err!(RustObj::from_raw_ref(rust_obj), env);
+
+    let pairing = throw_on_err!(
+        rust_obj
+            .pairing
+            .as_ref()
+            .ok_or_else(|| Error::new_wrong_state("Pairing is not started")),
+        env
+    );
+    throw_on_err!(pairing.enter_pin(pin), env);
+}
+
+extern "C" fn cancel_pairing(env: JNIEnv, _: JClass, rust_obj: i64) {
+    info!("Cancel pairing is called");
+
+    let rust_obj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+    drop(rust_obj.pairing.take());
+}
+
+extern "C" fn start_discovery(env: JNIEnv, _: JClass, rust_obj: i64) {
+    info!("Start discovery is called");
+
+    let rust_obj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+
+    if rust_obj.discovery.is_none() {
+        let discovery = throw_on_err!(Discovery::new(rust_obj.java_cb_send.clone()), env);
+        rust_obj.discovery = Some(discovery);
+    }
+}
+
+extern "C" fn stop_discovery(env: JNIEnv, _: JClass, rust_obj: i64) {
+    info!("Stop discovery is called");
+
+    let rust_obj = throw_on_err!(RustObj::from_raw_mut(rust_obj), env);
+
+    if let Some(mut discovery) = rust_obj.discovery.take() {
+        throw_on_err!(discovery.stop(), env);
+    }
+}
+
+extern "C" fn get_discovered_servers(env: JNIEnv, _: JClass, rust_obj: i64) -> jobjectArray {
+    let null = JObject::null().into_inner();
+    let rust_obj = throw_on_err!(RustObj::from_raw_ref(rust_obj), env, null);
+
+    let servers = rust_obj
+        .discovery
+        .as_ref()
+        .map_or_else(Vec::new, |d| d.get_servers());
+    throw_on_err!(
+        to_java::new_server_array(&env, &rust_obj.server_cls, &servers),
+        env,
+        null
+    )
+}
+
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "C" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut c_void) -> i32 {
@@ -213,7 +458,8 @@ fn register_methods(env: JNIEnv) -> Result<(), Error> {
         },
         jni::sys::JNINativeMethod {
             name: b"createObjectNative\0".as_ptr() as _,
-            signature: b"(Lcom/streamaudio/client/service/rust/RustCb;)J\0".as_ptr() as _,
+            signature: b"(Lcom/streamaudio/client/service/rust/RustCb;Ljava/lang/String;)J\0"
+                .as_ptr() as _,
             fnPtr: create_object as *mut c_void,
         },
         jni::sys::JNINativeMethod {
@@ -223,9 +469,21 @@ fn register_methods(env: JNIEnv) -> Result<(), Error> {
         },
         jni::sys::JNINativeMethod {
             name: b"playNative\0".as_ptr() as _,
-            signature: b"(JLjava/lang/String;)V\0".as_ptr() as _,
+            signature: b"(JLjava/lang/String;Ljava/lang/String;[BLjava/lang/String;)V\0".as_ptr()
+                as _,
             fnPtr: play as *mut c_void,
         },
+        jni::sys::JNINativeMethod {
+            name: b"playRtpNative\0".as_ptr() as _,
+            signature: b"(JLjava/lang/String;Ljava/lang/String;Ljava/lang/String;III)V\0".as_ptr()
+                as _,
+            fnPtr: play_rtp as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"playSdpNative\0".as_ptr() as _,
+            signature: b"(JLjava/lang/String;Ljava/lang/String;)V\0".as_ptr() as _,
+            fnPtr: play_sdp as *mut c_void,
+        },
         jni::sys::JNINativeMethod {
             name: b"stopNative\0".as_ptr() as _,
             signature: b"(J)V\0".as_ptr() as _,
@@ -256,6 +514,11 @@ fn register_methods(env: JNIEnv) -> Result<(), Error> {
             signature: b"(J)Z\0".as_ptr() as _,
             fnPtr: is_delay_fixed as *mut c_void,
         },
+        jni::sys::JNINativeMethod {
+            name: b"setMulticastNative\0".as_ptr() as _,
+            signature: b"(JZ)V\0".as_ptr() as _,
+            fnPtr: set_multicast as *mut c_void,
+        },
         jni::sys::JNINativeMethod {
             name: b"fixDelayAtNative\0".as_ptr() as _,
             signature: b"(JJ)V\0".as_ptr() as _,
@@ -266,6 +529,47 @@ fn register_methods(env: JNIEnv) -> Result<(), Error> {
             signature: b"(J)V\0".as_ptr() as _,
             fnPtr: unfix_delay as *mut c_void,
         },
+        jni::sys::JNINativeMethod {
+            name: b"getStatsNative\0".as_ptr() as _,
+            signature: b"(J)Lcom/streamaudio/client/service/rust/NetStats;\0".as_ptr() as _,
+            fnPtr: get_stats as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"getRttNative\0".as_ptr() as _,
+            signature: b"(J)Lcom/streamaudio/client/service/rust/RttStats;\0".as_ptr() as _,
+            fnPtr: get_rtt as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"startPairingNative\0".as_ptr() as _,
+            signature: b"(JLjava/lang/String;)V\0".as_ptr() as _,
+            fnPtr: start_pairing as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"enterPairingPinNative\0".as_ptr() as _,
+            signature: b"(JLjava/lang/String;)V\0".as_ptr() as _,
+            fnPtr: enter_pairing_pin as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"cancelPairingNative\0".as_ptr() as _,
+            signature: b"(J)V\0".as_ptr() as _,
+            fnPtr: cancel_pairing as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"startDiscoveryNative\0".as_ptr() as _,
+            signature: b"(J)V\0".as_ptr() as _,
+            fnPtr: start_discovery as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"stopDiscoveryNative\0".as_ptr() as _,
+            signature: b"(J)V\0".as_ptr() as _,
+            fnPtr: stop_discovery as *mut c_void,
+        },
+        jni::sys::JNINativeMethod {
+            name: b"getDiscoveredServersNative\0".as_ptr() as _,
+            signature: b"(J)[Lcom/streamaudio/client/service/rust/DiscoveredServer;\0".as_ptr()
+                as _,
+            fnPtr: get_discovered_servers as *mut c_void,
+        },
     ];
 
     let res = jni_non_void_call!(
@@ -284,15 +588,22 @@ fn register_methods(env: JNIEnv) -> Result<(), Error> {
 }
 
 impl RustObj {
-    fn new(env: &JNIEnv, cb: JObject) -> Result<Self, Error> {
+    fn new(env: &JNIEnv, cb: JObject, pairings: PairingStore) -> Result<Self, Error> {
         let vm = env.get_java_vm()?;
         let cb = env.new_global_ref(cb)?;
+        let server_cls = env.find_class(to_java::DISCOVERED_SERVER_CLASS)?;
+        let server_cls = env.new_global_ref(server_cls.into())?;
 
         let (send, recv) = mpsc::channel();
-        let thread = thread::spawn(move || java_callback_loop(vm, cb, recv));
+        let cb_server_cls = server_cls.clone();
+        let thread = thread::spawn(move || java_callback_loop(vm, cb, cb_server_cls, recv));
         Ok(Self {
             net_client: None,
             player: None,
+            discovery: None,
+            pairing: None,
+            pairings: Arc::new(Mutex::new(pairings)),
+            server_cls,
             java_cb_send: send,
             java_cb_thread: Some(thread),
         })
@@ -321,6 +632,8 @@ impl RustObj {
     }
 
     fn stop(&mut self) {
+        drop(self.pairing.take());
+        drop(self.discovery.take());
         log_and_ignore_err!(self.java_cb_send.send(ToJavaMsg::Stop));
         if let Some(thread) = self.java_cb_thread.take() {
             let res = thread.join();
@@ -330,6 +643,33 @@ impl RustObj {
         }
     }
 
+    fn play(
+        &mut self,
+        remote_addr: String,
+        bind_addr: SocketAddr,
+        psk: Option<&[u8]>,
+        config: net_client::Config,
+    ) -> Result<(), Error> {
+        self.net_client.take();
+
+        let player = Player::new(self.java_cb_send.clone())?;
+        let pairings = self.pairings.lock()?;
+        let net_client = net_client::NetClient::new(
+            remote_addr,
+            bind_addr,
+            psk,
+            &pairings,
+            config,
+            player.clone(),
+            self.java_cb_send.clone(),
+        )?;
+        drop(pairings);
+
+        self.player = Some(player);
+        self.net_client = Some(net_client);
+        Ok(())
+    }
+
     fn get_player(&self) -> Result<&Player, Error> {
         self.player
             .as_ref()
@@ -1,5 +1,10 @@
+use crate::discovery::DiscoveredServer;
 use crate::error::Error;
-use jni::{objects::GlobalRef, JNIEnv, JavaVM};
+use crate::net_client::{ConnectionState, PairingState, ReceiveStatsSnapshot, RttSnapshot};
+use crate::player::BufferStats;
+use jni::objects::{GlobalRef, JClass, JObject};
+use jni::sys::{jobject, jobjectArray};
+use jni::{JNIEnv, JavaVM};
 use log::error;
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
@@ -7,13 +12,28 @@ use std::time::{Duration, Instant};
 pub enum ToJavaMsg {
     Error(Error),
     BufferSizeChanged(Duration),
+    ConnectionStateChanged(ConnectionState),
+    ServersDiscovered(Vec<DiscoveredServer>),
+    PairingStateChanged(PairingState),
     Stop,
 }
 
-pub fn java_callback_loop(vm: JavaVM, cb: GlobalRef, recv: mpsc::Receiver<ToJavaMsg>) {
+/// Java class of the elements passed to `onServersDiscovered`
+pub const DISCOVERED_SERVER_CLASS: &str = "com/streamaudio/client/service/rust/DiscoveredServer";
+const NET_STATS_CLASS: &str = "com/streamaudio/client/service/rust/NetStats";
+const RTT_STATS_CLASS: &str = "com/streamaudio/client/service/rust/RttStats";
+
+/// `server_cls` must be looked up on a Java thread beforehand: `FindClass` on a natively attached
+/// thread only sees the system classes
+pub fn java_callback_loop(
+    vm: JavaVM,
+    cb: GlobalRef,
+    server_cls: GlobalRef,
+    recv: mpsc::Receiver<ToJavaMsg>,
+) {
     let _guard = log_err!(vm.attach_current_thread(); "attaching thread to Java VM");
     let env = log_err!(vm.get_env(); "Retrieving JNIEnv");
-    let mut this: JavaLoop = log_err!(JavaLoop::new(env, cb); "Creating JavaLoop");
+    let mut this: JavaLoop = log_err!(JavaLoop::new(env, cb, server_cls); "Creating JavaLoop");
 
     loop {
         let msg = log_err!(recv.recv(); "receiving from channel in java cb loop");
@@ -29,6 +49,18 @@ pub fn java_callback_loop(vm: JavaVM, cb: GlobalRef, recv: mpsc::Receiver<ToJava
                 this.notify_buffer_size_changed(duration),
                 "notifying java that the buffer size has changed"
             ),
+            ToJavaMsg::ConnectionStateChanged(state) => log_and_ignore_err!(
+                this.notify_connection_state_changed(state),
+                "notifying java that the connection state has changed"
+            ),
+            ToJavaMsg::ServersDiscovered(servers) => log_and_ignore_err!(
+                this.notify_servers_discovered(&servers),
+                "notifying java about discovered servers"
+            ),
+            ToJavaMsg::PairingStateChanged(state) => log_and_ignore_err!(
+                this.notify_pairing_state_changed(state),
+                "notifying java that the pairing state has changed"
+            ),
             ToJavaMsg::Stop => {
                 break;
             }
@@ -39,21 +71,35 @@ pub fn java_callback_loop(vm: JavaVM, cb: GlobalRef, recv: mpsc::Receiver<ToJava
 struct JavaLoop<'a> {
     env: JNIEnv<'a>,
     cb_obj: GlobalRef,
+    server_cls: GlobalRef,
     last_buffer_size_notify: Option<Instant>,
 }
 
 const MIN_NOTIFY_DURATION: Duration = Duration::from_millis(500);
 
 impl<'a> JavaLoop<'a> {
-    fn new(env: JNIEnv<'a>, cb: GlobalRef) -> Result<Self, Error> {
+    fn new(env: JNIEnv<'a>, cb: GlobalRef, server_cls: GlobalRef) -> Result<Self, Error> {
         Ok(Self {
             env,
             cb_obj: cb,
+            server_cls,
             last_buffer_size_notify: None,
         })
     }
 
-    fn raise_error(&self, _e: &Error) -> Result<(), Error> {
+    fn raise_error(&self, e: &Error) -> Result<(), Error> {
+        let msg = self.env.new_string(format!("{}", e))?;
+
+        let res = self.env.call_method(
+            self.cb_obj.as_obj(),
+            "onError",
+            "(Ljava/lang/String;)V",
+            &[JObject::from(msg).into()],
+        );
+        // The attached thread never returns to Java, so its local refs are never freed otherwise
+        self.env.delete_local_ref(JObject::from(msg))?;
+        res?;
+
         Ok(())
     }
 
@@ -74,6 +120,43 @@ impl<'a> JavaLoop<'a> {
         Ok(())
     }
 
+    fn notify_connection_state_changed(&mut self, state: ConnectionState) -> Result<(), Error> {
+        self.env.call_method(
+            self.cb_obj.as_obj(),
+            "onConnectionStateChanged",
+            "(I)V",
+            &[(state as i32).into()],
+        )?;
+
+        Ok(())
+    }
+
+    fn notify_pairing_state_changed(&mut self, state: PairingState) -> Result<(), Error> {
+        self.env.call_method(
+            self.cb_obj.as_obj(),
+            "onPairingStateChanged",
+            "(I)V",
+            &[(state as i32).into()],
+        )?;
+
+        Ok(())
+    }
+
+    fn notify_servers_discovered(&mut self, servers: &[DiscoveredServer]) -> Result<(), Error> {
+        let arr = new_server_array(&self.env, &self.server_cls, servers)?;
+
+        let res = self.env.call_method(
+            self.cb_obj.as_obj(),
+            "onServersDiscovered",
+            "([Lcom/streamaudio/client/service/rust/DiscoveredServer;)V",
+            &[JObject::from(arr).into()],
+        );
+        self.env.delete_local_ref(JObject::from(arr))?;
+        res?;
+
+        Ok(())
+    }
+
     fn should_notify_buffer_size_changed(&mut self) -> bool {
         let res = match &self.last_buffer_size_notify {
             Some(t) => t.elapsed() >= MIN_NOTIFY_DURATION,
@@ -87,3 +170,77 @@ impl<'a> JavaLoop<'a> {
         res
     }
 }
+
+/// Returns a local ref, the element refs are deleted as they are created
+pub fn new_server_array(
+    env: &JNIEnv,
+    server_cls: &GlobalRef,
+    servers: &[DiscoveredServer],
+) -> Result<jobjectArray, Error> {
+    let cls = JClass::from(server_cls.as_obj());
+    let arr = env.new_object_array(servers.len() as _, cls, JObject::null())?;
+
+    for (i, server) in servers.iter().enumerate() {
+        let name = env.new_string(&server.name)?;
+        let addr = env.new_string(server.addr.to_string())?;
+        let codec = env.new_string(server.codec.name())?;
+
+        let obj = env.new_object(
+            cls,
+            "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+            &[
+                JObject::from(name).into(),
+                JObject::from(addr).into(),
+                JObject::from(codec).into(),
+            ],
+        );
+        for s in &[name, addr, codec] {
+            env.delete_local_ref(JObject::from(*s))?;
+        }
+        let obj = obj?;
+        env.set_object_array_element(arr, i as _, obj)?;
+        env.delete_local_ref(obj)?;
+    }
+
+    Ok(arr)
+}
+
+/// Returns a local ref owned by the JNI frame of the calling native method
+pub fn new_stats_object(
+    env: &JNIEnv,
+    net: &ReceiveStatsSnapshot,
+    buffer: &BufferStats,
+) -> Result<jobject, Error> {
+    let values = [
+        net.received,
+        net.lost,
+        net.duplicated,
+        net.reordered,
+        buffer.late as u64,
+        net.jitter.as_micros() as u64,
+        buffer.recovered as u64,
+        buffer.concealed as u64,
+        buffer.overflowed as u64,
+        buffer.memory.buffered_frames as u64,
+        buffer.memory.bytes as u64,
+    ];
+    let args: Vec<_> = values.iter().map(|&v| (v as i64).into()).collect();
+
+    let obj = env.new_object(NET_STATS_CLASS, "(JJJJJJJJJJJ)V", &args)?;
+    Ok(obj.into_inner())
+}
+
+/// Returns a local ref owned by the JNI frame of the calling native method
+pub fn new_rtt_object(env: &JNIEnv, rtt: &RttSnapshot) -> Result<jobject, Error> {
+    let obj = env.new_object(
+        RTT_STATS_CLASS,
+        "(JJJI)V",
+        &[
+            (rtt.min.as_micros() as i64).into(),
+            (rtt.avg.as_micros() as i64).into(),
+            (rtt.p95.as_micros() as i64).into(),
+            (rtt.samples as i32).into(),
+        ],
+    )?;
+    Ok(obj.into_inner())
+}
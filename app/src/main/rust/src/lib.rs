@@ -2,7 +2,10 @@
 mod macros;
 mod android_audio;
 mod android_helper;
+mod discovery;
 mod error;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 mod jni_ffi;
 mod net_client;
 mod player;
@@ -0,0 +1,618 @@
+use super::pkt_decoder::Codec;
+use crate::error::Error;
+use crate::util::byte_reader::ByteReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// "SC" in ASCII
+pub const CONTROL_MAGIC: u16 = 0x5343;
+pub const CONTROL_VERSION: u8 = 1;
+
+/// Control packet layout, all numbers are big-endian:
+/// | magic: u16 | version: u8 | msg type: u8 | req_id: u32 | body |
+///
+/// A reply carries the `req_id` of the request it answers:
+/// `Info` answers `InfoRequest`, `Ack` answers `Start` and `Stop`.
+/// `Keepalive` is sent periodically by both sides and is not answered.
+/// `Announce` answers a broadcast `Probe`.
+/// `Nack` is not answered, the server resends the listed audio packets instead.
+/// `Report` is sent periodically while streaming and is not answered.
+/// `EchoReply` answers `Echo`, sent by either side, and carries the timestamp of the request.
+/// Pairing: `PairCommit` answers `PairStart`, `PairKey` answers `PairKey`,
+/// `Ack` answers `PairConfirm`, see `pairing::exchange`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlPkt {
+    pub req_id: u32,
+    pub msg: ControlMsg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMsg {
+    /// Carries the local port the client has bound to and the `FEATURE_*` bits it supports.
+    /// The server enables a subset of them in `Info`, old servers ignore the bits.
+    /// Over TCP the port is the one of the connection, the audio goes over it too.
+    InfoRequest {
+        port: u16,
+        features: u8,
+    },
+    Info(StreamInfo),
+    Start,
+    Stop,
+    Ack,
+    Keepalive,
+    Probe,
+    Announce(Announcement),
+    /// The cnts of the lost audio packets
+    Nack(Vec<u32>),
+    PairStart,
+    /// SHA-256 of the server public key, sent before the client key is known
+    PairCommit([u8; 32]),
+    /// X25519 public key
+    PairKey([u8; 32]),
+    /// Proves that the user has entered the matching PIN
+    PairConfirm([u8; 32]),
+    Report(ReceiverReport),
+    /// The sender's timestamp in microseconds, opaque to the other side
+    Echo(u64),
+    EchoReply(u64),
+}
+
+/// A server found by the LAN discovery
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub name: String,
+    /// `None` if the server should be reached at the address the announcement came from
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    pub codec: Codec,
+}
+
+/// How the playback is going, the server may adapt the bitrate or FEC to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceiverReport {
+    /// Lost since the previous report, in 1/256 as in RTCP
+    pub fraction_lost: u8,
+    pub total_lost: u32,
+    /// The highest cnt received
+    pub max_cnt: u32,
+    /// Interarrival jitter in microseconds
+    pub jitter_us: u32,
+    /// The average time a frame spends in the jitter buffer
+    pub buffer_delay_ms: u32,
+    /// Lost frames replaced by replaying the previous one, since the start
+    pub concealed: u32,
+}
+
+/// Parameters of the audio stream announced by the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub codec: Codec,
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// Samples per channel in one encoded frame
+    pub frame_size: u16,
+    /// The `FEATURE_*` bits enabled by the server
+    pub features: u8,
+    /// The group the audio is sent to, with `FEATURE_MULTICAST` only
+    pub multicast: Option<SocketAddr>,
+}
+
+/// Audio packets carry earlier frames next to the current one, see `PktDecoder::parse`
+pub const FEATURE_REDUNDANCY: u8 = 0x01;
+/// The audio is sent to a multicast group, the control messages stay unicast.
+/// Offered only if multicast is enabled in `Config`
+pub const FEATURE_MULTICAST: u8 = 0x02;
+/// All the features this client always supports
+pub const SUPPORTED_FEATURES: u8 = FEATURE_REDUNDANCY;
+
+impl ControlPkt {
+    pub fn new(req_id: u32, msg: ControlMsg) -> Self {
+        Self { req_id, msg }
+    }
+
+    pub fn encode(&self, to: &mut Vec<u8>) {
+        to.clear();
+        to.extend_from_slice(&CONTROL_MAGIC.to_be_bytes());
+        to.push(CONTROL_VERSION);
+        to.push(self.msg.type_id());
+        to.extend_from_slice(&self.req_id.to_be_bytes());
+
+        match &self.msg {
+            ControlMsg::InfoRequest { port, features } => {
+                to.extend_from_slice(&port.to_be_bytes());
+                to.push(*features);
+            }
+            ControlMsg::Info(info) => info.encode(to),
+            ControlMsg::Announce(announcement) => announcement.encode(to),
+            ControlMsg::Nack(cnts) => encode_nack(cnts, to),
+            ControlMsg::PairCommit(bytes)
+            | ControlMsg::PairKey(bytes)
+            | ControlMsg::PairConfirm(bytes) => to.extend_from_slice(bytes),
+            ControlMsg::Report(report) => report.encode(to),
+            ControlMsg::Echo(timestamp) | ControlMsg::EchoReply(timestamp) => {
+                to.extend_from_slice(&timestamp.to_be_bytes())
+            }
+            _ => {}
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut reader = ByteReader::new(buf);
+
+        let magic = reader.read_u16()?;
+        if magic != CONTROL_MAGIC {
+            return Err(Error::new_malformed_pkt(format!(
+                "Wrong control packet magic: {:#06x}",
+                magic
+            )));
+        }
+
+        let version = reader.read_u8()?;
+        if version != CONTROL_VERSION {
+            return Err(Error::new_unsupported_version(version));
+        }
+
+        let type_id = reader.read_u8()?;
+        let req_id = reader.read_u32()?;
+
+        let msg = match type_id {
+            INFO_REQUEST_TYPE_ID => ControlMsg::InfoRequest {
+                port: reader.read_u16()?,
+                features: read_optional_u8(&mut reader)?,
+            },
+            INFO_TYPE_ID => ControlMsg::Info(StreamInfo::decode(&mut reader)?),
+            START_TYPE_ID => ControlMsg::Start,
+            STOP_TYPE_ID => ControlMsg::Stop,
+            ACK_TYPE_ID => ControlMsg::Ack,
+            KEEPALIVE_TYPE_ID => ControlMsg::Keepalive,
+            PROBE_TYPE_ID => ControlMsg::Probe,
+            ANNOUNCE_TYPE_ID => ControlMsg::Announce(Announcement::decode(&mut reader)?),
+            NACK_TYPE_ID => ControlMsg::Nack(decode_nack(&mut reader)?),
+            PAIR_START_TYPE_ID => ControlMsg::PairStart,
+            PAIR_COMMIT_TYPE_ID => ControlMsg::PairCommit(read_32_bytes(&mut reader)?),
+            PAIR_KEY_TYPE_ID => ControlMsg::PairKey(read_32_bytes(&mut reader)?),
+            PAIR_CONFIRM_TYPE_ID => ControlMsg::PairConfirm(read_32_bytes(&mut reader)?),
+            REPORT_TYPE_ID => ControlMsg::Report(ReceiverReport::decode(&mut reader)?),
+            ECHO_TYPE_ID => ControlMsg::Echo(reader.read_u64()?),
+            ECHO_REPLY_TYPE_ID => ControlMsg::EchoReply(reader.read_u64()?),
+            _ => {
+                return Err(Error::new_malformed_pkt(format!(
+                    "Unknown control message type: {}",
+                    type_id
+                )));
+            }
+        };
+
+        Ok(Self { req_id, msg })
+    }
+}
+
+impl ControlMsg {
+    fn type_id(&self) -> u8 {
+        match self {
+            ControlMsg::InfoRequest { .. } => INFO_REQUEST_TYPE_ID,
+            ControlMsg::Info(_) => INFO_TYPE_ID,
+            ControlMsg::Start => START_TYPE_ID,
+            ControlMsg::Stop => STOP_TYPE_ID,
+            ControlMsg::Ack => ACK_TYPE_ID,
+            ControlMsg::Keepalive => KEEPALIVE_TYPE_ID,
+            ControlMsg::Probe => PROBE_TYPE_ID,
+            ControlMsg::Announce(_) => ANNOUNCE_TYPE_ID,
+            ControlMsg::Nack(_) => NACK_TYPE_ID,
+            ControlMsg::PairStart => PAIR_START_TYPE_ID,
+            ControlMsg::PairCommit(_) => PAIR_COMMIT_TYPE_ID,
+            ControlMsg::PairKey(_) => PAIR_KEY_TYPE_ID,
+            ControlMsg::PairConfirm(_) => PAIR_CONFIRM_TYPE_ID,
+            ControlMsg::Report(_) => REPORT_TYPE_ID,
+            ControlMsg::Echo(_) => ECHO_TYPE_ID,
+            ControlMsg::EchoReply(_) => ECHO_REPLY_TYPE_ID,
+        }
+    }
+}
+
+impl StreamInfo {
+    /// | codec: u8 | sample_rate: u32 | channels: u8 | frame_size: u16 | features: u8 |
+    /// | multicast group ip version: u8 (4 or 6) | ip | port: u16 |
+    /// `features` is absent in the replies of old servers, the group is present only with
+    /// `FEATURE_MULTICAST`
+    fn encode(&self, to: &mut Vec<u8>) {
+        to.push(self.codec.to_id());
+        to.extend_from_slice(&self.sample_rate.to_be_bytes());
+        to.push(self.channels);
+        to.extend_from_slice(&self.frame_size.to_be_bytes());
+        to.push(self.features);
+        if let (true, Some(group)) = (self.has_feature(FEATURE_MULTICAST), self.multicast) {
+            encode_ip(Some(group.ip()), to);
+            to.extend_from_slice(&group.port().to_be_bytes());
+        }
+    }
+
+    fn decode(reader: &mut ByteReader) -> Result<Self, Error> {
+        let codec = Codec::from_id(reader.read_u8()?)?;
+        let sample_rate = reader.read_u32()?;
+        let channels = reader.read_u8()?;
+        let frame_size = reader.read_u16()?;
+        let features = read_optional_u8(reader)?;
+        let multicast = if features & FEATURE_MULTICAST != 0 {
+            let ip = decode_ip(reader)?
+                .ok_or_else(|| Error::new_malformed_pkt("No multicast group in stream info"))?;
+            Some(SocketAddr::new(ip, reader.read_u16()?))
+        } else {
+            None
+        };
+
+        if sample_rate == 0 || channels == 0 || frame_size == 0 {
+            return Err(Error::new_malformed_pkt(format!(
+                "Invalid stream info: rate: {}, channels: {}, frame size: {}",
+                sample_rate, channels, frame_size
+            )));
+        }
+
+        Ok(Self {
+            codec,
+            sample_rate,
+            channels,
+            frame_size,
+            features,
+            multicast,
+        })
+    }
+
+    pub fn has_feature(&self, feature: u8) -> bool {
+        self.features & feature != 0
+    }
+}
+
+/// Returns 0 for a field added in a later revision if the peer doesn't send it
+fn read_optional_u8(reader: &mut ByteReader) -> Result<u8, Error> {
+    if reader.remaining() > 0 {
+        reader.read_u8()
+    } else {
+        Ok(0)
+    }
+}
+
+fn read_32_bytes(reader: &mut ByteReader) -> Result<[u8; 32], Error> {
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(reader.read_bytes(32)?);
+    Ok(bytes)
+}
+
+/// The max qty of cnts in one `Nack`
+pub const MAX_NACK_CNTS: usize = std::u8::MAX as usize;
+
+/// | qty: u8 | cnt: u32 |...
+fn encode_nack(cnts: &[u32], to: &mut Vec<u8>) {
+    let cnts = &cnts[..std::cmp::min(cnts.len(), MAX_NACK_CNTS)];
+    to.push(cnts.len() as u8);
+    for cnt in cnts {
+        to.extend_from_slice(&cnt.to_be_bytes());
+    }
+}
+
+fn decode_nack(reader: &mut ByteReader) -> Result<Vec<u32>, Error> {
+    let qty = reader.read_u8()?;
+    (0..qty).map(|_| reader.read_u32()).collect()
+}
+
+/// Bumped when fields are added, the older decoders skip the trailing ones
+const REPORT_VERSION: u8 = 1;
+
+impl ReceiverReport {
+    /// | report version: u8 | fraction lost: u8 | total lost: u32 | max cnt: u32 |
+    /// | jitter us: u32 | buffer delay ms: u32 | concealed: u32 |
+    fn encode(&self, to: &mut Vec<u8>) {
+        to.push(REPORT_VERSION);
+        to.push(self.fraction_lost);
+        to.extend_from_slice(&self.total_lost.to_be_bytes());
+        to.extend_from_slice(&self.max_cnt.to_be_bytes());
+        to.extend_from_slice(&self.jitter_us.to_be_bytes());
+        to.extend_from_slice(&self.buffer_delay_ms.to_be_bytes());
+        to.extend_from_slice(&self.concealed.to_be_bytes());
+    }
+
+    fn decode(reader: &mut ByteReader) -> Result<Self, Error> {
+        let version = reader.read_u8()?;
+        if version == 0 {
+            return Err(Error::new_unsupported_version(version));
+        }
+
+        Ok(Self {
+            fraction_lost: reader.read_u8()?,
+            total_lost: reader.read_u32()?,
+            max_cnt: reader.read_u32()?,
+            jitter_us: reader.read_u32()?,
+            buffer_delay_ms: reader.read_u32()?,
+            concealed: reader.read_u32()?,
+        })
+    }
+}
+
+impl Announcement {
+    /// | port: u16 | codec: u8 | ip version: u8 (0, 4 or 6) | ip | name len: u8 | name: utf-8 |
+    fn encode(&self, to: &mut Vec<u8>) {
+        to.extend_from_slice(&self.port.to_be_bytes());
+        to.push(self.codec.to_id());
+        encode_ip(self.ip, to);
+
+        let name = self.name.as_bytes();
+        let name = &name[..std::cmp::min(name.len(), std::u8::MAX as usize)];
+        to.push(name.len() as u8);
+        to.extend_from_slice(name);
+    }
+
+    fn decode(reader: &mut ByteReader) -> Result<Self, Error> {
+        let port = reader.read_u16()?;
+        let codec = Codec::from_id(reader.read_u8()?)?;
+        let ip = decode_ip(reader)?;
+
+        let name_len = reader.read_u8()? as usize;
+        let name = String::from_utf8_lossy(reader.read_bytes(name_len)?).into_owned();
+
+        Ok(Self {
+            name,
+            ip,
+            port,
+            codec,
+        })
+    }
+}
+
+/// | ip version: u8 (0, 4 or 6) | ip |
+fn encode_ip(ip: Option<IpAddr>, to: &mut Vec<u8>) {
+    match ip {
+        None => to.push(0),
+        Some(IpAddr::V4(ip)) => {
+            to.push(4);
+            to.extend_from_slice(&ip.octets());
+        }
+        Some(IpAddr::V6(ip)) => {
+            to.push(6);
+            to.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+fn decode_ip(reader: &mut ByteReader) -> Result<Option<IpAddr>, Error> {
+    let ip_version = reader.read_u8()?;
+    match ip_version {
+        0 => Ok(None),
+        4 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(reader.read_bytes(4)?);
+            Ok(Some(Ipv4Addr::from(octets).into()))
+        }
+        6 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(reader.read_bytes(16)?);
+            Ok(Some(Ipv6Addr::from(octets).into()))
+        }
+        _ => Err(Error::new_malformed_pkt(format!(
+            "Unknown ip version: {}",
+            ip_version
+        ))),
+    }
+}
+
+impl Default for StreamInfo {
+    /// What the server sent before the stream info was negotiated
+    fn default() -> Self {
+        Self {
+            codec: Codec::Aac,
+            sample_rate: 44100,
+            channels: 2,
+            frame_size: 1024,
+            features: 0,
+            multicast: None,
+        }
+    }
+}
+
+pub const INFO_REQUEST_TYPE_ID: u8 = 1;
+pub const INFO_TYPE_ID: u8 = 2;
+const START_TYPE_ID: u8 = 3;
+const STOP_TYPE_ID: u8 = 4;
+const ACK_TYPE_ID: u8 = 5;
+const KEEPALIVE_TYPE_ID: u8 = 6;
+const PROBE_TYPE_ID: u8 = 7;
+const ANNOUNCE_TYPE_ID: u8 = 8;
+const NACK_TYPE_ID: u8 = 9;
+const PAIR_START_TYPE_ID: u8 = 10;
+const PAIR_COMMIT_TYPE_ID: u8 = 11;
+const PAIR_KEY_TYPE_ID: u8 = 12;
+const PAIR_CONFIRM_TYPE_ID: u8 = 13;
+const REPORT_TYPE_ID: u8 = 14;
+const ECHO_TYPE_ID: u8 = 15;
+const ECHO_REPLY_TYPE_ID: u8 = 16;
+
+/// `Info`, `Ack` and `EchoReply` carry the `req_id` of the request they answer
+pub fn is_reply(type_id: u8) -> bool {
+    type_id == INFO_TYPE_ID || type_id == ACK_TYPE_ID || type_id == ECHO_REPLY_TYPE_ID
+}
+
+pub fn is_control_pkt(buf: &[u8]) -> bool {
+    buf.len() >= 2 && buf[..2] == CONTROL_MAGIC.to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(msg: ControlMsg) {
+        let pkt = ControlPkt::new(0x0102_0304, msg);
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf);
+        assert!(is_control_pkt(&buf));
+        assert_eq!(ControlPkt::decode(&buf).unwrap(), pkt);
+    }
+
+    fn encode(msg: ControlMsg) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ControlPkt::new(1, msg).encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn every_message_survives_roundtrip() {
+        let report = ReceiverReport {
+            fraction_lost: 3,
+            total_lost: 10,
+            max_cnt: std::u32::MAX,
+            jitter_us: 2500,
+            buffer_delay_ms: 120,
+            concealed: 4,
+        };
+        let msgs = vec![
+            ControlMsg::InfoRequest {
+                port: 25204,
+                features: SUPPORTED_FEATURES | FEATURE_MULTICAST,
+            },
+            ControlMsg::Info(StreamInfo::default()),
+            ControlMsg::Info(StreamInfo {
+                features: FEATURE_REDUNDANCY | FEATURE_MULTICAST,
+                multicast: Some("[ff12::1]:5000".parse().unwrap()),
+                ..StreamInfo::default()
+            }),
+            ControlMsg::Start,
+            ControlMsg::Stop,
+            ControlMsg::Ack,
+            ControlMsg::Keepalive,
+            ControlMsg::Probe,
+            ControlMsg::Announce(Announcement {
+                name: "Living room".to_owned(),
+                ip: None,
+                port: 25204,
+                codec: Codec::Aac,
+            }),
+            ControlMsg::Announce(Announcement {
+                name: String::new(),
+                ip: Some("192.168.1.2".parse().unwrap()),
+                port: 1,
+                codec: Codec::Aac,
+            }),
+            ControlMsg::Nack(vec![]),
+            ControlMsg::Nack(vec![0, 7, std::u32::MAX]),
+            ControlMsg::PairStart,
+            ControlMsg::PairCommit([1; 32]),
+            ControlMsg::PairKey([2; 32]),
+            ControlMsg::PairConfirm([3; 32]),
+            ControlMsg::Report(report),
+            ControlMsg::Echo(123_456_789),
+            ControlMsg::EchoReply(std::u64::MAX),
+        ];
+
+        for msg in msgs {
+            roundtrip(msg);
+        }
+    }
+
+    #[test]
+    fn optional_fields_of_old_peers_are_zero() {
+        let mut buf = encode(ControlMsg::InfoRequest {
+            port: 25204,
+            features: FEATURE_REDUNDANCY,
+        });
+        buf.pop();
+        let pkt = ControlPkt::decode(&buf).unwrap();
+        assert_eq!(
+            pkt.msg,
+            ControlMsg::InfoRequest {
+                port: 25204,
+                features: 0
+            }
+        );
+
+        let mut buf = encode(ControlMsg::Info(StreamInfo {
+            features: FEATURE_REDUNDANCY,
+            ..StreamInfo::default()
+        }));
+        buf.pop();
+        let pkt = ControlPkt::decode(&buf).unwrap();
+        assert_eq!(pkt.msg, ControlMsg::Info(StreamInfo::default()));
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        // The last field is cut, `features` of `InfoRequest` is optional so its port is cut
+        let msgs = vec![
+            (
+                ControlMsg::InfoRequest {
+                    port: 25204,
+                    features: 0,
+                },
+                2,
+            ),
+            (
+                ControlMsg::Info(StreamInfo {
+                    features: FEATURE_MULTICAST,
+                    multicast: Some("239.1.2.3:5000".parse().unwrap()),
+                    ..StreamInfo::default()
+                }),
+                1,
+            ),
+            (ControlMsg::Nack(vec![1, 2]), 1),
+            (ControlMsg::PairKey([2; 32]), 1),
+            (
+                ControlMsg::Report(ReceiverReport {
+                    fraction_lost: 0,
+                    total_lost: 0,
+                    max_cnt: 0,
+                    jitter_us: 0,
+                    buffer_delay_ms: 0,
+                    concealed: 0,
+                }),
+                1,
+            ),
+            (ControlMsg::Echo(1), 1),
+        ];
+
+        for (msg, cut) in msgs {
+            let buf = encode(msg.clone());
+            assert!(
+                ControlPkt::decode(&buf[..buf.len() - cut]).is_err(),
+                "{:?}",
+                msg
+            );
+        }
+        // In the header
+        assert!(ControlPkt::decode(&encode(ControlMsg::Ack)[..7]).is_err());
+    }
+
+    #[test]
+    fn nack_is_limited_to_max_cnts() {
+        let cnts: Vec<u32> = (0..MAX_NACK_CNTS as u32 + 10).collect();
+        let buf = encode(ControlMsg::Nack(cnts.clone()));
+        let pkt = ControlPkt::decode(&buf).unwrap();
+        assert_eq!(pkt.msg, ControlMsg::Nack(cnts[..MAX_NACK_CNTS].to_vec()));
+
+        // The qty claims more cnts than the packet has
+        let mut buf = encode(ControlMsg::Nack(vec![1, 2]));
+        buf[8] = 3;
+        assert!(ControlPkt::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn only_answers_are_replies() {
+        let replies = [
+            ControlMsg::Info(StreamInfo::default()),
+            ControlMsg::Ack,
+            ControlMsg::EchoReply(1),
+        ];
+        for msg in &replies {
+            assert!(is_reply(msg.type_id()), "{:?}", msg);
+        }
+
+        let requests = [
+            ControlMsg::InfoRequest {
+                port: 1,
+                features: 0,
+            },
+            ControlMsg::Start,
+            ControlMsg::Stop,
+            ControlMsg::Keepalive,
+            ControlMsg::Echo(1),
+        ];
+        for msg in &requests {
+            assert!(!is_reply(msg.type_id()), "{:?}", msg);
+        }
+    }
+}
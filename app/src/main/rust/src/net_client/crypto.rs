@@ -0,0 +1,536 @@
+use super::control;
+use super::pkt_decoder::PKT_MAGIC;
+use crate::error::Error;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use log::warn;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+
+/// Authenticated encryption of the datagrams with the keys derived from a pre-shared secret.
+///
+/// The header stays in plaintext and is authenticated as associated data,
+/// the payload is encrypted in place and followed by a 16 bytes tag.
+///
+/// Every info request starts a new session. It's sealed under the handshake key derived
+/// from the PSK and a random `client_random`, which follows the tag in plaintext.
+/// `Info` is sealed under the same key with a random nonce and is followed by
+/// | server_random: 16 | nonce: 12 |, `server_random` is authenticated with the header.
+/// The rest of the session is sealed under the key derived from the PSK and both randoms,
+/// with the nonce derived from the header, so it never repeats across sessions:
+/// | direction: u8 | audio flags or control type: u8 | zeros: 6 | cnt or req_id: u32 |
+/// A retransmitted datagram must be sent byte for byte the same as the original,
+/// so the server must answer a retransmitted info request with the same `server_random`.
+pub struct Cipher {
+    psk: Vec<u8>,
+    client_random: [u8; RANDOM_LEN],
+    handshake_aead: ChaCha20Poly1305,
+    /// `None` until `Info` is received
+    session_aead: Option<ChaCha20Poly1305>,
+    audio_window: ReplayWindow,
+    control_window: ReplayWindow,
+    /// Replies carry the ids of our requests, not of the server's ones
+    reply_window: ReplayWindow,
+    unauthenticated: u64,
+    replayed: u64,
+}
+
+/// Accepts every sequence number once, within the last `REPLAY_WINDOW_LEN` of the highest one
+struct ReplayWindow {
+    highest: Option<u32>,
+    /// Bit `i` is set if `highest - i` has been accepted
+    bitmap: u64,
+}
+
+const TAG_LEN: usize = 16;
+const RANDOM_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const AUDIO_HEADER_LEN: usize = 17;
+const CONTROL_HEADER_LEN: usize = 8;
+const REPLAY_WINDOW_LEN: u32 = 64;
+const HANDSHAKE_KEY_INFO: &[u8] = b"stream-audio v2 handshake key";
+const SESSION_KEY_INFO: &[u8] = b"stream-audio v2 session key";
+const LOG_EVERY_PKTS: u64 = 1000;
+
+#[derive(Clone, Copy)]
+enum Direction {
+    ServerAudio = 1,
+    ServerControl = 2,
+    ClientControl = 3,
+}
+
+impl Cipher {
+    pub fn new(psk: &[u8]) -> Result<Self, Error> {
+        if psk.is_empty() {
+            return Err(Error::new_wrong_argument("Pre-shared key is empty"));
+        }
+
+        let client_random = new_random();
+        Ok(Self {
+            psk: psk.to_vec(),
+            client_random,
+            handshake_aead: derive_aead(psk, &client_random, HANDSHAKE_KEY_INFO)?,
+            session_aead: None,
+            audio_window: ReplayWindow::new(),
+            control_window: ReplayWindow::new(),
+            reply_window: ReplayWindow::new(),
+            unauthenticated: 0,
+            replayed: 0,
+        })
+    }
+
+    /// Called before every info request, the server may have restarted its counters
+    pub fn new_session(&mut self) -> Result<(), Error> {
+        self.client_random = new_random();
+        self.handshake_aead = derive_aead(&self.psk, &self.client_random, HANDSHAKE_KEY_INFO)?;
+        self.session_aead = None;
+        self.audio_window = ReplayWindow::new();
+        self.control_window = ReplayWindow::new();
+        self.reply_window = ReplayWindow::new();
+        Ok(())
+    }
+
+    /// Encrypts an encoded control packet in place.
+    /// Only the info request can be sent before `Info` is received
+    pub fn seal_control(&self, pkt: &mut Vec<u8>) -> Result<(), Error> {
+        if pkt.len() < CONTROL_HEADER_LEN {
+            return Err(Error::new_malformed_pkt("Control packet is too short"));
+        }
+
+        let type_id = pkt[3];
+        let is_info_request = type_id == control::INFO_REQUEST_TYPE_ID;
+        let aead = if is_info_request {
+            &self.handshake_aead
+        } else {
+            self.session_aead
+                .as_ref()
+                .ok_or_else(|| Error::new_wrong_state("No session key before the stream info"))?
+        };
+
+        let nonce = make_nonce(Direction::ClientControl, type_id, read_u32(&pkt[4..]));
+        let (header, payload) = pkt.split_at_mut(CONTROL_HEADER_LEN);
+        let tag = aead
+            .encrypt_in_place_detached(&nonce, header, payload)
+            .map_err(|_| Error::new_crypto("Cannot encrypt the packet"))?;
+        pkt.extend_from_slice(&tag);
+        if is_info_request {
+            pkt.extend_from_slice(&self.client_random);
+        }
+        Ok(())
+    }
+
+    /// Decrypts a datagram in place and returns the length of the plaintext packet.
+    /// Returns `None` if the datagram is not authentic or is replayed, it must be dropped then.
+    pub fn open(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let res = self.try_open(buf);
+        match res {
+            Ok(len) => Some(len),
+            Err(OpenError::Unauthenticated) => {
+                self.unauthenticated += 1;
+                if self.unauthenticated % LOG_EVERY_PKTS == 1 {
+                    warn!("Dropped {} unauthenticated packets", self.unauthenticated);
+                }
+                None
+            }
+            Err(OpenError::Replayed) => {
+                self.replayed += 1;
+                if self.replayed % LOG_EVERY_PKTS == 1 {
+                    warn!("Dropped {} replayed packets", self.replayed);
+                }
+                None
+            }
+        }
+    }
+
+    fn try_open(&mut self, buf: &mut [u8]) -> Result<usize, OpenError> {
+        let is_control = control::is_control_pkt(buf);
+        let header_len = if is_control {
+            CONTROL_HEADER_LEN
+        } else if buf.len() >= 2 && buf[..2] == PKT_MAGIC.to_be_bytes() {
+            AUDIO_HEADER_LEN
+        } else {
+            return Err(OpenError::Unauthenticated);
+        };
+        if buf.len() < header_len + TAG_LEN {
+            return Err(OpenError::Unauthenticated);
+        }
+        if is_control && buf[3] == control::INFO_TYPE_ID {
+            return self.open_info(buf);
+        }
+
+        let aead = match &self.session_aead {
+            Some(aead) => aead,
+            None => {
+                return Err(OpenError::Unauthenticated);
+            }
+        };
+        let (nonce, window, seq) = if is_control {
+            let type_id = buf[3];
+            let req_id = read_u32(&buf[4..]);
+            let window = if control::is_reply(type_id) {
+                &mut self.reply_window
+            } else {
+                &mut self.control_window
+            };
+            (
+                make_nonce(Direction::ServerControl, type_id, req_id),
+                window,
+                req_id,
+            )
+        } else {
+            let cnt = read_u32(&buf[5..]);
+            (
+                make_nonce(Direction::ServerAudio, buf[4], cnt),
+                &mut self.audio_window,
+                cnt,
+            )
+        };
+
+        if !window.is_new(seq) {
+            return Err(OpenError::Replayed);
+        }
+
+        let plaintext_len = buf.len() - TAG_LEN;
+        let (pkt, tag) = buf.split_at_mut(plaintext_len);
+        let (header, payload) = pkt.split_at_mut(header_len);
+        aead.decrypt_in_place_detached(&nonce, header, payload, Tag::from_slice(tag))
+            .map_err(|_| OpenError::Unauthenticated)?;
+
+        window.accept(seq);
+        Ok(plaintext_len)
+    }
+
+    /// Only the first `Info` of the session is accepted, it sets the session key.
+    /// It answers the request with our `client_random`, so an older one fails to open
+    fn open_info(&mut self, buf: &mut [u8]) -> Result<usize, OpenError> {
+        if buf.len() < CONTROL_HEADER_LEN + TAG_LEN + RANDOM_LEN + NONCE_LEN {
+            return Err(OpenError::Unauthenticated);
+        }
+
+        let (sealed, trailer) = buf.split_at_mut(buf.len() - RANDOM_LEN - NONCE_LEN);
+        let (server_random, nonce) = trailer.split_at(RANDOM_LEN);
+        let plaintext_len = sealed.len() - TAG_LEN;
+        let (pkt, tag) = sealed.split_at_mut(plaintext_len);
+        let (header, payload) = pkt.split_at_mut(CONTROL_HEADER_LEN);
+
+        let mut associated_data = [0; CONTROL_HEADER_LEN + RANDOM_LEN];
+        associated_data[..CONTROL_HEADER_LEN].copy_from_slice(header);
+        associated_data[CONTROL_HEADER_LEN..].copy_from_slice(server_random);
+        self.handshake_aead
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                &associated_data,
+                payload,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| OpenError::Unauthenticated)?;
+
+        if self.session_aead.is_some() {
+            return Err(OpenError::Replayed);
+        }
+
+        let mut salt = [0; 2 * RANDOM_LEN];
+        salt[..RANDOM_LEN].copy_from_slice(&self.client_random);
+        salt[RANDOM_LEN..].copy_from_slice(server_random);
+        let aead = derive_aead(&self.psk, &salt, SESSION_KEY_INFO)
+            .map_err(|_| OpenError::Unauthenticated)?;
+        self.session_aead = Some(aead);
+        Ok(plaintext_len)
+    }
+}
+
+enum OpenError {
+    Unauthenticated,
+    Replayed,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            highest: None,
+            bitmap: 0,
+        }
+    }
+
+    fn is_new(&self, seq: u32) -> bool {
+        let highest = match self.highest {
+            None => {
+                return true;
+            }
+            Some(highest) => highest,
+        };
+
+        let ahead = seq.wrapping_sub(highest);
+        if ahead != 0 && ahead < std::u32::MAX / 2 {
+            return true;
+        }
+
+        let behind = highest.wrapping_sub(seq);
+        behind < REPLAY_WINDOW_LEN && self.bitmap & (1 << behind) == 0
+    }
+
+    fn accept(&mut self, seq: u32) {
+        let highest = match self.highest {
+            None => {
+                self.highest = Some(seq);
+                self.bitmap = 1;
+                return;
+            }
+            Some(highest) => highest,
+        };
+
+        let ahead = seq.wrapping_sub(highest);
+        if ahead != 0 && ahead < std::u32::MAX / 2 {
+            self.bitmap = if ahead < REPLAY_WINDOW_LEN {
+                (self.bitmap << ahead) | 1
+            } else {
+                1
+            };
+            self.highest = Some(seq);
+        } else {
+            self.bitmap |= 1 << highest.wrapping_sub(seq);
+        }
+    }
+}
+
+fn derive_aead(psk: &[u8], salt: &[u8], info: &[u8]) -> Result<ChaCha20Poly1305, Error> {
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(salt), psk)
+        .expand(info, &mut key)
+        .map_err(|_| Error::new_crypto("Cannot derive the key"))?;
+    Ok(ChaCha20Poly1305::new(&key))
+}
+
+fn new_random() -> [u8; RANDOM_LEN] {
+    let mut random = [0; RANDOM_LEN];
+    OsRng.fill_bytes(&mut random);
+    random
+}
+
+fn make_nonce(direction: Direction, kind: u8, seq: u32) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[0] = direction as u8;
+    nonce[1] = kind;
+    nonce[8..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net_client::control::{ControlMsg, ControlPkt, StreamInfo};
+
+    const PSK: &[u8] = b"test pre-shared key";
+
+    /// The server side of a session
+    struct Server {
+        handshake_aead: ChaCha20Poly1305,
+        session_aead: ChaCha20Poly1305,
+        server_random: [u8; RANDOM_LEN],
+    }
+
+    impl Server {
+        /// Opens the info request, like the server does to start the session
+        fn accept(request: &[u8]) -> Self {
+            let (sealed, client_random) = request.split_at(request.len() - RANDOM_LEN);
+            let handshake_aead = derive_aead(PSK, client_random, HANDSHAKE_KEY_INFO).unwrap();
+
+            let mut pkt = sealed.to_vec();
+            let nonce = make_nonce(
+                Direction::ClientControl,
+                control::INFO_REQUEST_TYPE_ID,
+                read_u32(&pkt[4..]),
+            );
+            let plaintext_len = pkt.len() - TAG_LEN;
+            let (pkt, tag) = pkt.split_at_mut(plaintext_len);
+            let (header, payload) = pkt.split_at_mut(CONTROL_HEADER_LEN);
+            handshake_aead
+                .decrypt_in_place_detached(&nonce, header, payload, Tag::from_slice(tag))
+                .unwrap();
+
+            let server_random = new_random();
+            let mut salt = client_random.to_vec();
+            salt.extend_from_slice(&server_random);
+            Self {
+                handshake_aead,
+                session_aead: derive_aead(PSK, &salt, SESSION_KEY_INFO).unwrap(),
+                server_random,
+            }
+        }
+
+        fn seal_info(&self, req_id: u32) -> Vec<u8> {
+            let mut pkt = Vec::new();
+            ControlPkt::new(req_id, ControlMsg::Info(StreamInfo::default())).encode(&mut pkt);
+
+            let nonce = new_random();
+            let nonce = Nonce::from_slice(&nonce[..NONCE_LEN]);
+            let mut associated_data = pkt[..CONTROL_HEADER_LEN].to_vec();
+            associated_data.extend_from_slice(&self.server_random);
+            let tag = self
+                .handshake_aead
+                .encrypt_in_place_detached(nonce, &associated_data, &mut pkt[CONTROL_HEADER_LEN..])
+                .unwrap();
+            pkt.extend_from_slice(&tag);
+            pkt.extend_from_slice(&self.server_random);
+            pkt.extend_from_slice(nonce);
+            pkt
+        }
+
+        fn seal_control(&self, req_id: u32, msg: ControlMsg) -> Vec<u8> {
+            let mut pkt = Vec::new();
+            ControlPkt::new(req_id, msg).encode(&mut pkt);
+            let nonce = make_nonce(Direction::ServerControl, pkt[3], req_id);
+            self.seal(&nonce, CONTROL_HEADER_LEN, pkt)
+        }
+
+        fn seal_audio(&self, cnt: u32) -> Vec<u8> {
+            let mut pkt = PKT_MAGIC.to_be_bytes().to_vec();
+            pkt.resize(AUDIO_HEADER_LEN, 0);
+            pkt[5..9].copy_from_slice(&cnt.to_be_bytes());
+            pkt.extend_from_slice(b"audio");
+            let nonce = make_nonce(Direction::ServerAudio, pkt[4], cnt);
+            self.seal(&nonce, AUDIO_HEADER_LEN, pkt)
+        }
+
+        fn seal(&self, nonce: &Nonce, header_len: usize, mut pkt: Vec<u8>) -> Vec<u8> {
+            let (header, payload) = pkt.split_at_mut(header_len);
+            let tag = self
+                .session_aead
+                .encrypt_in_place_detached(nonce, header, payload)
+                .unwrap();
+            pkt.extend_from_slice(&tag);
+            pkt
+        }
+    }
+
+    fn request_info(cipher: &Cipher, req_id: u32) -> Vec<u8> {
+        let mut pkt = Vec::new();
+        let msg = ControlMsg::InfoRequest {
+            port: 5000,
+            features: 0,
+        };
+        ControlPkt::new(req_id, msg).encode(&mut pkt);
+        cipher.seal_control(&mut pkt).unwrap();
+        pkt
+    }
+
+    fn open(cipher: &mut Cipher, datagram: &[u8]) -> Option<Vec<u8>> {
+        let mut buf = datagram.to_vec();
+        let len = cipher.open(&mut buf)?;
+        buf.truncate(len);
+        Some(buf)
+    }
+
+    fn handshake(cipher: &mut Cipher, req_id: u32) -> Server {
+        let server = Server::accept(&request_info(cipher, req_id));
+        let info = open(cipher, &server.seal_info(req_id)).unwrap();
+        assert_eq!(
+            ControlPkt::decode(&info).unwrap().msg,
+            ControlMsg::Info(StreamInfo::default())
+        );
+        server
+    }
+
+    #[test]
+    fn session_key_is_negotiated_in_handshake() {
+        let mut cipher = Cipher::new(PSK).unwrap();
+        let server = handshake(&mut cipher, 1);
+
+        let ack = open(&mut cipher, &server.seal_control(2, ControlMsg::Ack)).unwrap();
+        assert_eq!(ControlPkt::decode(&ack).unwrap().msg, ControlMsg::Ack);
+        assert!(open(&mut cipher, &server.seal_audio(7)).is_some());
+
+        let mut start = Vec::new();
+        ControlPkt::new(2, ControlMsg::Start).encode(&mut start);
+        cipher.seal_control(&mut start).unwrap();
+        let nonce = make_nonce(Direction::ClientControl, start[3], 2);
+        let plaintext_len = start.len() - TAG_LEN;
+        let (pkt, tag) = start.split_at_mut(plaintext_len);
+        let (header, payload) = pkt.split_at_mut(CONTROL_HEADER_LEN);
+        assert!(server
+            .session_aead
+            .decrypt_in_place_detached(&nonce, header, payload, Tag::from_slice(tag))
+            .is_ok());
+    }
+
+    #[test]
+    fn only_info_request_is_sent_before_info() {
+        let cipher = Cipher::new(PSK).unwrap();
+        let mut pkt = Vec::new();
+        ControlPkt::new(1, ControlMsg::Start).encode(&mut pkt);
+        assert!(cipher.seal_control(&mut pkt).is_err());
+    }
+
+    #[test]
+    fn replies_are_replay_protected() {
+        let mut cipher = Cipher::new(PSK).unwrap();
+        let server = handshake(&mut cipher, 1);
+
+        let info = server.seal_info(1);
+        assert!(open(&mut cipher, &info).is_none());
+
+        let ack = server.seal_control(2, ControlMsg::Ack);
+        assert!(open(&mut cipher, &ack).is_some());
+        assert!(open(&mut cipher, &ack).is_none());
+
+        let echo_reply = server.seal_control(3, ControlMsg::EchoReply(42));
+        assert!(open(&mut cipher, &echo_reply).is_some());
+        assert!(open(&mut cipher, &echo_reply).is_none());
+        assert_eq!(cipher.replayed, 3);
+    }
+
+    #[test]
+    fn audio_is_replay_protected() {
+        let mut cipher = Cipher::new(PSK).unwrap();
+        let server = handshake(&mut cipher, 1);
+
+        assert!(open(&mut cipher, &server.seal_audio(10)).is_some());
+        assert!(open(&mut cipher, &server.seal_audio(12)).is_some());
+        assert!(open(&mut cipher, &server.seal_audio(10)).is_none());
+        assert!(open(&mut cipher, &server.seal_audio(11)).is_some());
+        assert!(open(&mut cipher, &server.seal_audio(11)).is_none());
+        assert_eq!(cipher.replayed, 2);
+    }
+
+    #[test]
+    fn previous_session_is_rejected() {
+        let mut cipher = Cipher::new(PSK).unwrap();
+        let old_server = handshake(&mut cipher, 1);
+        let old_request = request_info(&cipher, 2);
+
+        cipher.new_session().unwrap();
+        let request = request_info(&cipher, 2);
+        assert_ne!(request, old_request);
+
+        assert!(open(&mut cipher, &old_server.seal_info(1)).is_none());
+        assert!(open(&mut cipher, &old_server.seal_audio(1)).is_none());
+        assert!(open(&mut cipher, &old_server.seal_control(3, ControlMsg::Stop)).is_none());
+
+        let server = Server::accept(&request);
+        assert!(open(&mut cipher, &server.seal_info(2)).is_some());
+        assert!(open(&mut cipher, &old_server.seal_audio(5)).is_none());
+        assert!(open(&mut cipher, &server.seal_audio(1)).is_some());
+        assert_eq!(cipher.unauthenticated, 4);
+    }
+
+    #[test]
+    fn tampered_datagram_is_rejected() {
+        let mut cipher = Cipher::new(PSK).unwrap();
+        let server = handshake(&mut cipher, 1);
+
+        let mut audio = server.seal_audio(1);
+        audio[6] ^= 1;
+        assert!(open(&mut cipher, &audio).is_none());
+
+        let mut info = server.seal_info(1);
+        let len = info.len();
+        info[len - NONCE_LEN - 1] ^= 1;
+        assert!(open(&mut cipher, &info).is_none());
+        assert_eq!(cipher.unauthenticated, 2);
+    }
+}
@@ -1,88 +1,276 @@
+mod control;
+mod crypto;
+mod multicast;
+mod nack;
+mod pairing;
 mod pkt_decoder;
+mod resolver;
+mod rtp;
+mod rtt;
+mod sdp;
+mod socket;
+mod source_filter;
+mod stats;
+mod transport;
 
-pub use pkt_decoder::Pkt;
+#[cfg(feature = "fuzzing")]
+pub use control::FEATURE_REDUNDANCY;
+pub use control::{Announcement, ControlMsg, ControlPkt, ReceiverReport, StreamInfo};
+pub use multicast::MulticastConfig;
+pub use pairing::{Pairing, PairingState, PairingStore};
+pub use pkt_decoder::{Codec, Pkt, PktDecoder};
+pub use rtp::RtpParams;
+pub use rtt::RttSnapshot;
+pub use sdp::SessionDescription;
+pub use socket::parse_socket_addr;
+pub use stats::ReceiveStatsSnapshot;
 
+use self::crypto::Cipher;
+use self::multicast::{Membership, MulticastHandle, MulticastSwitch};
+use self::nack::NackTracker;
+use self::resolver::Resolver;
+use self::rtt::RttMeter;
+use self::source_filter::SourceFilter;
+use self::stats::ReceiveStats;
+use self::transport::{Connector, NetConnector, Transport};
 use crate::error::Error;
 use crate::jni_ffi::ToJavaMsg;
 use crate::player::Player;
 use crate::util::interval_measure::IntervalMeasure;
+use crate::util::stopper::{StopHandle, Stopper};
 use log::{info, warn};
-use mio::net::UdpSocket;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::mpsc;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-const UDP_TOKEN: mio::Token = mio::Token(0);
+const TRANSPORT_TOKEN: mio::Token = mio::Token(0);
 const STOP_TOKEN: mio::Token = mio::Token(1);
+const RESOLVE_TOKEN: mio::Token = mio::Token(2);
+const MULTICAST_TOKEN: mio::Token = mio::Token(3);
+const MULTICAST_SWITCH_TOKEN: mio::Token = mio::Token(4);
 
 pub struct NetClient {
-    set_readiness: mio::SetReadiness,
-    stop_flag: Arc<AtomicBool>,
+    stop_handle: StopHandle,
+    multicast_handle: MulticastHandle,
     join_handle: Option<JoinHandle<()>>,
+    stats: Arc<Mutex<ReceiveStats>>,
+    rtt: Arc<Mutex<RttMeter>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Gives up if the stream is not started within this time
+    pub handshake_timeout: Duration,
+    /// The first retransmission interval of an unanswered request, doubled after every retry
+    pub retransmit_interval: Duration,
+    pub max_retransmit_interval: Duration,
+    /// Streaming is reported as stalled if no audio is received within this time
+    pub stall_timeout: Duration,
+    pub keepalive_interval: Duration,
+    /// The handshake is restarted if nothing is received from the server within this time
+    pub silence_timeout: Duration,
+    /// A lost frame is requested again after this time if it's still missing
+    pub nack_retry_interval: Duration,
+    /// A lost frame is not requested if it would be played sooner than this,
+    /// the retransmission can't make it anyway
+    pub nack_min_lead: Duration,
+    /// How often the playback statistics are reported to the server
+    pub report_interval: Duration,
+    /// How often the round-trip time is measured while streaming
+    pub echo_interval: Duration,
+    /// Receive plain RTP instead of talking to our server. There's no handshake then,
+    /// the sender is expected to stream to the local address
+    pub rtp: Option<RtpParams>,
+    /// Offer the server to receive the audio from a multicast group.
+    /// `None`: always unicast
+    pub multicast: Option<MulticastConfig>,
+}
+
+/// The connection state reported to Java
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConnectionState {
+    Resolving = 0,
+    RequestingInfo = 1,
+    Starting = 2,
+    Streaming = 3,
+    Stalled = 4,
+    Disconnected = 5,
 }
 
 impl NetClient {
+    /// `remote_addr` is either `ip:port` or `hostname:port`.
+    /// All the addresses the hostname is resolved to are tried in turn, each over UDP first
+    /// and then over TCP to the same port.
+    /// With `psk` every datagram is encrypted and authenticated, the server must use the same key.
+    /// Multicast is not offered then.
+    /// Without it the key from the pairing with `remote_addr` is used, if there is one.
+    /// With `config.rtp` only the packets from the IP of `remote_addr` are accepted, and never
+    /// encrypted.
     pub fn new(
-        remote_addr: SocketAddr,
+        remote_addr: String,
         local_addr: SocketAddr,
+        psk: Option<&[u8]>,
+        pairings: &PairingStore,
+        config: Config,
         player: Player,
         to_java_send: mpsc::Sender<ToJavaMsg>,
     ) -> Result<Self, Error> {
-        let socket = UdpSocket::bind(&local_addr)?;
+        let psk = match &config.rtp {
+            Some(_) if psk.is_some() => {
+                return Err(Error::new_wrong_argument("RTP sessions can't be encrypted"));
+            }
+            Some(_) => None,
+            None => psk.or_else(|| pairings.find(&remote_addr).map(|r| &r.psk[..])),
+        };
+        let cipher = match psk {
+            Some(psk) => Some(Cipher::new(psk)?),
+            None => None,
+        };
 
-        let poll = mio::Poll::new()?;
+        Self::spawn(
+            remote_addr,
+            local_addr,
+            cipher,
+            config,
+            player,
+            to_java_send,
+            Box::new(NetConnector),
+        )
+    }
+
+    /// Unencrypted, the transports to the server are created by `connector`
+    #[cfg(test)]
+    pub fn with_connector(
+        remote_addr: String,
+        config: Config,
+        player: Player,
+        to_java_send: mpsc::Sender<ToJavaMsg>,
+        connector: Box<dyn Connector>,
+    ) -> Result<Self, Error> {
+        Self::spawn(
+            remote_addr,
+            "0.0.0.0:0".parse().unwrap(),
+            None,
+            config,
+            player,
+            to_java_send,
+            connector,
+        )
+    }
 
-        let (registration, set_readiness) = mio::Registration::new2();
-        let stop_flag = Arc::new(AtomicBool::new(false));
-        let stopper = Stopper {
-            registration,
-            flag: stop_flag.clone(),
+    fn spawn(
+        remote_addr: String,
+        local_addr: SocketAddr,
+        cipher: Option<Cipher>,
+        config: Config,
+        player: Player,
+        to_java_send: mpsc::Sender<ToJavaMsg>,
+        connector: Box<dyn Connector>,
+    ) -> Result<Self, Error> {
+        let pkt_decoder = match &config.rtp {
+            Some(params) => PktDecoder::new_rtp(params.clone())?,
+            None => PktDecoder::new(),
         };
 
+        let poll = mio::Poll::new()?;
+
+        let (stopper, stop_handle) = Stopper::new();
+
         poll.register(
-            &socket,
-            UDP_TOKEN,
+            &stopper,
+            STOP_TOKEN,
+            mio::Ready::readable(),
+            mio::PollOpt::level(),
+        )?;
+
+        let (multicast_switch, multicast_handle) = MulticastSwitch::new();
+        poll.register(
+            &multicast_switch,
+            MULTICAST_SWITCH_TOKEN,
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
 
+        let resolver = Resolver::spawn(remote_addr);
         poll.register(
-            &stopper,
-            STOP_TOKEN,
+            &resolver,
+            RESOLVE_TOKEN,
             mio::Ready::readable(),
-            mio::PollOpt::level(),
+            mio::PollOpt::edge(),
         )?;
 
-        socket.send_to(b"info", &remote_addr)?;
+        let stats = Arc::new(Mutex::new(ReceiveStats::new()));
+        let rtt = Arc::new(Mutex::new(RttMeter::new()));
 
+        let now = Instant::now();
         let poll_loop = PollLoop {
             poll,
-            socket,
-            addr: remote_addr,
-            state: State::InfoRequested,
+            connector,
+            transport: None,
+            multicast: None,
+            is_multicast_failed: false,
+            multicast_switch,
+            addr: local_addr,
+            addrs: Vec::new(),
+            next_addr_idx: 0,
+            local_addr,
+            local_port: 0,
+            state: State::Resolving,
+            config,
             player,
             stopper,
+            resolver,
             to_java_send,
             interval_measure: IntervalMeasure::new(),
-            pkt_decoder: pkt_decoder::PktDecoder::new(),
+            pkt_decoder,
+            source_filter: SourceFilter::new(),
+            stats: stats.clone(),
+            rtt: rtt.clone(),
+            cipher,
+            next_req_id: 1,
+            send_buf: Vec::new(),
+            handshake_deadline: now,
+            last_audio: now,
+            last_heard: now,
+            next_keepalive: now,
+            next_report: now,
+            next_echo: now,
+            next_nack_check: now,
+            nack_tracker: NackTracker::new(),
+            missing: Vec::new(),
+            to_nack: Vec::new(),
         };
+        poll_loop.notify_java_with_state();
+
         let join_handle = thread::spawn(move || poll_loop.poll_loop());
 
         Ok(Self {
-            set_readiness,
-            stop_flag,
+            stop_handle,
+            multicast_handle,
             join_handle: Some(join_handle),
+            stats,
+            rtt,
         })
     }
 
+    pub fn get_stats(&self) -> ReceiveStatsSnapshot {
+        self.stats.lock().unwrap().snapshot()
+    }
+
+    pub fn get_rtt(&self) -> RttSnapshot {
+        self.rtt.lock().unwrap().snapshot()
+    }
+
+    /// Leaves the multicast group for unicast and back without stopping the playback,
+    /// the stream is requested from the server again. Applies with `Config::multicast` only
+    pub fn set_multicast_enabled(&self, is_enabled: bool) -> Result<(), Error> {
+        self.multicast_handle.set_enabled(is_enabled)
+    }
+
     pub fn stop(&mut self) -> Result<(), Error> {
-        self.stop_flag.store(true, Ordering::SeqCst);
-        self.set_readiness.set_readiness(mio::Ready::readable())?;
+        self.stop_handle.stop()?;
         if let Some(join_handle) = self.join_handle.take() {
             let res = join_handle.join();
             if let Err(_) = res {
@@ -102,27 +290,92 @@ impl Drop for NetClient {
     }
 }
 
-#[derive(Debug)]
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(10),
+            retransmit_interval: Duration::from_millis(200),
+            max_retransmit_interval: Duration::from_secs(2),
+            stall_timeout: Duration::from_millis(500),
+            keepalive_interval: Duration::from_secs(1),
+            silence_timeout: Duration::from_secs(3),
+            nack_retry_interval: Duration::from_millis(50),
+            nack_min_lead: Duration::from_millis(20),
+            report_interval: Duration::from_secs(2),
+            echo_interval: Duration::from_secs(1),
+            rtp: None,
+            multicast: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 enum State {
-    InfoRequested,
-    Started,
+    Resolving,
+    /// Waiting for `Info` answering the request
+    RequestingInfo(PendingRequest),
+    /// Waiting for `Ack` of the `Start` request
+    Starting(PendingRequest),
+    Streaming,
+    /// Started, but no audio has been received for `Config::stall_timeout`.
+    /// Goes back to `RequestingInfo` after `Config::silence_timeout`
+    Stalled,
+    /// The address cannot be resolved, the handshake has timed out with all the addresses,
+    /// or the server has stopped the stream
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    req_id: u32,
+    interval: Duration,
+    retransmit_at: Instant,
 }
 
 struct PollLoop {
     poll: mio::Poll,
-    socket: UdpSocket,
+    connector: Box<dyn Connector>,
+    /// Bound once the address is resolved, rebound for every address tried.
+    /// UDP, replaced by TCP if the UDP handshake with the address has timed out
+    transport: Option<Box<dyn Transport>>,
+    /// The group the audio is received from, if the server has announced one
+    multicast: Option<Membership>,
+    /// Set if the announced group can't be joined, the server is asked for unicast then
+    is_multicast_failed: bool,
+    multicast_switch: MulticastSwitch,
+    /// The server address
     addr: SocketAddr,
+    /// All the addresses the server hostname is resolved to
+    addrs: Vec<SocketAddr>,
+    next_addr_idx: usize,
+    local_addr: SocketAddr,
+    local_port: u16,
     state: State,
+    config: Config,
     player: Player,
     stopper: Stopper,
+    resolver: Resolver,
     to_java_send: mpsc::Sender<ToJavaMsg>,
     interval_measure: IntervalMeasure,
-    pkt_decoder: pkt_decoder::PktDecoder,
-}
-
-struct Stopper {
-    registration: mio::Registration,
-    flag: Arc<AtomicBool>,
+    pkt_decoder: PktDecoder,
+    source_filter: SourceFilter,
+    stats: Arc<Mutex<ReceiveStats>>,
+    rtt: Arc<Mutex<RttMeter>>,
+    cipher: Option<Cipher>,
+    next_req_id: u32,
+    send_buf: Vec<u8>,
+    handshake_deadline: Instant,
+    last_audio: Instant,
+    /// The last time anything has been received from the server
+    last_heard: Instant,
+    next_keepalive: Instant,
+    next_report: Instant,
+    next_echo: Instant,
+    /// Scanning the buffer for the missing frames locks it, so it's done once per retry interval
+    next_nack_check: Instant,
+    nack_tracker: NackTracker,
+    missing: Vec<u32>,
+    to_nack: Vec<u32>,
 }
 
 impl PollLoop {
@@ -131,29 +384,63 @@ impl PollLoop {
         let mut buf = vec![0; 65536];
 
         loop {
-            self.poll.poll(&mut events, None).unwrap();
+            let timeout = self.next_timeout();
+            self.poll.poll(&mut events, timeout).unwrap();
             for event in &events {
                 match event.token() {
-                    UDP_TOKEN => self.receive_data(&mut buf),
+                    TRANSPORT_TOKEN => self.receive_data(&mut buf),
+                    MULTICAST_TOKEN => self.receive_multicast(&mut buf),
+                    MULTICAST_SWITCH_TOKEN => self.on_multicast_switched(),
+                    RESOLVE_TOKEN => self.on_resolved(),
                     STOP_TOKEN => {
                         if self.stopper.is_stopped() {
                             self.send_stop();
+                            info!("Source filter: {}", self.source_filter);
                             return;
                         }
                     }
                     _ => unreachable!(),
                 }
             }
+            self.process_timers();
         }
     }
 
     fn receive_data(&mut self, buf: &mut [u8]) {
         loop {
-            let res = self.socket.recv_from(buf);
+            let res = match &mut self.transport {
+                Some(transport) => transport.receive(buf),
+                None => {
+                    return;
+                }
+            };
             match res {
-                Ok((n, _)) => self.process_data(&buf[..n]),
+                Ok(Some((n, from))) => {
+                    if from.ip() != self.addr.ip() {
+                        self.source_filter.reject(from, n);
+                        continue;
+                    }
+
+                    let n = match self.decrypt(&mut buf[..n]) {
+                        Some(n) => n,
+                        None => continue,
+                    };
+
+                    // RTP senders don't bind to the port they send to
+                    if from == self.addr || self.is_rtp() {
+                        self.process_data(&buf[..n]);
+                    } else if self.is_server_moved(from, &buf[..n]) {
+                        self.on_server_moved(from);
+                        self.process_data(&buf[..n]);
+                    } else {
+                        self.source_filter.reject(from, n);
+                    }
+                }
+                Ok(None) => break,
                 Err(e) => {
-                    if !is_try_again(&e) {
+                    if self.is_reliable() {
+                        self.on_connection_lost(e);
+                    } else {
                         warn!("Error receiving data: {}", e);
                     }
                     break;
@@ -162,88 +449,1110 @@ impl PollLoop {
         }
     }
 
+    /// Only the audio is accepted from the group, the control messages stay unicast
+    fn receive_multicast(&mut self, buf: &mut [u8]) {
+        loop {
+            let res = match &self.multicast {
+                Some(membership) => membership.socket().recv_from(buf),
+                None => {
+                    return;
+                }
+            };
+            match res {
+                Ok((n, from)) => {
+                    let from = socket::normalize_addr(from);
+                    if from.ip() != self.addr.ip() || control::is_control_pkt(&buf[..n]) {
+                        self.source_filter.reject(from, n);
+                        continue;
+                    }
+
+                    let n = match self.decrypt(&mut buf[..n]) {
+                        Some(n) => n,
+                        None => continue,
+                    };
+                    self.process_data(&buf[..n]);
+                }
+                Err(e) => {
+                    if !is_try_again(&e) {
+                        warn!("Error receiving multicast data: {}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn on_connection_lost(&mut self, e: io::Error) {
+        warn!("Connection to {} is lost: {}", self.addr, e);
+        if let Some(transport) = self.transport.take() {
+            log_and_ignore_err!(transport.deregister(&self.poll));
+        }
+
+        match self.state {
+            State::RequestingInfo(_) | State::Starting(_) => self.connect_next(),
+            State::Streaming | State::Stalled => self.reconnect(),
+            State::Resolving | State::Disconnected => {}
+        }
+    }
+
+    fn join_multicast(&mut self, group: SocketAddr) -> Result<(), Error> {
+        self.leave_multicast();
+
+        let config = self
+            .config
+            .multicast
+            .as_ref()
+            .ok_or_else(|| Error::new_wrong_state("Multicast is not enabled"))?;
+        let membership = Membership::join(group, config)?;
+        self.poll.register(
+            membership.socket(),
+            MULTICAST_TOKEN,
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )?;
+
+        self.multicast = Some(membership);
+        Ok(())
+    }
+
+    /// The stream is requested again if the group is to be joined or left,
+    /// otherwise the switch applies to the next request
+    fn on_multicast_switched(&mut self) {
+        info!(
+            "Multicast is switched, enabled: {}",
+            self.multicast_switch.is_enabled()
+        );
+        match self.state {
+            State::Starting(_) | State::Streaming | State::Stalled => {}
+            State::Resolving | State::RequestingInfo(_) | State::Disconnected => {
+                return;
+            }
+        }
+        if self.is_multicast_offered() == self.multicast.is_some() {
+            return;
+        }
+
+        self.leave_multicast();
+        let res = self.send_info_request();
+        if let Err(e) = res {
+            warn!("Error requesting info from {}: {}", self.addr, e);
+            self.set_state(State::Disconnected);
+        }
+    }
+
+    fn leave_multicast(&mut self) {
+        if let Some(membership) = self.multicast.take() {
+            log_and_ignore_err!(self.poll.deregister(membership.socket()));
+            log_and_ignore_err!(membership.leave());
+        }
+    }
+
+    /// Returns the length of the plaintext or `None` if the datagram must be dropped
+    fn decrypt(&mut self, buf: &mut [u8]) -> Option<usize> {
+        match &mut self.cipher {
+            Some(cipher) => cipher.open(buf),
+            None => Some(buf.len()),
+        }
+    }
+
+    fn on_resolved(&mut self) {
+        let res = match self.resolver.try_get() {
+            Some(res) => res,
+            None => {
+                return;
+            }
+        };
+
+        match res {
+            Ok(addrs) => {
+                self.addrs = addrs;
+                self.next_addr_idx = 0;
+                self.connect_next();
+            }
+            Err(e) => {
+                warn!("{}", e);
+                self.report_error(e);
+                self.set_state(State::Disconnected);
+            }
+        }
+    }
+
+    /// Starts the handshake with the next address the server hostname is resolved to
+    fn connect_next(&mut self) {
+        while self.next_addr_idx < self.addrs.len() {
+            let addr = self.addrs[self.next_addr_idx];
+            self.next_addr_idx += 1;
+
+            let res = self.connect(addr);
+            match res {
+                Ok(()) => {
+                    return;
+                }
+                Err(e) => {
+                    warn!("Error connecting to {}: {}", addr, e);
+                    self.report_error(e);
+                }
+            }
+        }
+
+        warn!("No more addresses to try");
+        self.set_state(State::Disconnected);
+    }
+
+    fn connect(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        info!("Connecting to {}", addr);
+        self.close_transport()?;
+
+        let transport = self.connector.bind_udp(addr, self.local_addr)?;
+        self.open_transport(transport)?;
+        self.is_multicast_failed = false;
+
+        if let Some(info) = self.config.rtp.as_ref().map(RtpParams::stream_info) {
+            info!("Waiting for RTP on port {}: {:?}", self.local_port, info);
+            self.player.configure(&info)?;
+            self.on_started();
+            return Ok(());
+        }
+        self.send_info_request()
+    }
+
+    /// UDP may be blocked on the way, so the same address is tried over TCP
+    /// before the next one
+    fn connect_tcp(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        info!("Falling back to TCP with {}", addr);
+        self.close_transport()?;
+
+        let transport = self.connector.connect_tcp(addr)?;
+        self.open_transport(transport)?;
+        self.send_info_request()
+    }
+
+    fn open_transport(&mut self, transport: Box<dyn Transport>) -> Result<(), Error> {
+        self.local_port = transport.local_port()?;
+        transport.register(&self.poll, TRANSPORT_TOKEN)?;
+
+        self.addr = transport.peer_addr();
+        self.transport = Some(transport);
+        self.reset_session();
+        Ok(())
+    }
+
+    fn close_transport(&mut self) -> Result<(), Error> {
+        self.leave_multicast();
+        if let Some(transport) = self.transport.take() {
+            transport.deregister(&self.poll)?;
+        }
+        Ok(())
+    }
+
+    /// The server on the other end knows nothing about us
+    fn reset_session(&mut self) {
+        self.stats.lock().unwrap().reset();
+        self.rtt.lock().unwrap().reset();
+    }
+
+    fn on_handshake_timeout(&mut self) {
+        warn!("Handshake with {} timed out", self.addr);
+        if self.is_reliable() || self.is_rtp() {
+            self.connect_next();
+            return;
+        }
+
+        let addr = self.addr;
+        let res = self.connect_tcp(addr);
+        if let Err(e) = res {
+            warn!("Error connecting over TCP to {}: {}", addr, e);
+            self.report_error(e);
+            self.connect_next();
+        }
+    }
+
+    fn is_reliable(&self) -> bool {
+        self.transport.as_ref().map_or(false, |t| t.is_reliable())
+    }
+
+    fn is_rtp(&self) -> bool {
+        self.config.rtp.is_some()
+    }
+
+    /// A server restarted on a new port is accepted only if it comes from the same IP,
+    /// sends a valid control packet, and the stream from the old address isn't running.
+    fn is_server_moved(&self, from: SocketAddr, buf: &[u8]) -> bool {
+        if from.ip() != self.addr.ip() {
+            return false;
+        }
+
+        match self.state {
+            State::RequestingInfo(_) | State::Starting(_) | State::Stalled => {}
+            State::Resolving | State::Streaming | State::Disconnected => {
+                return false;
+            }
+        }
+
+        control::is_control_pkt(buf) && ControlPkt::decode(buf).is_ok()
+    }
+
+    fn on_server_moved(&mut self, new_addr: SocketAddr) {
+        warn!("Server moved from {} to {}", self.addr, new_addr);
+        self.leave_multicast();
+        self.addr = new_addr;
+        if let Some(transport) = &mut self.transport {
+            transport.set_peer_addr(new_addr);
+        }
+        self.reset_session();
+
+        let res = self.send_info_request();
+        if let Err(e) = res {
+            warn!("Error requesting info from {}: {}", self.addr, e);
+            self.set_state(State::Disconnected);
+        }
+    }
+
     fn send_stop(&mut self) {
-        let res = self.socket.send_to(b"stop", &self.addr);
+        if self.transport.is_none() || self.is_rtp() {
+            return;
+        }
+
+        let res = self.send_control(ControlMsg::Stop);
         if let Err(e) = res {
             warn!("Error sending stop to: {}. {}", self.addr, e);
         }
     }
 
     fn process_data(&mut self, buf: &[u8]) {
+        self.last_heard = Instant::now();
+
+        if control::is_control_pkt(buf) {
+            let res = ControlPkt::decode(buf).and_then(|pkt| self.process_control(pkt));
+            if let Err(e) = res {
+                warn!("Error processing control packet: {}", e);
+            }
+            return;
+        }
+
         match self.state {
-            State::InfoRequested => self.send_start(),
-            State::Started => {
-                let res = self.play(buf);
-                if let Err(e) = res {
-                    warn!("Error playing buffer: {}", e);
+            State::Resolving | State::RequestingInfo(_) | State::Disconnected => {
+                info!("Ignoring audio packet in state: {:?}", self.state);
+                return;
+            }
+            State::Starting(_) => {
+                info!("Got audio before the start ack, assuming it was lost");
+                self.on_started();
+            }
+            State::Stalled => {
+                info!("Audio is received again");
+                self.set_state(State::Streaming);
+            }
+            State::Streaming => {}
+        }
+        self.last_audio = Instant::now();
+
+        let res = self.play(buf);
+        if let Err(e) = res {
+            warn!("Error playing buffer: {}", e);
+        }
+    }
+
+    fn process_control(&mut self, pkt: ControlPkt) -> Result<(), Error> {
+        match (self.state, pkt.msg) {
+            (State::RequestingInfo(req), ControlMsg::Info(info)) if req.req_id == pkt.req_id => {
+                info!("Got stream info: {:?}", info);
+                if let Some(group) = info.multicast {
+                    // The request has been sent before multicast was switched off
+                    if !self.is_multicast_offered() {
+                        info!("Multicast is disabled, requesting unicast");
+                        return self.send_info_request();
+                    }
+                    let res = self.join_multicast(group);
+                    if let Err(e) = res {
+                        warn!("Error joining {}, falling back to unicast: {}", group, e);
+                        self.report_error(e);
+                        self.is_multicast_failed = true;
+                        return self.send_info_request();
+                    }
                 }
+                self.player.configure(&info)?;
+                self.pkt_decoder.configure(&info);
+                self.send_start()
+            }
+            (State::Starting(req), ControlMsg::Ack) if req.req_id == pkt.req_id => {
+                self.on_started();
+                Ok(())
+            }
+            (_, ControlMsg::Keepalive) => Ok(()),
+            (_, ControlMsg::Echo(timestamp)) => {
+                self.send_control_with_id(pkt.req_id, ControlMsg::EchoReply(timestamp))
+            }
+            (_, ControlMsg::EchoReply(timestamp)) => {
+                self.rtt.lock().unwrap().on_reply(pkt.req_id, timestamp);
+                Ok(())
+            }
+            (_, ControlMsg::Stop) => {
+                info!("Server stopped the stream");
+                self.leave_multicast();
+                log_and_ignore_err!(self.player.stop_playing());
+                self.set_state(State::Disconnected);
+                self.send_control_with_id(pkt.req_id, ControlMsg::Ack)
             }
+            (state, msg) => {
+                warn!(
+                    "Unexpected control message: {:?} with id: {} in state: {:?}",
+                    msg, pkt.req_id, state
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn send_info_request(&mut self) -> Result<(), Error> {
+        info!("Requesting stream info");
+        if let Some(cipher) = &mut self.cipher {
+            cipher.new_session()?;
         }
+        self.handshake_deadline = Instant::now() + self.config.handshake_timeout;
+        let req = self.send_request(ControlMsg::InfoRequest {
+            port: self.local_port,
+            features: self.offered_features(),
+        })?;
+        self.set_state(State::RequestingInfo(req));
+        Ok(())
     }
 
-    fn send_start(&mut self) {
+    fn offered_features(&self) -> u8 {
+        // The group is reached over UDP, and its audio can't be sealed with our session key
+        if self.config.multicast.is_some()
+            && !self.is_multicast_failed
+            && !self.is_reliable()
+            && self.cipher.is_none()
+            && self.multicast_switch.is_enabled()
+        {
+            control::SUPPORTED_FEATURES | control::FEATURE_MULTICAST
+        } else {
+            control::SUPPORTED_FEATURES
+        }
+    }
+
+    fn is_multicast_offered(&self) -> bool {
+        self.offered_features() & control::FEATURE_MULTICAST != 0
+    }
+
+    fn send_start(&mut self) -> Result<(), Error> {
         info!("Sending start");
+        let req = self.send_request(ControlMsg::Start)?;
+        self.set_state(State::Starting(req));
+        Ok(())
+    }
+
+    fn on_started(&mut self) {
         let res = self.player.start_playing();
         if let Err(e) = res {
             warn!("Error setting start playing: {}", e);
         }
 
-        let res = self.socket.send_to(b"start", &self.addr);
-        if let Err(e) = res {
-            warn!("Error sending start to {}: {}", self.addr, e);
+        let now = Instant::now();
+        self.last_audio = now;
+        self.next_keepalive = now + self.config.keepalive_interval;
+        self.next_report = now + self.config.report_interval;
+        self.next_echo = now;
+        self.set_state(State::Streaming);
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        let wake_at = match &self.state {
+            State::RequestingInfo(req) | State::Starting(req) => {
+                std::cmp::min(req.retransmit_at, self.handshake_deadline)
+            }
+            State::Streaming => std::cmp::min(
+                self.last_audio + self.config.stall_timeout,
+                self.next_connection_check(),
+            ),
+            State::Stalled => self.next_connection_check(),
+            State::Resolving | State::Disconnected => {
+                return None;
+            }
+        };
+
+        Some(wake_at.saturating_duration_since(Instant::now()))
+    }
+
+    fn process_timers(&mut self) {
+        let now = Instant::now();
+        match self.state {
+            State::RequestingInfo(_) | State::Starting(_) => {
+                if now >= self.handshake_deadline {
+                    self.on_handshake_timeout();
+                } else {
+                    self.retransmit_if_required(now);
+                }
+            }
+            State::Streaming | State::Stalled => {
+                if now >= self.last_heard + self.config.silence_timeout {
+                    warn!(
+                        "Nothing received from {} for {} ms.",
+                        self.addr,
+                        self.config.silence_timeout.as_millis()
+                    );
+                    self.reconnect();
+                    return;
+                }
+
+                if let State::Streaming = self.state {
+                    if now >= self.last_audio + self.config.stall_timeout {
+                        warn!("No audio for {} ms.", self.config.stall_timeout.as_millis());
+                        // Not to replay the last packet until the audio is back
+                        self.player.flush();
+                        self.set_state(State::Stalled);
+                    }
+                }
+
+                // An RTP sender is not talked to
+                if self.is_rtp() {
+                    return;
+                }
+
+                if now >= self.next_keepalive {
+                    self.next_keepalive = now + self.config.keepalive_interval;
+                    log_and_ignore_err!(self.send_control(ControlMsg::Keepalive));
+                }
+
+                if now >= self.next_report {
+                    self.next_report = now + self.config.report_interval;
+                    self.send_report();
+                }
+
+                if now >= self.next_echo {
+                    self.next_echo = now + self.config.echo_interval;
+                    self.send_echo();
+                }
+
+                if now >= self.next_nack_check {
+                    self.next_nack_check = now + self.config.nack_retry_interval;
+                    self.send_nacks_if_required();
+                }
+            }
+            State::Resolving | State::Disconnected => {}
         }
+    }
 
-        self.state = State::Started;
+    /// Checked when the loop wakes up after `next_nack_check`, which happens at least once
+    /// per frame while the audio is flowing
+    fn send_nacks_if_required(&mut self) {
+        let depth = self
+            .player
+            .collect_missing(self.config.nack_min_lead, &mut self.missing);
+        self.nack_tracker.update(
+            &mut self.missing,
+            depth,
+            self.config.nack_retry_interval,
+            &mut self.to_nack,
+        );
+
+        let to_nack = std::mem::replace(&mut self.to_nack, Vec::new());
+        for cnts in to_nack.chunks(control::MAX_NACK_CNTS) {
+            let msg = ControlMsg::Nack(cnts.to_vec());
+            log_and_ignore_err!(self.send_control(msg));
+        }
+        self.to_nack = to_nack;
+    }
+
+    fn send_report(&mut self) {
+        let (snapshot, fraction_lost) = {
+            let mut stats = self.stats.lock().unwrap();
+            (stats.snapshot(), stats.take_fraction_lost())
+        };
+        let buffer_stats = self.player.get_stats();
+
+        let report = ReceiverReport {
+            fraction_lost,
+            total_lost: snapshot.lost as u32,
+            max_cnt: snapshot.max_cnt,
+            jitter_us: snapshot.jitter.as_micros() as u32,
+            buffer_delay_ms: self.player.get_delay().as_millis() as u32,
+            concealed: buffer_stats.concealed as u32,
+        };
+        log_and_ignore_err!(self.send_control(ControlMsg::Report(report)));
+    }
+
+    fn send_echo(&mut self) {
+        let timestamp = self.rtt.lock().unwrap().timestamp();
+        let res = self.send_control(ControlMsg::Echo(timestamp));
+        match res {
+            Ok(req_id) => self.rtt.lock().unwrap().on_sent(req_id, timestamp),
+            Err(e) => warn!("Error sending echo to {}: {}", self.addr, e),
+        }
+    }
+
+    /// The closest of the periodic checks done while streaming
+    fn next_connection_check(&self) -> Instant {
+        if self.is_rtp() {
+            return self.last_heard + self.config.silence_timeout;
+        }
+
+        let checks = [
+            self.last_heard + self.config.silence_timeout,
+            self.next_keepalive,
+            self.next_report,
+            self.next_echo,
+        ];
+        *checks.iter().min().unwrap()
+    }
+
+    /// Restarts the handshake with the same player after the connection is lost.
+    /// UDP is tried first again, the network may have changed
+    fn reconnect(&mut self) {
+        info!("Reconnecting to {}", self.addr);
+        self.player.flush();
+
+        self.next_addr_idx = 0;
+        self.connect_next();
+    }
+
+    fn retransmit_if_required(&mut self, now: Instant) {
+        // TCP delivers the request anyway
+        if self.is_reliable() {
+            return;
+        }
+
+        let features = self.offered_features();
+        let (msg, req) = match &mut self.state {
+            State::RequestingInfo(req) => (
+                ControlMsg::InfoRequest {
+                    port: self.local_port,
+                    features,
+                },
+                req,
+            ),
+            State::Starting(req) => (ControlMsg::Start, req),
+            _ => {
+                return;
+            }
+        };
+
+        if now < req.retransmit_at {
+            return;
+        }
+
+        req.interval = std::cmp::min(req.interval * 2, self.config.max_retransmit_interval);
+        req.retransmit_at = now + req.interval;
+        let req_id = req.req_id;
+
+        info!("Retransmitting {:?} with id: {}", msg, req_id);
+        log_and_ignore_err!(self.send_control_with_id(req_id, msg));
+    }
+
+    fn set_state(&mut self, state: State) {
+        let prev = self.state.to_connection_state();
+        self.state = state;
+
+        if prev != self.state.to_connection_state() {
+            info!("Connection state: {:?} -> {:?}", prev, self.state);
+            self.notify_java_with_state();
+        }
+    }
+
+    fn report_error(&self, e: Error) {
+        log_and_ignore_err!(self.to_java_send.send(ToJavaMsg::Error(e)));
+    }
+
+    fn notify_java_with_state(&self) {
+        log_and_ignore_err!(self.to_java_send.send(ToJavaMsg::ConnectionStateChanged(
+            self.state.to_connection_state()
+        )));
+    }
+
+    /// Sends the request which is retransmitted until answered
+    fn send_request(&mut self, msg: ControlMsg) -> Result<PendingRequest, Error> {
+        let req_id = self.send_control(msg)?;
+        let interval = self.config.retransmit_interval;
+
+        Ok(PendingRequest {
+            req_id,
+            interval,
+            retransmit_at: Instant::now() + interval,
+        })
+    }
+
+    /// Returns the request id of the sent message
+    fn send_control(&mut self, msg: ControlMsg) -> Result<u32, Error> {
+        let req_id = self.next_req_id;
+        self.next_req_id = self.next_req_id.wrapping_add(1);
+
+        self.send_control_with_id(req_id, msg)?;
+        Ok(req_id)
+    }
+
+    fn send_control_with_id(&mut self, req_id: u32, msg: ControlMsg) -> Result<(), Error> {
+        let transport = self
+            .transport
+            .as_mut()
+            .ok_or_else(|| Error::new_wrong_state("Socket is not bound yet"))?;
+
+        ControlPkt::new(req_id, msg).encode(&mut self.send_buf);
+        if let Some(cipher) = &self.cipher {
+            cipher.seal_control(&mut self.send_buf)?;
+        }
+        transport.send(&self.send_buf)?;
+        Ok(())
     }
 
     fn play(&mut self, buf: &[u8]) -> Result<(), Error> {
         let is_changed = self.interval_measure.new_event();
         if is_changed {
-            info!("Packet intervals: {}", self.interval_measure);
+            info!(
+                "Packet intervals: {}, buffer memory: {}",
+                self.interval_measure,
+                self.player.get_stats().memory
+            );
         }
 
-        let pkt = self.pkt_decoder.parse(buf)?;
-        self.player.enqueue(&pkt)?;
+        let mut pkts = Vec::new();
+        self.pkt_decoder.parse(buf, &mut pkts)?;
+        // The primary one goes first
+        if let Some(pkt) = pkts.first().filter(|p| !p.is_fec()) {
+            self.stats.lock().unwrap().on_pkt(pkt.cnt, pkt.timestamp);
+        }
+        for pkt in &pkts {
+            self.player.enqueue(pkt)?;
+        }
 
         Ok(())
     }
 }
 
-impl Stopper {
-    fn is_stopped(&self) -> bool {
-        self.flag.load(Ordering::SeqCst)
+impl State {
+    fn to_connection_state(&self) -> ConnectionState {
+        match self {
+            State::Resolving => ConnectionState::Resolving,
+            State::RequestingInfo(_) => ConnectionState::RequestingInfo,
+            State::Starting(_) => ConnectionState::Starting,
+            State::Streaming => ConnectionState::Streaming,
+            State::Stalled => ConnectionState::Stalled,
+            State::Disconnected => ConnectionState::Disconnected,
+        }
     }
 }
 
-impl mio::Evented for Stopper {
-    fn register(
-        &self,
-        poll: &mio::Poll,
-        token: mio::Token,
-        interest: mio::Ready,
-        opts: mio::PollOpt,
-    ) -> Result<(), std::io::Error> {
-        self.registration.register(poll, token, interest, opts)
+fn is_try_again(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => true,
+        _ => false,
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pkt_decoder::{PKT_MAGIC, PKT_VERSION};
+    use super::transport::{Fate, MemoryConnector, MemoryPeer, MemoryTransport};
+    use super::*;
+    use std::cell::Cell;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const SERVER_ADDR: &str = "127.0.0.1:34567";
+    const LOCAL_PORT: u16 = 40000;
 
-    fn reregister(
-        &self,
-        poll: &mio::Poll,
-        token: mio::Token,
-        interest: mio::Ready,
-        opts: mio::PollOpt,
-    ) -> Result<(), std::io::Error> {
-        self.registration.reregister(poll, token, interest, opts)
+    struct Client {
+        net_client: NetClient,
+        player: Player,
+        to_java_recv: mpsc::Receiver<ToJavaMsg>,
     }
 
-    fn deregister(&self, poll: &mio::Poll) -> Result<(), std::io::Error> {
-        poll.deregister(&self.registration)
+    impl Client {
+        /// The transports are handed out in turn, the first one is used for UDP
+        fn start(config: Config, transports: Vec<MemoryTransport>) -> Self {
+            let connector = MemoryConnector::new();
+            for transport in transports {
+                connector.add(transport);
+            }
+
+            let (to_java_send, to_java_recv) = mpsc::channel();
+            let player = Player::new(to_java_send.clone()).unwrap();
+            let net_client = NetClient::with_connector(
+                SERVER_ADDR.to_owned(),
+                config,
+                player.clone(),
+                to_java_send,
+                Box::new(connector),
+            )
+            .unwrap();
+
+            Self {
+                net_client,
+                player,
+                to_java_recv,
+            }
+        }
+
+        /// Over the real network with the default config
+        fn connect(server_addr: SocketAddr, local_addr: &str) -> Self {
+            let (to_java_send, to_java_recv) = mpsc::channel();
+            let player = Player::new(to_java_send.clone()).unwrap();
+            let net_client = NetClient::new(
+                server_addr.to_string(),
+                local_addr.parse().unwrap(),
+                None,
+                &PairingStore::new_in_memory(),
+                Config::default(),
+                player.clone(),
+                to_java_send,
+            )
+            .unwrap();
+
+            Self {
+                net_client,
+                player,
+                to_java_recv,
+            }
+        }
+
+        fn wait_for(&self, expected: ConnectionState) {
+            loop {
+                match self.to_java_recv.recv_timeout(TIMEOUT).unwrap() {
+                    ToJavaMsg::ConnectionStateChanged(state) if state == expected => {
+                        return;
+                    }
+                    ToJavaMsg::ConnectionStateChanged(state) => {
+                        info!("Connection state: {:?}", state)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        fn wait_for_received(&self, expected: u64) -> ReceiveStatsSnapshot {
+            let deadline = Instant::now() + TIMEOUT;
+            loop {
+                let stats = self.net_client.get_stats();
+                if stats.received >= expected || Instant::now() >= deadline {
+                    return stats;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        /// Frames counted as received may still be on their way to the player
+        fn wait_for_buffered(&self, expected: usize) {
+            let deadline = Instant::now() + TIMEOUT;
+            while self.player.get_stats().memory.buffered_frames < expected {
+                assert!(Instant::now() < deadline, "Frames are not buffered");
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        fn wait_for_no_missing(&self) {
+            let deadline = Instant::now() + TIMEOUT;
+            let mut missing = Vec::new();
+            loop {
+                self.player
+                    .collect_missing(Duration::from_secs(0), &mut missing);
+                if missing.is_empty() {
+                    return;
+                }
+                assert!(Instant::now() < deadline, "Missing frames: {:?}", missing);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        /// Pulls `qty` buffers as the audio output does, returns the concealed frames so far
+        fn play(&self, qty: usize) -> usize {
+            for _ in 0..qty {
+                // The payloads are not real AAC, it's the way through the buffer that's tested
+                let _ = self.player.pull();
+            }
+            self.player.get_stats().concealed
+        }
     }
-}
 
-fn is_try_again(e: &io::Error) -> bool {
-    match e.kind() {
-        io::ErrorKind::WouldBlock => true,
-        _ => false,
+    /// The server end the tests talk to the client through
+    trait Peer {
+        fn receive_timeout(&self, timeout: Duration) -> Option<Vec<u8>>;
+
+        fn send(&self, datagram: &[u8]);
+
+        /// The local port the client is expected to announce
+        fn client_port(&self) -> u16;
+    }
+
+    impl Peer for MemoryPeer {
+        fn receive_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+            MemoryPeer::receive_timeout(self, timeout)
+        }
+
+        fn send(&self, datagram: &[u8]) {
+            MemoryPeer::send(self, datagram)
+        }
+
+        fn client_port(&self) -> u16 {
+            LOCAL_PORT
+        }
+    }
+
+    /// A server on a loopback socket, it answers the address it has last received from
+    struct UdpPeer {
+        socket: std::net::UdpSocket,
+        client_addr: Cell<Option<SocketAddr>>,
+    }
+
+    impl UdpPeer {
+        fn bind(addr: &str) -> Self {
+            Self {
+                socket: std::net::UdpSocket::bind(addr).unwrap(),
+                client_addr: Cell::new(None),
+            }
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+
+        fn client_addr(&self) -> SocketAddr {
+            self.client_addr.get().unwrap()
+        }
+    }
+
+    impl Peer for UdpPeer {
+        fn receive_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+            self.socket.set_read_timeout(Some(timeout)).unwrap();
+            let mut buf = vec![0; 65536];
+            let (n, from) = self.socket.recv_from(&mut buf).ok()?;
+            self.client_addr.set(Some(from));
+            buf.truncate(n);
+            Some(buf)
+        }
+
+        fn send(&self, datagram: &[u8]) {
+            self.socket.send_to(datagram, self.client_addr()).unwrap();
+        }
+
+        fn client_port(&self) -> u16 {
+            self.client_addr().port()
+        }
+    }
+
+    fn new_transport(is_reliable: bool) -> (MemoryTransport, MemoryPeer) {
+        MemoryTransport::pair(SERVER_ADDR.parse().unwrap(), LOCAL_PORT, is_reliable)
+    }
+
+    /// The next message from the client `is_expected` accepts, the others are skipped
+    fn expect<P, F>(peer: &P, is_expected: F) -> ControlPkt
+    where
+        P: Peer,
+        F: Fn(&ControlMsg) -> bool,
+    {
+        loop {
+            let datagram = peer
+                .receive_timeout(TIMEOUT)
+                .expect("Nothing is received from the client");
+            let pkt = ControlPkt::decode(&datagram).unwrap();
+            if is_expected(&pkt.msg) {
+                return pkt;
+            }
+            info!("Skipping {:?}", pkt.msg);
+        }
+    }
+
+    fn send_control<P: Peer>(peer: &P, req_id: u32, msg: ControlMsg) {
+        let mut buf = Vec::new();
+        ControlPkt::new(req_id, msg).encode(&mut buf);
+        peer.send(&buf);
+    }
+
+    /// Answers the info request and the start as the server does
+    fn serve_handshake<P: Peer>(peer: &P) {
+        let req = expect(peer, |msg| match msg {
+            ControlMsg::InfoRequest { port, .. } => *port == peer.client_port(),
+            _ => false,
+        });
+        let info = StreamInfo {
+            sample_rate: 48000,
+            ..StreamInfo::default()
+        };
+        send_control(peer, req.req_id, ControlMsg::Info(info));
+
+        let req = expect(peer, |msg| *msg == ControlMsg::Start);
+        send_control(peer, req.req_id, ControlMsg::Ack);
+    }
+
+    fn audio_pkt(cnt: u32) -> Vec<u8> {
+        let mut pkt = PKT_MAGIC.to_be_bytes().to_vec();
+        pkt.extend(&[PKT_VERSION, Codec::Aac.to_id(), 0]);
+        pkt.extend(&cnt.to_be_bytes());
+        pkt.extend(&(cnt as u64 * 21333).to_be_bytes());
+        pkt.extend(&[0; 16]);
+        pkt
+    }
+
+    #[test]
+    fn retransmits_lost_handshake_requests() {
+        let (transport, peer) = new_transport(false);
+        // The first info request and the first start are lost, the info is late
+        peer.script_from_client(&[Fate::Lose, Fate::Deliver, Fate::Lose]);
+        peer.script_to_client(&[Fate::Delay(Duration::from_millis(50))]);
+        let client = Client::start(Config::default(), vec![transport]);
+
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+    }
+
+    #[test]
+    fn receives_audio_from_server_only() {
+        let (transport, peer) = new_transport(false);
+        let client = Client::start(Config::default(), vec![transport]);
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        let mut stranger = peer.addr();
+        stranger.set_ip("127.0.0.2".parse().unwrap());
+        peer.send_from(stranger, &audio_pkt(0));
+        for cnt in 1..=5 {
+            peer.send(&audio_pkt(cnt));
+        }
+
+        let stats = client.wait_for_received(5);
+        assert_eq!(stats.received, 5);
+        assert_eq!(stats.max_cnt, 5);
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn counts_lost_and_reordered_audio() {
+        let (transport, peer) = new_transport(false);
+        let client = Client::start(Config::default(), vec![transport]);
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        // cnt 3 is lost, cnt 5 arrives after 7
+        peer.script_to_client(&[
+            Fate::Deliver,
+            Fate::Deliver,
+            Fate::Deliver,
+            Fate::Lose,
+            Fate::Deliver,
+            Fate::Reorder(2),
+        ]);
+        for cnt in 0..10 {
+            peer.send(&audio_pkt(cnt));
+        }
+
+        let stats = client.wait_for_received(9);
+        assert_eq!(stats.received, 9);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.max_cnt, 9);
+    }
+
+    #[test]
+    fn conceals_lost_frame_when_played() {
+        let (transport, peer) = new_transport(false);
+        let client = Client::start(Config::default(), vec![transport]);
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        peer.script_to_client(&[Fate::Deliver, Fate::Deliver, Fate::Deliver, Fate::Lose]);
+        for cnt in 0..10 {
+            peer.send(&audio_pkt(cnt));
+        }
+
+        // cnt 0 is played on arrival, the gap of cnt 3 is buffered
+        client.wait_for_buffered(9);
+        assert_eq!(client.play(2), 0);
+        assert_eq!(client.play(1), 1);
+        assert_eq!(client.play(6), 1);
+    }
+
+    #[test]
+    fn plays_retransmitted_frame_instead_of_concealing() {
+        let (transport, peer) = new_transport(false);
+        let client = Client::start(Config::default(), vec![transport]);
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        peer.script_to_client(&[Fate::Deliver, Fate::Deliver, Fate::Deliver, Fate::Lose]);
+        for cnt in 0..10 {
+            peer.send(&audio_pkt(cnt));
+        }
+
+        expect(&peer, |msg| match msg {
+            ControlMsg::Nack(cnts) => cnts.contains(&3),
+            _ => false,
+        });
+        peer.send(&audio_pkt(3));
+
+        client.wait_for_buffered(9);
+        client.wait_for_no_missing();
+        assert_eq!(client.play(9), 0);
+    }
+
+    #[test]
+    fn sends_stop_to_server() {
+        let (transport, peer) = new_transport(false);
+        let mut client = Client::start(Config::default(), vec![transport]);
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        client.net_client.stop().unwrap();
+        expect(&peer, |msg| *msg == ControlMsg::Stop);
+    }
+
+    #[test]
+    fn disconnects_when_server_stops() {
+        let (transport, peer) = new_transport(false);
+        let client = Client::start(Config::default(), vec![transport]);
+        serve_handshake(&peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        send_control(&peer, 100, ControlMsg::Stop);
+        client.wait_for(ConnectionState::Disconnected);
+        let ack = expect(&peer, |msg| *msg == ControlMsg::Ack);
+        assert_eq!(ack.req_id, 100);
+    }
+
+    #[test]
+    fn falls_back_to_tcp_and_reconnects_over_udp() {
+        let (udp, _silent_peer) = new_transport(false);
+        let (tcp, tcp_peer) = new_transport(true);
+        let (udp_again, udp_peer) = new_transport(false);
+        let config = Config {
+            handshake_timeout: Duration::from_millis(300),
+            ..Config::default()
+        };
+        let client = Client::start(config, vec![udp, tcp, udp_again]);
+
+        serve_handshake(&tcp_peer);
+        client.wait_for(ConnectionState::Streaming);
+
+        tcp_peer.close();
+        serve_handshake(&udp_peer);
+        client.wait_for(ConnectionState::Streaming);
+    }
+
+    #[test]
+    fn streams_from_ipv6_loopback_server() {
+        let server = UdpPeer::bind("[::1]:0");
+        // The default IPv4 wildcard can't reach an IPv6 server, it's replaced
+        let client = Client::connect(server.addr(), "0.0.0.0:0");
+
+        serve_handshake(&server);
+        client.wait_for(ConnectionState::Streaming);
+        assert!(server.client_addr().is_ipv6());
+
+        for cnt in 0..3 {
+            server.send(&audio_pkt(cnt));
+        }
+        assert_eq!(client.wait_for_received(3).received, 3);
+    }
+
+    #[test]
+    fn streams_from_ipv4_server_over_dual_stack_socket() {
+        let server = UdpPeer::bind("127.0.0.1:0");
+        let client = Client::connect(server.addr(), "[::]:0");
+
+        serve_handshake(&server);
+        client.wait_for(ConnectionState::Streaming);
+
+        // The IPv4-mapped sender is accepted as the IPv4 server
+        for cnt in 0..3 {
+            server.send(&audio_pkt(cnt));
+        }
+        assert_eq!(client.wait_for_received(3).received, 3);
     }
 }
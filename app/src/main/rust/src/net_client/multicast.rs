@@ -0,0 +1,370 @@
+use super::socket;
+use crate::error::Error;
+use log::{info, warn};
+use mio::net::UdpSocket;
+use net2::UdpBuilder;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Where the multicast groups announced by the server are joined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MulticastConfig {
+    /// The address of the interface for IPv4 groups, unspecified: chosen by the system
+    pub interface_v4: Ipv4Addr,
+    /// The interface index for IPv6 groups, 0: chosen by the system
+    pub interface_v6: u32,
+}
+
+/// The membership in a multicast group with the socket receiving from it.
+/// The group is left on drop, if `leave` isn't called before.
+pub struct Membership {
+    socket: UdpSocket,
+    group: SocketAddr,
+    config: MulticastConfig,
+    is_joined: bool,
+}
+
+/// Registered in the `mio::Poll` of `PollLoop`, becomes readable when multicast is switched
+/// with `MulticastHandle::set_enabled` from another thread
+pub struct MulticastSwitch {
+    registration: mio::Registration,
+    is_enabled: Arc<AtomicBool>,
+}
+
+pub struct MulticastHandle {
+    set_readiness: mio::SetReadiness,
+    is_enabled: Arc<AtomicBool>,
+}
+
+impl MulticastConfig {
+    /// `interface` is empty for the system default, an IPv4 address,
+    /// or the name or the index of the interface for IPv6
+    pub fn parse(interface: &str) -> Result<Self, Error> {
+        let mut config = Self::default();
+        if interface.is_empty() {
+            return Ok(config);
+        }
+
+        match interface.parse() {
+            Ok(ip) => config.interface_v4 = ip,
+            Err(_) => config.interface_v6 = socket::parse_scope_id(interface)?,
+        }
+        Ok(config)
+    }
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            interface_v4: Ipv4Addr::UNSPECIFIED,
+            interface_v6: 0,
+        }
+    }
+}
+
+impl Membership {
+    /// Binds to the group address, so only the datagrams sent to the group are received,
+    /// and joins it. The port may be shared with the other receivers of the group.
+    pub fn join(group: SocketAddr, config: &MulticastConfig) -> Result<Self, Error> {
+        if !group.ip().is_multicast() {
+            return Err(Error::new_wrong_argument(format!(
+                "Not a multicast group: {}",
+                group
+            )));
+        }
+
+        let builder = match group {
+            SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let builder = UdpBuilder::new_v6()?;
+                builder.only_v6(true)?;
+                builder
+            }
+        };
+        builder.reuse_address(true)?;
+        // A link-local group can be bound only with the interface it's joined on
+        let bind_addr = match group {
+            SocketAddr::V6(mut addr) if addr.scope_id() == 0 => {
+                addr.set_scope_id(config.interface_v6);
+                SocketAddr::V6(addr)
+            }
+            _ => group,
+        };
+        let socket = builder
+            .bind(bind_addr)
+            .map_err(|e| Error::new_io(e, format!("binding to {}", bind_addr)))?;
+        let socket = UdpSocket::from_socket(socket)?;
+
+        match group.ip() {
+            IpAddr::V4(ip) => socket.join_multicast_v4(&ip, &config.interface_v4),
+            IpAddr::V6(ip) => socket.join_multicast_v6(&ip, config.interface_v6),
+        }
+        .map_err(|e| Error::new_io(e, format!("joining {}", group)))?;
+        info!("Joined multicast group {} with {:?}", group, config);
+
+        Ok(Self {
+            socket,
+            group,
+            config: *config,
+            is_joined: true,
+        })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn leave(mut self) -> Result<(), Error> {
+        self.leave_group()
+    }
+
+    fn leave_group(&mut self) -> Result<(), Error> {
+        if !self.is_joined {
+            return Ok(());
+        }
+        self.is_joined = false;
+
+        match self.group.ip() {
+            IpAddr::V4(ip) => self
+                .socket
+                .leave_multicast_v4(&ip, &self.config.interface_v4),
+            IpAddr::V6(ip) => self
+                .socket
+                .leave_multicast_v6(&ip, self.config.interface_v6),
+        }
+        .map_err(|e| Error::new_io(e, format!("leaving {}", self.group)))?;
+        info!("Left multicast group {}", self.group);
+        Ok(())
+    }
+}
+
+impl MulticastSwitch {
+    pub fn new() -> (Self, MulticastHandle) {
+        let (registration, set_readiness) = mio::Registration::new2();
+        let is_enabled = Arc::new(AtomicBool::new(true));
+
+        let switch = Self {
+            registration,
+            is_enabled: is_enabled.clone(),
+        };
+        let handle = MulticastHandle {
+            set_readiness,
+            is_enabled,
+        };
+        (switch, handle)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled.load(Ordering::SeqCst)
+    }
+}
+
+impl MulticastHandle {
+    pub fn set_enabled(&self, is_enabled: bool) -> Result<(), Error> {
+        self.is_enabled.store(is_enabled, Ordering::SeqCst);
+        // Registered as edge-triggered, so it's lowered first to be raised again
+        self.set_readiness.set_readiness(mio::Ready::empty())?;
+        self.set_readiness.set_readiness(mio::Ready::readable())?;
+        Ok(())
+    }
+}
+
+impl mio::Evented for MulticastSwitch {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> Result<(), std::io::Error> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> Result<(), std::io::Error> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> Result<(), std::io::Error> {
+        poll.deregister(&self.registration)
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let res = self.leave_group();
+        if let Err(e) = res {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io;
+    use std::net::Ipv6Addr;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn lo_index() -> u32 {
+        socket::parse_scope_id("lo").unwrap()
+    }
+
+    /// Whether the kernel has the group joined on `lo`, as in `ip maddr show dev lo`
+    fn is_joined_on_lo(group: IpAddr) -> bool {
+        match group {
+            IpAddr::V4(ip) => {
+                // Listed under the device, in the host byte order
+                let group = format!("{:08X}", u32::from_ne_bytes(ip.octets()));
+                let igmp = fs::read_to_string("/proc/net/igmp").unwrap();
+                let mut device = "";
+                igmp.lines().skip(1).any(|line| {
+                    if !line.starts_with('\t') {
+                        device = line.split_whitespace().nth(1).unwrap_or("");
+                        false
+                    } else {
+                        device == "lo" && line.split_whitespace().next() == Some(&group)
+                    }
+                })
+            }
+            IpAddr::V6(ip) => {
+                let group: String = ip.octets().iter().map(|b| format!("{:02x}", b)).collect();
+                let igmp6 = fs::read_to_string("/proc/net/igmp6").unwrap();
+                igmp6.lines().any(|line| {
+                    let fields: Vec<_> = line.split_whitespace().collect();
+                    fields.get(1) == Some(&"lo") && fields.get(2) == Some(&group.as_str())
+                })
+            }
+        }
+    }
+
+    /// Sends through `lo`, the default route goes elsewhere
+    fn new_sender(group: IpAddr) -> std::net::UdpSocket {
+        let (socket, res) = match group {
+            IpAddr::V4(_) => {
+                let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+                let interface = libc::in_addr {
+                    s_addr: u32::from_ne_bytes(Ipv4Addr::LOCALHOST.octets()),
+                };
+                let res = unsafe {
+                    libc::setsockopt(
+                        socket.as_raw_fd(),
+                        libc::IPPROTO_IP,
+                        libc::IP_MULTICAST_IF,
+                        &interface as *const _ as *const libc::c_void,
+                        std::mem::size_of_val(&interface) as libc::socklen_t,
+                    )
+                };
+                (socket, res)
+            }
+            IpAddr::V6(_) => {
+                let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).unwrap();
+                let interface = lo_index() as libc::c_uint;
+                let res = unsafe {
+                    libc::setsockopt(
+                        socket.as_raw_fd(),
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_MULTICAST_IF,
+                        &interface as *const _ as *const libc::c_void,
+                        std::mem::size_of_val(&interface) as libc::socklen_t,
+                    )
+                };
+                (socket, res)
+            }
+        };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        socket
+    }
+
+    fn join(group: &str, config: &MulticastConfig) -> (Membership, SocketAddr) {
+        let membership = Membership::join(group.parse().unwrap(), config).unwrap();
+        // Bound to port 0, the group is reached at the port chosen
+        let group = membership.socket().local_addr().unwrap();
+        assert!(is_joined_on_lo(group.ip()));
+        (membership, group)
+    }
+
+    fn receive_from_group(membership: &Membership, group: SocketAddr) {
+        let sender = new_sender(group.ip());
+        let res = sender.send_to(b"audio", group);
+        match res {
+            Ok(_) => {}
+            // Linux has no IPv6 multicast route through `lo` unless it's given
+            // the MULTICAST flag, the membership is still checked
+            Err(ref e) if group.is_ipv6() && e.kind() == io::ErrorKind::NetworkUnreachable => {
+                warn!("Can't send to {} through lo: {}", group, e);
+                return;
+            }
+            Err(e) => panic!("{}", e),
+        }
+
+        let mut buf = [0; 16];
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match membership.socket().recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    assert_eq!(&buf[..n], b"audio");
+                    assert_eq!(socket::normalize_addr(from), sender.local_addr().unwrap());
+                    return;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "Nothing received from {}", group);
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn receives_ipv4_group_on_loopback() {
+        let config = MulticastConfig::parse("127.0.0.1").unwrap();
+        let (membership, group) = join("239.255.77.1:0", &config);
+
+        receive_from_group(&membership, group);
+        membership.leave().unwrap();
+        assert!(!is_joined_on_lo(group.ip()));
+    }
+
+    #[test]
+    fn receives_ipv6_group_on_loopback() {
+        let config = MulticastConfig::parse("lo").unwrap();
+        assert_eq!(config.interface_v6, lo_index());
+        let (membership, group) = join("[ff12::5eed:1]:0", &config);
+
+        receive_from_group(&membership, group);
+        membership.leave().unwrap();
+        assert!(!is_joined_on_lo(group.ip()));
+    }
+
+    #[test]
+    fn drop_leaves_group() {
+        let config = MulticastConfig::parse("127.0.0.1").unwrap();
+        let (membership, group) = join("239.255.77.2:0", &config);
+        drop(membership);
+        assert!(!is_joined_on_lo(group.ip()));
+
+        let config = MulticastConfig::parse(&lo_index().to_string()).unwrap();
+        let (membership, group) = join("[ff12::5eed:2]:0", &config);
+        drop(membership);
+        assert!(!is_joined_on_lo(group.ip()));
+    }
+
+    #[test]
+    fn unicast_address_is_not_joined() {
+        let config = MulticastConfig::default();
+        assert!(Membership::join("127.0.0.1:5004".parse().unwrap(), &config).is_err());
+        assert!(Membership::join("[::1]:5004".parse().unwrap(), &config).is_err());
+    }
+}
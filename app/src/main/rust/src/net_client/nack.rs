@@ -0,0 +1,124 @@
+use log::info;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Decides which missing frames should be requested from the server again
+pub struct NackTracker {
+    pending: HashMap<u32, NackEntry>,
+}
+
+struct NackEntry {
+    retries: usize,
+    last_sent: Instant,
+}
+
+impl NackTracker {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// `missing` are the frames which can still be played if they arrive now, sorted in place.
+    /// A frame is requested `retry_interval` apart, at most as many times as there are frames
+    /// in the jitter buffer (`buffer_depth`): a longer buffer leaves time for more retries.
+    pub fn update(
+        &mut self,
+        missing: &mut [u32],
+        buffer_depth: usize,
+        retry_interval: Duration,
+        to_request: &mut Vec<u32>,
+    ) {
+        to_request.clear();
+
+        // Arrived or too late
+        missing.sort_unstable();
+        self.pending
+            .retain(|cnt, _| missing.binary_search(cnt).is_ok());
+
+        let now = Instant::now();
+        for &cnt in missing.iter() {
+            let entry = self.pending.entry(cnt).or_insert(NackEntry {
+                retries: 0,
+                last_sent: now,
+            });
+
+            if entry.retries == 0 {
+                info!("Requesting missing frame {}", cnt);
+            } else if entry.retries >= buffer_depth || now < entry.last_sent + retry_interval {
+                continue;
+            }
+
+            entry.retries += 1;
+            entry.last_sent = now;
+            to_request.push(cnt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_INTERVAL: Duration = Duration::from_secs(3600);
+
+    fn update(
+        tracker: &mut NackTracker,
+        missing: &[u32],
+        buffer_depth: usize,
+        retry_interval: Duration,
+    ) -> Vec<u32> {
+        let mut missing = missing.to_vec();
+        let mut to_request = vec![42];
+        tracker.update(&mut missing, buffer_depth, retry_interval, &mut to_request);
+        to_request
+    }
+
+    #[test]
+    fn requests_new_missing_frames_immediately() {
+        let mut tracker = NackTracker::new();
+
+        assert_eq!(
+            update(&mut tracker, &[7, 3, 5], 8, LONG_INTERVAL),
+            [3, 5, 7]
+        );
+        assert_eq!(update(&mut tracker, &[3, 5, 7, 9], 8, LONG_INTERVAL), [9]);
+    }
+
+    #[test]
+    fn waits_retry_interval_before_requesting_again() {
+        let mut tracker = NackTracker::new();
+        let retry_interval = Duration::from_millis(20);
+
+        assert_eq!(update(&mut tracker, &[1], 8, retry_interval), [1]);
+        assert!(update(&mut tracker, &[1], 8, retry_interval).is_empty());
+
+        std::thread::sleep(retry_interval);
+        assert_eq!(update(&mut tracker, &[1], 8, retry_interval), [1]);
+    }
+
+    #[test]
+    fn retries_are_limited_by_buffer_depth() {
+        let mut tracker = NackTracker::new();
+
+        let requested: usize = (0..10)
+            .map(|_| update(&mut tracker, &[1], 3, Duration::from_secs(0)).len())
+            .sum();
+        assert_eq!(requested, 3);
+    }
+
+    #[test]
+    fn forgets_frames_no_longer_missing() {
+        let mut tracker = NackTracker::new();
+        let no_wait = Duration::from_secs(0);
+
+        assert_eq!(update(&mut tracker, &[1, 2], 2, no_wait), [1, 2]);
+        assert_eq!(update(&mut tracker, &[2], 2, no_wait), [2]);
+        assert_eq!(tracker.pending.len(), 1);
+
+        // 1 is a new loss now, 2 ran out of retries
+        assert_eq!(update(&mut tracker, &[1, 2], 2, no_wait), [1]);
+        assert!(update(&mut tracker, &[], 2, no_wait).is_empty());
+        assert!(tracker.pending.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+//! The cryptographic part of the pairing, independent of the transport.
+//!
+//! 1. client -> `PairStart`, server -> `PairCommit(SHA-256(server key))`
+//! 2. client -> `PairKey(client key)`, server -> `PairKey(server key)`
+//! 3. Both sides derive a PIN from the transcript, the server shows it, the user enters it
+//!    on the phone. The commitment keeps a man in the middle from choosing its key
+//!    to get the same PIN on both sides.
+//! 4. client -> `PairConfirm`, server -> `Ack`. Both sides keep the derived PSK.
+
+use crate::error::Error;
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+pub const PIN_DIGITS: usize = 6;
+pub const PSK_LEN: usize = 32;
+
+const PSK_INFO: &[u8] = b"stream-audio v1 pairing psk";
+const CONFIRM_INFO: &[u8] = b"stream-audio v1 pairing confirm";
+const PIN_PREFIX: &[u8] = b"stream-audio v1 pairing pin";
+
+pub struct ClientExchange {
+    secret: Option<EphemeralSecret>,
+    public: PublicKey,
+    commitment: Option<[u8; 32]>,
+    keys: Option<SessionKeys>,
+}
+
+/// The server side of the exchange, it's what the in-process stand-in server runs
+#[cfg(test)]
+pub struct ServerExchange {
+    secret: Option<EphemeralSecret>,
+    public: PublicKey,
+    client_key: Option<[u8; 32]>,
+    keys: Option<SessionKeys>,
+}
+
+struct SessionKeys {
+    pin: String,
+    psk: [u8; PSK_LEN],
+    confirm: [u8; 32],
+}
+
+impl ClientExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self {
+            secret: Some(secret),
+            public,
+            commitment: None,
+            keys: None,
+        }
+    }
+
+    /// Returns the public key to send after the commitment is received
+    pub fn on_commit(&mut self, commitment: [u8; 32]) -> [u8; 32] {
+        self.commitment = Some(commitment);
+        self.public.to_bytes()
+    }
+
+    pub fn on_server_key(&mut self, server_key: [u8; 32]) -> Result<(), Error> {
+        let commitment = self
+            .commitment
+            .ok_or_else(|| Error::new_wrong_state("The server key came before the commitment"))?;
+        let server_key = PublicKey::from(server_key);
+        if commit(&server_key) != commitment {
+            return Err(Error::new_crypto(
+                "The server key doesn't match its commitment",
+            ));
+        }
+
+        let secret = self
+            .secret
+            .take()
+            .ok_or_else(|| Error::new_wrong_state("The server key is already received"))?;
+        let shared = secret.diffie_hellman(&server_key);
+
+        self.keys = Some(SessionKeys::derive(
+            shared.as_bytes(),
+            &commitment,
+            &self.public,
+            &server_key,
+        )?);
+        Ok(())
+    }
+
+    /// Returns the PSK and the `PairConfirm` body if the PIN entered by the user is correct
+    pub fn confirm(&self, entered_pin: &str) -> Result<([u8; PSK_LEN], [u8; 32]), Error> {
+        let keys = self
+            .keys
+            .as_ref()
+            .ok_or_else(|| Error::new_wrong_state("The keys are not exchanged yet"))?;
+
+        if !is_eq(entered_pin.trim().as_bytes(), keys.pin.as_bytes()) {
+            return Err(Error::new_crypto("Wrong PIN"));
+        }
+        Ok((keys.psk, keys.confirm))
+    }
+}
+
+#[cfg(test)]
+impl ServerExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self {
+            secret: Some(secret),
+            public,
+            client_key: None,
+            keys: None,
+        }
+    }
+
+    pub fn commitment(&self) -> [u8; 32] {
+        commit(&self.public)
+    }
+
+    /// Returns the server public key, a retransmitted client key gets the same answer
+    pub fn on_client_key(&mut self, client_key: [u8; 32]) -> Result<[u8; 32], Error> {
+        if self.client_key == Some(client_key) {
+            return Ok(self.public.to_bytes());
+        }
+
+        let secret = self
+            .secret
+            .take()
+            .ok_or_else(|| Error::new_wrong_state("The client key is already received"))?;
+        self.client_key = Some(client_key);
+        let client_key = PublicKey::from(client_key);
+        let shared = secret.diffie_hellman(&client_key);
+
+        self.keys = Some(SessionKeys::derive(
+            shared.as_bytes(),
+            &self.commitment(),
+            &client_key,
+            &self.public,
+        )?);
+        Ok(self.public.to_bytes())
+    }
+
+    /// The PIN to show to the user
+    pub fn pin(&self) -> Option<&str> {
+        self.keys.as_ref().map(|k| k.pin.as_str())
+    }
+
+    /// Returns the PSK if the client has entered the right PIN
+    pub fn on_confirm(&self, confirm: &[u8; 32]) -> Result<[u8; PSK_LEN], Error> {
+        let keys = self
+            .keys
+            .as_ref()
+            .ok_or_else(|| Error::new_wrong_state("The keys are not exchanged yet"))?;
+
+        if !is_eq(confirm, &keys.confirm) {
+            return Err(Error::new_crypto("Pairing is not confirmed"));
+        }
+        Ok(keys.psk)
+    }
+}
+
+impl SessionKeys {
+    fn derive(
+        shared: &[u8; 32],
+        commitment: &[u8; 32],
+        client_key: &PublicKey,
+        server_key: &PublicKey,
+    ) -> Result<Self, Error> {
+        let transcript = Sha256::new()
+            .chain_update(commitment)
+            .chain_update(client_key.as_bytes())
+            .chain_update(server_key.as_bytes())
+            .finalize();
+
+        let pin_hash = Sha256::new()
+            .chain_update(PIN_PREFIX)
+            .chain_update(&transcript)
+            .finalize();
+        let pin_num = u64::from_be_bytes([
+            pin_hash[0],
+            pin_hash[1],
+            pin_hash[2],
+            pin_hash[3],
+            pin_hash[4],
+            pin_hash[5],
+            pin_hash[6],
+            pin_hash[7],
+        ]) % 10u64.pow(PIN_DIGITS as u32);
+        let pin = format!("{:0width$}", pin_num, width = PIN_DIGITS);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript), shared);
+        let mut psk = [0; PSK_LEN];
+        let mut confirm = [0; 32];
+        hkdf.expand(PSK_INFO, &mut psk)
+            .and_then(|_| hkdf.expand(CONFIRM_INFO, &mut confirm))
+            .map_err(|_| Error::new_crypto("Cannot derive the pairing keys"))?;
+
+        Ok(Self { pin, psk, confirm })
+    }
+}
+
+fn commit(key: &PublicKey) -> [u8; 32] {
+    let mut commitment = [0; 32];
+    commitment.copy_from_slice(&Sha256::digest(key.as_bytes()));
+    commitment
+}
+
+/// Constant time, not to leak how many leading bytes match
+fn is_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
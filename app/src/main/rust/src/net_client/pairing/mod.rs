@@ -0,0 +1,382 @@
+mod exchange;
+#[cfg(test)]
+mod stand_in;
+mod store;
+
+use self::exchange::ClientExchange;
+#[cfg(test)]
+use self::stand_in::StandInServer;
+pub use self::store::{PairingRecord, PairingStore};
+use super::control::{ControlMsg, ControlPkt};
+use super::resolver;
+use crate::error::Error;
+use crate::jni_ffi::ToJavaMsg;
+use log::{info, warn};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(300);
+const MAX_ATTEMPTS: u32 = 10;
+/// The user has this much time to read the PIN on the server and type it in
+const PIN_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_PIN_ATTEMPTS: u32 = 3;
+
+/// Pairs with a server in its own thread and saves the record into the store.
+/// The progress is reported to Java as `ToJavaMsg::PairingStateChanged`.
+pub struct Pairing {
+    pin_send: mpsc::Sender<UserInput>,
+    reporter: Arc<Mutex<Reporter>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+/// The pairing state reported to Java
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PairingState {
+    ExchangingKeys = 0,
+    WaitingForPin = 1,
+    Confirming = 2,
+    Paired = 3,
+    Failed = 4,
+    Cancelled = 5,
+}
+
+enum UserInput {
+    Pin(String),
+    Cancel,
+}
+
+/// Shared with the owner of the thread, so nothing is reported once the pairing is cancelled,
+/// a new pairing may be reporting already
+struct Reporter {
+    to_java_send: mpsc::Sender<ToJavaMsg>,
+    is_over: bool,
+}
+
+struct PairingLoop {
+    server: String,
+    socket: Option<UdpSocket>,
+    store: Arc<Mutex<PairingStore>>,
+    pin_recv: mpsc::Receiver<UserInput>,
+    reporter: Arc<Mutex<Reporter>>,
+    next_req_id: u32,
+    send_buf: Vec<u8>,
+}
+
+enum Interrupt {
+    /// By the user, it's not an error to report
+    Cancelled,
+    Failed(Error),
+}
+
+impl Pairing {
+    /// `server` is either `ip:port` or `hostname:port`, the record is saved under this name
+    pub fn start(
+        server: String,
+        store: Arc<Mutex<PairingStore>>,
+        to_java_send: mpsc::Sender<ToJavaMsg>,
+    ) -> Self {
+        let (pin_send, pin_recv) = mpsc::channel();
+        let reporter = Arc::new(Mutex::new(Reporter {
+            to_java_send,
+            is_over: false,
+        }));
+        let pairing_loop = PairingLoop {
+            server,
+            socket: None,
+            store,
+            pin_recv,
+            reporter: reporter.clone(),
+            next_req_id: 1,
+            send_buf: Vec::new(),
+        };
+        let join_handle = thread::spawn(move || pairing_loop.run());
+
+        Self {
+            pin_send,
+            reporter,
+            join_handle: Some(join_handle),
+        }
+    }
+
+    /// The PIN shown by the server
+    pub fn enter_pin(&self, pin: String) -> Result<(), Error> {
+        self.pin_send
+            .send(UserInput::Pin(pin))
+            .map_err(|_| Error::new_wrong_state("Pairing is over"))
+    }
+
+    /// Doesn't wait for the thread, it may be resolving the server name or waiting for a reply.
+    /// It's joined in the background and reports nothing after this
+    pub fn cancel(&mut self) {
+        // The thread may be over already
+        let _ = self.pin_send.send(UserInput::Cancel);
+        self.reporter
+            .lock()
+            .unwrap()
+            .set_state(PairingState::Cancelled);
+
+        if let Some(join_handle) = self.join_handle.take() {
+            thread::spawn(move || {
+                let res = join_handle.join();
+                if let Err(_) = res {
+                    warn!("Pairing thread panicked");
+                }
+            });
+        }
+    }
+}
+impl Drop for Pairing {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl PairingLoop {
+    fn run(mut self) {
+        let res = self.pair();
+        match res {
+            Ok(()) => self.set_state(PairingState::Paired),
+            Err(Interrupt::Cancelled) => self.set_state(PairingState::Cancelled),
+            Err(Interrupt::Failed(e)) => {
+                warn!("Pairing with {} failed: {}", self.server, e);
+                self.report_error(e);
+                self.set_state(PairingState::Failed);
+            }
+        }
+    }
+
+    fn pair(&mut self) -> Result<(), Interrupt> {
+        self.set_state(PairingState::ExchangingKeys);
+        self.connect()?;
+
+        let mut exchange = ClientExchange::new();
+        let commitment = match self.request(ControlMsg::PairStart)? {
+            ControlMsg::PairCommit(commitment) => commitment,
+            msg => return Err(unexpected_reply(msg)),
+        };
+
+        let client_key = exchange.on_commit(commitment);
+        match self.request(ControlMsg::PairKey(client_key))? {
+            ControlMsg::PairKey(server_key) => exchange.on_server_key(server_key)?,
+            msg => return Err(unexpected_reply(msg)),
+        }
+
+        self.set_state(PairingState::WaitingForPin);
+        let mut attempts = 0;
+        let (psk, confirm) = loop {
+            let pin = match self.pin_recv.recv_timeout(PIN_TIMEOUT) {
+                Ok(UserInput::Pin(pin)) => pin,
+                Ok(UserInput::Cancel) => return Err(Interrupt::Cancelled),
+                Err(_) => return Err(Error::new_wrong_state("No PIN is entered in time").into()),
+            };
+
+            match exchange.confirm(&pin) {
+                Ok(res) => break res,
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= MAX_PIN_ATTEMPTS {
+                        return Err(e.into());
+                    }
+                    self.report_error(e);
+                }
+            }
+        };
+
+        self.set_state(PairingState::Confirming);
+        match self.request(ControlMsg::PairConfirm(confirm))? {
+            ControlMsg::Ack => {}
+            msg => return Err(unexpected_reply(msg)),
+        }
+
+        info!("Paired with {}", self.server);
+        let record = PairingRecord {
+            server: self.server.clone(),
+            psk,
+        };
+        self.store.lock().map_err(Error::from)?.save(record)?;
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<(), Error> {
+        let addr = resolver::resolve(&self.server)?[0];
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(RETRANSMIT_INTERVAL))?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    /// Sends `msg` until it's answered, checking for the cancellation between the retries
+    fn request(&mut self, msg: ControlMsg) -> Result<ControlMsg, Interrupt> {
+        let socket = self
+            .socket
+            .as_ref()
+            .ok_or_else(|| Error::new_wrong_state("Socket is not bound yet"))?;
+
+        let req_id = self.next_req_id;
+        self.next_req_id = self.next_req_id.wrapping_add(1);
+        ControlPkt::new(req_id, msg).encode(&mut self.send_buf);
+
+        let mut buf = vec![0; 1500];
+        for _ in 0..MAX_ATTEMPTS {
+            if let Ok(UserInput::Cancel) = self.pin_recv.try_recv() {
+                return Err(Interrupt::Cancelled);
+            }
+
+            socket.send(&self.send_buf).map_err(Error::from)?;
+            loop {
+                let n = match socket.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) if is_timeout(&e) => break,
+                    Err(e) => return Err(Error::from(e).into()),
+                };
+
+                match ControlPkt::decode(&buf[..n]) {
+                    Ok(pkt) if pkt.req_id == req_id => return Ok(pkt.msg),
+                    Ok(pkt) => info!("Ignoring stale pairing reply: {:?}", pkt),
+                    Err(e) => warn!("Error parsing pairing reply: {}", e),
+                }
+            }
+        }
+
+        Err(Error::new_wrong_state("The server doesn't answer the pairing").into())
+    }
+
+    fn set_state(&self, state: PairingState) {
+        self.reporter.lock().unwrap().set_state(state);
+    }
+
+    fn report_error(&self, e: Error) {
+        self.reporter.lock().unwrap().report_error(e);
+    }
+}
+
+impl Reporter {
+    fn set_state(&mut self, state: PairingState) {
+        if self.is_over {
+            return;
+        }
+        self.is_over = match state {
+            PairingState::ExchangingKeys
+            | PairingState::WaitingForPin
+            | PairingState::Confirming => false,
+            PairingState::Paired | PairingState::Failed | PairingState::Cancelled => true,
+        };
+
+        info!("Pairing state: {:?}", state);
+        log_and_ignore_err!(self
+            .to_java_send
+            .send(ToJavaMsg::PairingStateChanged(state)));
+    }
+
+    fn report_error(&self, e: Error) {
+        if !self.is_over {
+            log_and_ignore_err!(self.to_java_send.send(ToJavaMsg::Error(e)));
+        }
+    }
+}
+
+impl From<Error> for Interrupt {
+    fn from(e: Error) -> Self {
+        Interrupt::Failed(e)
+    }
+}
+
+fn unexpected_reply(msg: ControlMsg) -> Interrupt {
+    Error::new_malformed_pkt(format!("Unexpected pairing reply: {:?}", msg)).into()
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn start(server: &StandInServer) -> (Pairing, Arc<Mutex<PairingStore>>, Reports) {
+        let store = Arc::new(Mutex::new(PairingStore::new_in_memory()));
+        let (to_java_send, to_java_recv) = mpsc::channel();
+        let pairing = Pairing::start(server.addr().to_string(), store.clone(), to_java_send);
+        (pairing, store, Reports(to_java_recv))
+    }
+
+    struct Reports(mpsc::Receiver<ToJavaMsg>);
+
+    impl Reports {
+        /// Returns the errors reported meanwhile
+        fn wait_for(&self, expected: PairingState) -> usize {
+            let mut errors = 0;
+            loop {
+                match self.0.recv_timeout(TIMEOUT).unwrap() {
+                    ToJavaMsg::PairingStateChanged(state) if state == expected => {
+                        return errors;
+                    }
+                    ToJavaMsg::PairingStateChanged(state) => info!("Pairing state: {:?}", state),
+                    ToJavaMsg::Error(_) => errors += 1,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pairs_with_matching_pin() {
+        let server = StandInServer::spawn().unwrap();
+        let (pairing, store, reports) = start(&server);
+
+        assert_eq!(reports.wait_for(PairingState::WaitingForPin), 0);
+        pairing.enter_pin(server.pin().unwrap()).unwrap();
+        assert_eq!(reports.wait_for(PairingState::Paired), 0);
+
+        let store = store.lock().unwrap();
+        let record = store.find(&server.addr().to_string()).unwrap();
+        assert_eq!(Some(record.psk), server.psk());
+    }
+
+    #[test]
+    fn fails_with_wrong_pin() {
+        let server = StandInServer::spawn().unwrap();
+        let (pairing, store, reports) = start(&server);
+
+        assert_eq!(reports.wait_for(PairingState::WaitingForPin), 0);
+        let pin = server.pin().unwrap();
+        let wrong_pin = format!("{:06}", (pin.parse::<u32>().unwrap() + 1) % 1_000_000);
+        for _ in 0..MAX_PIN_ATTEMPTS {
+            pairing.enter_pin(wrong_pin.clone()).unwrap();
+        }
+
+        assert_eq!(
+            reports.wait_for(PairingState::Failed) as u32,
+            MAX_PIN_ATTEMPTS
+        );
+        assert!(store
+            .lock()
+            .unwrap()
+            .find(&server.addr().to_string())
+            .is_none());
+        assert!(server.psk().is_none());
+    }
+
+    #[test]
+    fn nothing_is_reported_after_cancel() {
+        let server = StandInServer::spawn().unwrap();
+        let (mut pairing, _store, reports) = start(&server);
+
+        assert_eq!(reports.wait_for(PairingState::WaitingForPin), 0);
+        pairing.cancel();
+        assert_eq!(reports.wait_for(PairingState::Cancelled), 0);
+        assert!(reports.0.recv_timeout(RETRANSMIT_INTERVAL).is_err());
+        assert!(pairing.enter_pin(server.pin().unwrap()).is_err());
+    }
+}
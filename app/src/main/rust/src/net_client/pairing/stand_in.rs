@@ -0,0 +1,116 @@
+use super::exchange::{ServerExchange, PSK_LEN};
+use crate::error::Error;
+use crate::net_client::control::{ControlMsg, ControlPkt};
+use log::warn;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The server side of the pairing on the loopback, in the same process.
+/// Lets the whole exchange run end-to-end without a real server:
+/// pair with `addr()`, then enter `pin()` as the user would.
+pub struct StandInServer {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    is_stopped: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    exchange: ServerExchange,
+    psk: Option<[u8; PSK_LEN]>,
+}
+
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+impl StandInServer {
+    pub fn spawn() -> Result<Self, Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+        let addr = socket.local_addr()?;
+
+        let shared = Arc::new(Mutex::new(Shared {
+            exchange: ServerExchange::new(),
+            psk: None,
+        }));
+        let is_stopped = Arc::new(AtomicBool::new(false));
+
+        let thread_shared = shared.clone();
+        let thread_is_stopped = is_stopped.clone();
+        let join_handle = thread::spawn(move || {
+            let mut buf = vec![0; 1500];
+            let mut send_buf = Vec::new();
+            while !thread_is_stopped.load(Ordering::SeqCst) {
+                let (n, from) = match socket.recv_from(&mut buf) {
+                    Ok(res) => res,
+                    Err(_) => continue,
+                };
+
+                let reply = ControlPkt::decode(&buf[..n])
+                    .and_then(|pkt| thread_shared.lock().unwrap().on_pkt(pkt));
+                match reply {
+                    Ok(reply) => {
+                        reply.encode(&mut send_buf);
+                        log_and_ignore_err!(socket.send_to(&send_buf, from));
+                    }
+                    Err(e) => warn!("Stand-in server rejected a packet: {}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            shared,
+            is_stopped,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The PIN the server would show, available once the keys are exchanged
+    pub fn pin(&self) -> Option<String> {
+        let shared = self.shared.lock().unwrap();
+        shared.exchange.pin().map(|pin| pin.to_owned())
+    }
+
+    /// The PSK the server would keep, available once the pairing is confirmed
+    pub fn psk(&self) -> Option<[u8; PSK_LEN]> {
+        self.shared.lock().unwrap().psk
+    }
+}
+impl Drop for StandInServer {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::SeqCst);
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
+
+impl Shared {
+    /// Retransmitted requests get the same replies
+    fn on_pkt(&mut self, pkt: ControlPkt) -> Result<ControlPkt, Error> {
+        let reply = match pkt.msg {
+            ControlMsg::PairStart => ControlMsg::PairCommit(self.exchange.commitment()),
+            ControlMsg::PairKey(client_key) => {
+                ControlMsg::PairKey(self.exchange.on_client_key(client_key)?)
+            }
+            ControlMsg::PairConfirm(confirm) => {
+                self.psk = Some(self.exchange.on_confirm(&confirm)?);
+                ControlMsg::Ack
+            }
+            msg => {
+                return Err(Error::new_malformed_pkt(format!(
+                    "Unexpected pairing request: {:?}",
+                    msg
+                )));
+            }
+        };
+        Ok(ControlPkt::new(pkt.req_id, reply))
+    }
+}
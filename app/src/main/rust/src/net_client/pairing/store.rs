@@ -0,0 +1,109 @@
+use super::exchange::PSK_LEN;
+use crate::error::Error;
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The result of a successful pairing
+#[derive(Clone)]
+pub struct PairingRecord {
+    /// The server address as it was passed to the pairing, `NetClient::new` looks it up by that
+    pub server: String,
+    pub psk: [u8; PSK_LEN],
+}
+
+/// Pairing records kept in a file, one per line: `<server> <psk in hex>`
+pub struct PairingStore {
+    path: Option<PathBuf>,
+    records: Vec<PairingRecord>,
+}
+
+impl PairingStore {
+    /// A missing file is an empty store, it's created on the first `save`
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(Error::new_io(e, path.to_string_lossy().into_owned()));
+            }
+        };
+
+        let mut records = Vec::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match parse_record(line) {
+                Some(record) => records.push(record),
+                None => warn!("Skipping malformed pairing record: {}", line),
+            }
+        }
+        info!("Loaded {} pairing records", records.len());
+
+        Ok(Self {
+            path: Some(path),
+            records,
+        })
+    }
+
+    /// Nothing is persisted, e.g. for a pairing with the `StandInServer`
+    #[cfg(test)]
+    pub fn new_in_memory() -> Self {
+        Self {
+            path: None,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn find(&self, server: &str) -> Option<&PairingRecord> {
+        self.records.iter().find(|r| r.server == server)
+    }
+
+    /// Replaces the previous pairing with the same server
+    pub fn save(&mut self, record: PairingRecord) -> Result<(), Error> {
+        self.records.retain(|r| r.server != record.server);
+        self.records.push(record);
+        self.persist()
+    }
+
+    /// Writes to a temporary file first not to lose all the records if the write is interrupted
+    fn persist(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                return Ok(());
+            }
+        };
+
+        let mut content = String::new();
+        for record in &self.records {
+            content.push_str(&record.server);
+            content.push(' ');
+            for b in record.psk.iter() {
+                let _ = write!(content, "{:02x}", b);
+            }
+            content.push('\n');
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let to_io_err = |e| Error::new_io(e, path.to_string_lossy().into_owned());
+        fs::write(&tmp_path, content).map_err(to_io_err)?;
+        fs::rename(&tmp_path, path).map_err(to_io_err)?;
+        Ok(())
+    }
+}
+
+fn parse_record(line: &str) -> Option<PairingRecord> {
+    let mut parts = line.split_whitespace();
+    let server = parts.next()?.to_owned();
+    let hex = parts.next()?;
+    if parts.next().is_some() || hex.len() != PSK_LEN * 2 {
+        return None;
+    }
+
+    let mut psk = [0; PSK_LEN];
+    for (i, b) in psk.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(PairingRecord { server, psk })
+}
@@ -1,37 +1,202 @@
+use super::control::{StreamInfo, FEATURE_REDUNDANCY};
+use super::rtp::{RtpDepacketizer, RtpParams};
 use crate::error::Error;
+use crate::util::byte_reader::ByteReader;
 use std::borrow::Cow;
-use std::convert::TryInto;
-use std::u32;
 
-pub struct PktDecoder {}
+/// "SA" in ASCII
+pub const PKT_MAGIC: u16 = 0x5341;
+pub const PKT_VERSION: u8 = 1;
+/// The payload is a parity over a group of packets, see `player::fec`
+pub const PKT_FLAG_FEC: u8 = 0x01;
+/// The payload carries copies of earlier frames, set only if `FEATURE_REDUNDANCY` is negotiated.
+/// The copies split out by `PktDecoder` keep the flag, the primary frame has it cleared.
+/// Parity packets are never sent with redundant copies.
+pub const PKT_FLAG_REDUNDANT: u8 = 0x02;
+
+/// Redundant copies are sent for recent frames only, the older ones are played already
+const MAX_REDUNDANT_FRAMES: u8 = 8;
+
+pub struct PktDecoder {
+    is_redundancy_enabled: bool,
+    /// Set if the session is plain RTP instead of our own packets
+    rtp: Option<RtpDepacketizer>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Codec {
+    Aac,
+}
 
 pub struct Pkt<'a> {
     pub cnt: u32,
+    pub codec: Codec,
+    pub flags: u8,
+    /// Sender timestamp in microseconds
+    pub timestamp: u64,
     pub data: Option<Cow<'a, [u8]>>,
 }
 
 impl PktDecoder {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            is_redundancy_enabled: false,
+            rtp: None,
+        }
+    }
+
+    pub fn new_rtp(params: RtpParams) -> Result<Self, Error> {
+        Ok(Self {
+            is_redundancy_enabled: false,
+            rtp: Some(RtpDepacketizer::new(params)?),
+        })
+    }
+
+    pub fn configure(&mut self, info: &StreamInfo) {
+        self.is_redundancy_enabled = info.has_feature(FEATURE_REDUNDANCY);
+    }
+
+    /// Packet layout, all numbers are big-endian:
+    /// | magic: u16 | version: u8 | codec: u8 | flags: u8 | cnt: u32 | timestamp: u64 | payload |
+    ///
+    /// With `PKT_FLAG_REDUNDANT` the payload is:
+    /// | qty: u8 | qty * (cnt offset: u8 | timestamp offset: u32 | len: u16) | copies | primary |
+    /// The offsets are subtracted from the header `cnt` and `timestamp` to get the ones of a copy.
+    ///
+    /// The primary frame is the first in `to`, the redundant copies follow.
+    /// In an RTP session the packets are parsed by `RtpDepacketizer` instead.
+    pub fn parse<'a>(&mut self, buf: &'a [u8], to: &mut Vec<Pkt<'a>>) -> Result<(), Error> {
+        if let Some(rtp) = &mut self.rtp {
+            return rtp.parse(buf, to);
+        }
+
+        to.clear();
+
+        let mut reader = ByteReader::new(buf);
+
+        let magic = reader.read_u16()?;
+        if magic != PKT_MAGIC {
+            return Err(Error::new_malformed_pkt(format!(
+                "Wrong packet magic: {:#06x}",
+                magic
+            )));
+        }
+
+        let version = reader.read_u8()?;
+        if version != PKT_VERSION {
+            return Err(Error::new_unsupported_version(version));
+        }
+
+        let codec = Codec::from_id(reader.read_u8()?)?;
+        let flags = reader.read_u8()?;
+        let cnt = reader.read_u32()?;
+        let timestamp = reader.read_u64()?;
+
+        if flags & PKT_FLAG_REDUNDANT == 0 {
+            to.push(Pkt {
+                cnt,
+                codec,
+                flags,
+                timestamp,
+                data: Some(reader.read_rest().into()),
+            });
+            return Ok(());
+        }
+
+        if !self.is_redundancy_enabled {
+            return Err(Error::new_malformed_pkt(
+                "Redundant packet while redundancy is not negotiated",
+            ));
+        }
+
+        if flags & PKT_FLAG_FEC != 0 {
+            // A copy of a parity packet would be taken for a parity of another group
+            return Err(Error::new_malformed_pkt(
+                "Parity packet with redundant frames",
+            ));
+        }
+
+        let qty = reader.read_u8()?;
+        if qty > MAX_REDUNDANT_FRAMES {
+            return Err(Error::new_malformed_pkt(format!(
+                "Too many redundant frames: {}",
+                qty
+            )));
+        }
+
+        let mut headers = [(0, 0, 0); MAX_REDUNDANT_FRAMES as usize];
+        let headers = &mut headers[..qty as usize];
+        for header in headers.iter_mut() {
+            let cnt_offset = reader.read_u8()?;
+            let timestamp_offset = reader.read_u32()?;
+            let len = reader.read_u16()?;
+            if cnt_offset == 0 {
+                return Err(Error::new_malformed_pkt("Zero redundant cnt offset"));
+            }
+            *header = (cnt_offset, timestamp_offset, len);
+        }
+
+        for &(cnt_offset, timestamp_offset, len) in headers.iter() {
+            to.push(Pkt {
+                cnt: cnt.wrapping_sub(cnt_offset as u32),
+                codec,
+                flags,
+                timestamp: timestamp.wrapping_sub(timestamp_offset as u64),
+                data: Some(reader.read_bytes(len as usize)?.into()),
+            });
+        }
+
+        let primary = Pkt {
+            cnt,
+            codec,
+            flags: flags & !PKT_FLAG_REDUNDANT,
+            timestamp,
+            data: Some(reader.read_rest().into()),
+        };
+        to.insert(0, primary);
+
+        Ok(())
+    }
+}
+
+impl Codec {
+    pub fn from_id(id: u8) -> Result<Self, Error> {
+        match id {
+            1 => Ok(Codec::Aac),
+            _ => Err(Error::new_unknown_codec(id)),
+        }
     }
 
-    pub fn parse<'a>(&mut self, buf: &'a [u8]) -> Result<Pkt<'a>, Error> {
-        let (cnt_bytes, buf) = buf.split_at(std::mem::size_of::<u32>());
-        let cnt = u32::from_be_bytes(cnt_bytes.try_into().unwrap());
+    pub fn to_id(&self) -> u8 {
+        match self {
+            Codec::Aac => 1,
+        }
+    }
 
-        let pkt = Pkt::new_borrower(cnt, buf);
-        Ok(pkt)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Aac => "aac",
+        }
     }
 }
 
 impl<'a> Pkt<'a> {
     pub fn new_empty(cnt: u32) -> Self {
-        Self { cnt, data: None }
+        Self {
+            cnt,
+            codec: Codec::Aac,
+            flags: 0,
+            timestamp: 0,
+            data: None,
+        }
     }
 
     pub fn new_owner(cnt: u32) -> Self {
         Self {
             cnt,
+            codec: Codec::Aac,
+            flags: 0,
+            timestamp: 0,
             data: Some(Vec::new().into()),
         }
     }
@@ -39,6 +204,9 @@ impl<'a> Pkt<'a> {
     pub fn new_borrower(cnt: u32, data: &'a [u8]) -> Self {
         Self {
             cnt,
+            codec: Codec::Aac,
+            flags: 0,
+            timestamp: 0,
             data: Some(data.into()),
         }
     }
@@ -47,6 +215,15 @@ impl<'a> Pkt<'a> {
         self.data.is_none()
     }
 
+    pub fn is_fec(&self) -> bool {
+        self.flags & PKT_FLAG_FEC != 0
+    }
+
+    /// A copy of an earlier frame, see `PKT_FLAG_REDUNDANT`
+    pub fn is_redundant(&self) -> bool {
+        self.flags & PKT_FLAG_REDUNDANT != 0
+    }
+
     pub fn len(&self) -> usize {
         self.data.as_ref().map(|d| d.len()).unwrap_or(0)
     }
@@ -64,6 +241,9 @@ impl<'a> Pkt<'a> {
         data.resize(from_data.len(), 0);
         data.copy_from_slice(from_data);
         self.cnt = from.cnt;
+        self.codec = from.codec;
+        self.flags = from.flags;
+        self.timestamp = from.timestamp;
     }
 
     pub fn copy_to_vec(&self, to: &mut Vec<u8>) -> bool {
@@ -79,3 +259,135 @@ impl<'a> Pkt<'a> {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CNT: u32 = 1000;
+    const TIMESTAMP: u64 = 5_000_000;
+
+    fn header(flags: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&PKT_MAGIC.to_be_bytes());
+        buf.push(PKT_VERSION);
+        buf.push(Codec::Aac.to_id());
+        buf.push(flags);
+        buf.extend_from_slice(&CNT.to_be_bytes());
+        buf.extend_from_slice(&TIMESTAMP.to_be_bytes());
+        buf
+    }
+
+    /// `copies` are (cnt offset, timestamp offset, payload)
+    fn redundant_pkt(flags: u8, copies: &[(u8, u32, &[u8])], primary: &[u8]) -> Vec<u8> {
+        let mut buf = header(flags | PKT_FLAG_REDUNDANT);
+        buf.push(copies.len() as u8);
+        for &(cnt_offset, timestamp_offset, data) in copies {
+            buf.push(cnt_offset);
+            buf.extend_from_slice(&timestamp_offset.to_be_bytes());
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        }
+        for &(_, _, data) in copies {
+            buf.extend_from_slice(data);
+        }
+        buf.extend_from_slice(primary);
+        buf
+    }
+
+    /// cnt, flags, timestamp, payload
+    type Parsed = (u32, u8, u64, Vec<u8>);
+
+    fn new_decoder(is_redundancy_enabled: bool) -> PktDecoder {
+        let mut decoder = PktDecoder::new();
+        decoder.is_redundancy_enabled = is_redundancy_enabled;
+        decoder
+    }
+
+    fn parse(decoder: &mut PktDecoder, buf: &[u8]) -> Result<Vec<Parsed>, Error> {
+        let mut pkts = Vec::new();
+        decoder.parse(buf, &mut pkts)?;
+        Ok(pkts
+            .iter()
+            .map(|p| {
+                (
+                    p.cnt,
+                    p.flags,
+                    p.timestamp,
+                    p.data.as_ref().unwrap().to_vec(),
+                )
+            })
+            .collect())
+    }
+
+    #[test]
+    fn parses_plain_packet() {
+        let mut buf = header(PKT_FLAG_FEC);
+        buf.extend_from_slice(&[1, 2, 3]);
+
+        let pkts = parse(&mut new_decoder(false), &buf).unwrap();
+        assert_eq!(pkts, vec![(CNT, PKT_FLAG_FEC, TIMESTAMP, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn splits_redundant_copies() {
+        let buf = redundant_pkt(0, &[(2, 42666, &[2, 2]), (1, 21333, &[1])], &[0, 0, 0]);
+
+        let pkts = parse(&mut new_decoder(true), &buf).unwrap();
+        assert_eq!(
+            pkts,
+            vec![
+                (CNT, 0, TIMESTAMP, vec![0, 0, 0]),
+                (CNT - 2, PKT_FLAG_REDUNDANT, TIMESTAMP - 42666, vec![2, 2]),
+                (CNT - 1, PKT_FLAG_REDUNDANT, TIMESTAMP - 21333, vec![1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_wrap_around_cnt_zero() {
+        let mut buf = redundant_pkt(0, &[(1, 1, &[1])], &[0]);
+        buf[5..9].copy_from_slice(&0u32.to_be_bytes());
+
+        let pkts = parse(&mut new_decoder(true), &buf).unwrap();
+        assert_eq!(pkts[1].0, std::u32::MAX);
+    }
+
+    #[test]
+    fn limits_redundant_frames() {
+        let copies: Vec<(u8, u32, &[u8])> = (1..=MAX_REDUNDANT_FRAMES + 1)
+            .map(|offset| (offset, offset as u32, &[0xaa][..]))
+            .collect();
+
+        let max = redundant_pkt(0, &copies[..MAX_REDUNDANT_FRAMES as usize], &[]);
+        let pkts = parse(&mut new_decoder(true), &max).unwrap();
+        assert_eq!(pkts.len(), MAX_REDUNDANT_FRAMES as usize + 1);
+
+        let too_many = redundant_pkt(0, &copies, &[]);
+        assert!(parse(&mut new_decoder(true), &too_many).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_redundant_packets() {
+        let valid = redundant_pkt(0, &[(1, 100, &[1, 1])], &[0]);
+        assert!(parse(&mut new_decoder(true), &valid).is_ok());
+
+        // Not negotiated
+        assert!(parse(&mut new_decoder(false), &valid).is_err());
+        // A copy can't be the primary frame
+        let zero_offset = redundant_pkt(0, &[(0, 100, &[1, 1])], &[0]);
+        assert!(parse(&mut new_decoder(true), &zero_offset).is_err());
+        // A copy is longer than the rest of the packet
+        let truncated = &valid[..valid.len() - 2];
+        assert!(parse(&mut new_decoder(true), truncated).is_err());
+        // The headers of the copies are cut
+        let header_len = header(0).len();
+        assert!(parse(&mut new_decoder(true), &valid[..header_len]).is_err());
+        assert!(parse(&mut new_decoder(true), &valid[..header_len + 3]).is_err());
+    }
+
+    #[test]
+    fn rejects_redundant_parity() {
+        let buf = redundant_pkt(PKT_FLAG_FEC, &[(1, 100, &[1, 1])], &[0]);
+        assert!(parse(&mut new_decoder(true), &buf).is_err());
+    }
+}
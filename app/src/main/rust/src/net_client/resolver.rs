@@ -0,0 +1,84 @@
+use super::socket;
+use crate::error::Error;
+use log::info;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+
+/// Resolves the server address in its own thread, as `getaddrinfo` cannot be interrupted.
+/// Becomes readable once the result is available.
+pub struct Resolver {
+    registration: mio::Registration,
+    recv: mpsc::Receiver<Result<Vec<SocketAddr>, Error>>,
+}
+
+impl Resolver {
+    pub fn spawn(host: String) -> Self {
+        let (registration, set_readiness) = mio::Registration::new2();
+        let (send, recv) = mpsc::channel();
+
+        thread::spawn(move || {
+            let res = resolve(&host);
+            // The receiver is gone if the client has been stopped in the meantime
+            if send.send(res).is_ok() {
+                log_and_ignore_err!(set_readiness.set_readiness(mio::Ready::readable()));
+            }
+        });
+
+        Self { registration, recv }
+    }
+
+    pub fn try_get(&self) -> Option<Result<Vec<SocketAddr>, Error>> {
+        self.recv.try_recv().ok()
+    }
+}
+
+impl mio::Evented for Resolver {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> Result<(), std::io::Error> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> Result<(), std::io::Error> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> Result<(), std::io::Error> {
+        poll.deregister(&self.registration)
+    }
+}
+
+/// Accepts both a literal address and `hostname:port`
+pub fn resolve(host: &str) -> Result<Vec<SocketAddr>, Error> {
+    if let Ok(addr) = socket::parse_socket_addr(host) {
+        return Ok(vec![addr]);
+    }
+
+    info!("Resolving {}", host);
+    let addrs: Vec<SocketAddr> = host
+        .to_socket_addrs()
+        .map_err(|e| Error::new_resolve(e, host.to_owned()))?
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(Error::new_resolve(
+            io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+            host.to_owned(),
+        ));
+    }
+
+    info!("{} is resolved to: {:?}", host, addrs);
+    Ok(addrs)
+}
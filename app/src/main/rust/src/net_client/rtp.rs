@@ -0,0 +1,572 @@
+use super::control::StreamInfo;
+use super::pkt_decoder::{Codec, Pkt};
+use crate::error::Error;
+use crate::util::byte_reader::ByteReader;
+use crate::util::serial;
+use log::info;
+
+/// Parameters of an RTP session, normally taken from SDP
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtpParams {
+    pub payload_type: u8,
+    pub format: RtpFormat,
+    pub clock_rate: u32,
+    pub channels: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RtpFormat {
+    /// RFC 3640 mpeg4-generic, the AU headers consist of the AU size and the AU index (delta).
+    /// The AUs are passed to the decoder with ADTS headers made of `config`.
+    Mpeg4Generic {
+        size_length: u8,
+        index_length: u8,
+        index_delta_length: u8,
+        config: AacConfig,
+    },
+}
+
+/// The part of AudioSpecificConfig (ISO 14496-3, 1.6.2.1) the ADTS header is made of
+#[derive(Debug, Clone, PartialEq)]
+pub struct AacConfig {
+    pub audio_object_type: u8,
+    /// Of the core AAC stream, half of the output rate with SBR
+    pub sample_rate: u32,
+    /// What the decoder outputs, the extension rate with SBR
+    pub output_sample_rate: u32,
+    pub channels: u8,
+}
+
+/// Turns RTP packets into `Pkt`s: the extended sequence number becomes `cnt`,
+/// the RTP timestamp is converted to microseconds.
+///
+/// Only one AU per RTP packet is supported, that's what GStreamer `rtpmp4gpay` sends
+/// and what ffmpeg sends with `-max_delay 0`.
+pub struct RtpDepacketizer {
+    params: RtpParams,
+    ssrc: Option<u32>,
+    last_seq: u16,
+    last_cnt: u32,
+    last_timestamp: u32,
+    /// The RTP timestamp extended to 64 bits
+    ext_timestamp: i64,
+    adts: [u8; ADTS_HEADER_LEN],
+}
+
+const RTP_VERSION: u8 = 2;
+const ADTS_HEADER_LEN: usize = 7;
+/// AAC-LC, what `config` of the most senders describes
+const DEFAULT_AUDIO_OBJECT_TYPE: u8 = 2;
+/// SBR and PS, signalled explicitly in AudioSpecificConfig
+const HE_AAC_OBJECT_TYPES: [u8; 2] = [5, 29];
+/// Frame size of AAC-LC
+const AAC_FRAME_SIZE: u16 = 1024;
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+impl RtpParams {
+    /// AAC-LC in AAC-hbr mode with the usual AU header layout: sizeLength=13, indexLength=3,
+    /// indexDeltaLength=3
+    pub fn new_aac_hbr(payload_type: u8, clock_rate: u32, channels: u8) -> Self {
+        Self {
+            payload_type,
+            format: RtpFormat::Mpeg4Generic {
+                size_length: 13,
+                index_length: 3,
+                index_delta_length: 3,
+                config: AacConfig {
+                    audio_object_type: DEFAULT_AUDIO_OBJECT_TYPE,
+                    sample_rate: clock_rate,
+                    output_sample_rate: clock_rate,
+                    channels,
+                },
+            },
+            clock_rate,
+            channels,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        match self.format {
+            RtpFormat::Mpeg4Generic { .. } => Codec::Aac,
+        }
+    }
+
+    /// What the server would have sent in `Info`, the decoder is configured with it.
+    /// The RTP clock rate of mpeg4-generic may differ from the rate of the AAC stream
+    pub fn stream_info(&self) -> StreamInfo {
+        let (sample_rate, channels, frame_size) = match &self.format {
+            RtpFormat::Mpeg4Generic { config, .. } => {
+                // SBR doubles the samples of a frame along with the rate
+                let frame_size = if config.output_sample_rate > config.sample_rate {
+                    2 * AAC_FRAME_SIZE
+                } else {
+                    AAC_FRAME_SIZE
+                };
+                (config.output_sample_rate, config.channels, frame_size)
+            }
+        };
+
+        StreamInfo {
+            codec: self.codec(),
+            sample_rate,
+            channels,
+            frame_size,
+            features: 0,
+            multicast: None,
+        }
+    }
+}
+
+impl RtpDepacketizer {
+    pub fn new(params: RtpParams) -> Result<Self, Error> {
+        if params.clock_rate == 0 || params.channels == 0 {
+            return Err(Error::new_wrong_argument(format!(
+                "Invalid RTP session: clock rate: {}, channels: {}",
+                params.clock_rate, params.channels
+            )));
+        }
+
+        let adts = match &params.format {
+            RtpFormat::Mpeg4Generic {
+                size_length,
+                config,
+                ..
+            } => {
+                if *size_length == 0 || *size_length > 16 {
+                    return Err(Error::new_wrong_argument(format!(
+                        "Unsupported AU size length: {}",
+                        size_length
+                    )));
+                }
+                make_adts_header(config)?
+            }
+        };
+
+        Ok(Self {
+            params,
+            ssrc: None,
+            last_seq: 0,
+            last_cnt: 0,
+            last_timestamp: 0,
+            ext_timestamp: 0,
+            adts,
+        })
+    }
+
+    /// RTP header, RFC 3550:
+    /// | V=2: 2 bits | P | X | CC: 4 bits | M | PT: 7 bits | seq: u16 | timestamp: u32 | SSRC: u32 |
+    /// | CSRC: CC * u32 | extension if X: (profile: u16 | len: u16 | len * u32) | payload | padding |
+    pub fn parse<'a>(&mut self, buf: &'a [u8], to: &mut Vec<Pkt<'a>>) -> Result<(), Error> {
+        to.clear();
+
+        let mut reader = ByteReader::new(buf);
+        let first = reader.read_u8()?;
+        let version = first >> 6;
+        if version != RTP_VERSION {
+            return Err(Error::new_unsupported_version(version));
+        }
+        let has_padding = first & 0x20 != 0;
+        let has_extension = first & 0x10 != 0;
+        let csrc_cnt = (first & 0x0f) as usize;
+
+        let payload_type = reader.read_u8()? & 0x7f;
+        if payload_type != self.params.payload_type {
+            return Err(Error::new_malformed_pkt(format!(
+                "Unexpected RTP payload type: {}",
+                payload_type
+            )));
+        }
+
+        let seq = reader.read_u16()?;
+        let timestamp = reader.read_u32()?;
+        let ssrc = reader.read_u32()?;
+        reader.read_bytes(csrc_cnt * 4)?;
+        if has_extension {
+            let _profile = reader.read_u16()?;
+            let len = reader.read_u16()? as usize;
+            reader.read_bytes(len * 4)?;
+        }
+
+        let mut payload = reader.read_rest();
+        if has_padding {
+            let padding = *payload.last().unwrap_or(&0) as usize;
+            if padding == 0 || padding > payload.len() {
+                return Err(Error::new_malformed_pkt(format!(
+                    "Wrong RTP padding: {}",
+                    padding
+                )));
+            }
+            payload = &payload[..payload.len() - padding];
+        }
+
+        let (cnt, ext_timestamp) = self.extend(ssrc, seq, timestamp);
+        let timestamp = ext_timestamp.max(0) as u64 * 1_000_000 / self.params.clock_rate as u64;
+
+        let data = match self.params.format {
+            RtpFormat::Mpeg4Generic { .. } => self.unpack_au(payload)?.into(),
+        };
+
+        to.push(Pkt {
+            cnt,
+            codec: self.params.codec(),
+            flags: 0,
+            timestamp,
+            data: Some(data),
+        });
+        Ok(())
+    }
+
+    /// Returns the extended sequence number and timestamp. A new SSRC starts over,
+    /// that's a new stream
+    fn extend(&mut self, ssrc: u32, seq: u16, timestamp: u32) -> (u32, i64) {
+        if self.ssrc != Some(ssrc) {
+            info!("New RTP source: {:#010x}", ssrc);
+            self.ssrc = Some(ssrc);
+            self.last_seq = seq;
+            self.last_cnt = seq as u32;
+            self.last_timestamp = timestamp;
+            self.ext_timestamp = timestamp as i64;
+            return (self.last_cnt, self.ext_timestamp);
+        }
+
+        let seq_diff = seq.wrapping_sub(self.last_seq) as i16 as i32;
+        let cnt = self.last_cnt.wrapping_add(seq_diff as u32);
+        let timestamp_diff = serial::diff(timestamp, self.last_timestamp) as i64;
+        let ext_timestamp = self.ext_timestamp + timestamp_diff;
+
+        // Only the newest packet moves the reference, a late one is just placed relative to it
+        if seq_diff > 0 {
+            self.last_seq = seq;
+            self.last_cnt = cnt;
+            self.last_timestamp = timestamp;
+            self.ext_timestamp = ext_timestamp;
+        }
+
+        (cnt, ext_timestamp)
+    }
+
+    /// RFC 3640, 3.2:
+    /// | AU-headers-length in bits: u16 | AU headers | padding to a byte | AUs |
+    /// The first AU header is | AU-size | AU-Index |, the rest are | AU-size | AU-Index-delta |
+    /// Returns the AU prefixed with the ADTS header
+    fn unpack_au(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let (size_length, index_length) = match &self.params.format {
+            RtpFormat::Mpeg4Generic {
+                size_length,
+                index_length,
+                ..
+            } => (*size_length as usize, *index_length as usize),
+        };
+
+        let mut reader = ByteReader::new(payload);
+        let headers_len = reader.read_u16()? as usize;
+        let headers = reader.read_bytes((headers_len + 7) / 8)?;
+        if headers_len < size_length + index_length {
+            return Err(Error::new_malformed_pkt(format!(
+                "AU headers are too short: {} bits",
+                headers_len
+            )));
+        }
+        if headers_len >= 2 * (size_length + index_length) {
+            return Err(Error::new_malformed_pkt(
+                "Several AUs in one RTP packet are not supported",
+            ));
+        }
+
+        let au_size = read_bits(headers, 0, size_length);
+        let au = reader.read_rest();
+        if au.len() != au_size {
+            return Err(Error::new_malformed_pkt(format!(
+                "AU size: {} doesn't match the payload: {}, fragmented AUs are not supported",
+                au_size,
+                au.len()
+            )));
+        }
+
+        let frame_len = ADTS_HEADER_LEN + au.len();
+        if frame_len >= 1 << 13 {
+            return Err(Error::new_malformed_pkt(format!(
+                "AU is too big: {}",
+                au.len()
+            )));
+        }
+
+        let mut frame = Vec::with_capacity(frame_len);
+        frame.extend_from_slice(&self.adts);
+        frame[3] |= (frame_len >> 11) as u8;
+        frame[4] = (frame_len >> 3) as u8;
+        frame[5] |= (frame_len << 5) as u8;
+        frame.extend_from_slice(au);
+        Ok(frame)
+    }
+}
+
+impl AacConfig {
+    /// AudioSpecificConfig as in the `config` SDP parameter:
+    /// | object type: 5 bits (31: 6 more) | freq index: 4 bits (15: freq: 24 bits) | channels: 4 |
+    /// With SBR or PS: | extension freq index: 4 bits (15: 24 bits) | object type: 5 bits |
+    pub fn parse(asc: &[u8]) -> Result<Self, Error> {
+        let bits = asc.len() * 8;
+        let mut pos = 0;
+        let mut read = |len: usize| -> Result<usize, Error> {
+            if pos + len > bits {
+                return Err(Error::new_malformed_pkt("AudioSpecificConfig is too short"));
+            }
+            let res = read_bits(asc, pos, len);
+            pos += len;
+            Ok(res)
+        };
+
+        let mut audio_object_type = read_object_type(&mut read)?;
+        let sample_rate = read_sample_rate(&mut read)?;
+        let channels = read(4)? as u8;
+        let mut output_sample_rate = sample_rate;
+        if HE_AAC_OBJECT_TYPES.contains(&audio_object_type) {
+            // The core rate goes to the ADTS header, the decoder finds SBR by itself
+            output_sample_rate = read_sample_rate(&mut read)?;
+            audio_object_type = read_object_type(&mut read)?;
+        }
+
+        if channels == 0 {
+            return Err(Error::new_malformed_pkt(
+                "Channel layout in the program config element is not supported",
+            ));
+        }
+
+        Ok(Self {
+            audio_object_type,
+            sample_rate,
+            output_sample_rate,
+            channels,
+        })
+    }
+}
+
+fn read_object_type<F>(read: &mut F) -> Result<u8, Error>
+where
+    F: FnMut(usize) -> Result<usize, Error>,
+{
+    let object_type = read(5)?;
+    if object_type == 31 {
+        Ok((32 + read(6)?) as u8)
+    } else {
+        Ok(object_type as u8)
+    }
+}
+
+fn read_sample_rate<F>(read: &mut F) -> Result<u32, Error>
+where
+    F: FnMut(usize) -> Result<usize, Error>,
+{
+    let idx = read(4)?;
+    if idx == 15 {
+        return Ok(read(24)? as u32);
+    }
+    AAC_SAMPLE_RATES
+        .get(idx)
+        .cloned()
+        .ok_or_else(|| Error::new_malformed_pkt(format!("Reserved AAC frequency index: {}", idx)))
+}
+
+/// Reads `len` bits starting at the bit `pos`, MSB first
+fn read_bits(buf: &[u8], pos: usize, len: usize) -> usize {
+    (pos..pos + len).fold(0, |acc, bit| {
+        let is_set = buf[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        acc << 1 | is_set as usize
+    })
+}
+
+/// ADTS header without CRC, the frame length is filled in per frame:
+/// | sync: 12 bits | ID | layer: 2 | no CRC | profile: 2 | freq index: 4 | private | channels: 3 |
+/// | original | home | copyright id | copyright start | frame length: 13 | fullness: 11 | frames: 2 |
+/// SBR is left to the implicit signalling, ADTS can't carry it
+fn make_adts_header(config: &AacConfig) -> Result<[u8; ADTS_HEADER_LEN], Error> {
+    let AacConfig {
+        audio_object_type,
+        sample_rate,
+        channels,
+        ..
+    } = *config;
+    if audio_object_type == 0 || audio_object_type > 4 {
+        return Err(Error::new_wrong_argument(format!(
+            "Unsupported AAC object type: {}",
+            audio_object_type
+        )));
+    }
+    let freq_idx = AAC_SAMPLE_RATES
+        .iter()
+        .position(|&r| r == sample_rate)
+        .ok_or_else(|| {
+            Error::new_wrong_argument(format!("Unsupported AAC sample rate: {}", sample_rate))
+        })? as u8;
+    if channels > 7 {
+        return Err(Error::new_wrong_argument(format!(
+            "Unsupported AAC channel count: {}",
+            channels
+        )));
+    }
+
+    let profile = audio_object_type - 1;
+    Ok([
+        0xff,
+        0xf1,
+        profile << 6 | freq_idx << 2 | channels >> 2,
+        (channels & 0x03) << 6,
+        0x00,
+        0x1f,
+        0xfc,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD_TYPE: u8 = 96;
+    const CLOCK_RATE: u32 = 44100;
+    const SSRC: u32 = 0x1234_5678;
+
+    fn new_depacketizer() -> RtpDepacketizer {
+        RtpDepacketizer::new(RtpParams::new_aac_hbr(PAYLOAD_TYPE, CLOCK_RATE, 2)).unwrap()
+    }
+
+    /// One AU with the AU-size: 13 bits, AU-Index: 3 bits header
+    fn au_payload(au: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0x00, 0x10];
+        payload.extend_from_slice(&((au.len() as u16) << 3).to_be_bytes());
+        payload.extend_from_slice(au);
+        payload
+    }
+
+    fn rtp_pkt(ssrc: u32, seq: u16, timestamp: u32, payload: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![RTP_VERSION << 6, 0x80 | PAYLOAD_TYPE];
+        pkt.extend_from_slice(&seq.to_be_bytes());
+        pkt.extend_from_slice(&timestamp.to_be_bytes());
+        pkt.extend_from_slice(&ssrc.to_be_bytes());
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    /// Returns `(cnt, timestamp in microseconds, data)`
+    fn parse(depacketizer: &mut RtpDepacketizer, pkt: &[u8]) -> (u32, u64, Vec<u8>) {
+        let mut pkts = Vec::new();
+        depacketizer.parse(pkt, &mut pkts).unwrap();
+        assert_eq!(pkts.len(), 1);
+        let pkt = &pkts[0];
+        assert_eq!(pkt.codec, Codec::Aac);
+        (pkt.cnt, pkt.timestamp, pkt.data.as_ref().unwrap().to_vec())
+    }
+
+    fn to_micros(ext_timestamp: i64) -> u64 {
+        ext_timestamp as u64 * 1_000_000 / CLOCK_RATE as u64
+    }
+
+    #[test]
+    fn extends_seq_and_timestamp_over_wraparound() {
+        let mut depacketizer = new_depacketizer();
+        let payload = au_payload(&[1, 2, 3]);
+        let first_timestamp: u32 = 0xffff_fc00;
+
+        // Both the seq and the timestamp wrap around at the third packet
+        for i in 0..4u16 {
+            let timestamp = first_timestamp.wrapping_add(i as u32 * 1024);
+            let pkt = rtp_pkt(SSRC, 0xfffe_u16.wrapping_add(i), timestamp, &payload);
+            let (cnt, timestamp, _) = parse(&mut depacketizer, &pkt);
+            assert_eq!(cnt, 0xfffe + i as u32);
+            assert_eq!(
+                timestamp,
+                to_micros(first_timestamp as i64 + i as i64 * 1024)
+            );
+        }
+
+        // A late packet from before the wraparound is placed relative to the newest one
+        let late = rtp_pkt(SSRC, 0xffff, first_timestamp.wrapping_add(1024), &payload);
+        let (cnt, timestamp, _) = parse(&mut depacketizer, &late);
+        assert_eq!(cnt, 0xffff);
+        assert_eq!(timestamp, to_micros(first_timestamp as i64 + 1024));
+    }
+
+    #[test]
+    fn skips_csrc_extension_and_padding() {
+        let au = [0xaa; 10];
+        let mut pkt = rtp_pkt(SSRC, 7, 1000, &[]);
+        // P, X, CC = 2
+        pkt[0] |= 0x20 | 0x10 | 2;
+        pkt.extend_from_slice(&[0x11; 8]);
+        pkt.extend_from_slice(&[0xbe, 0xde, 0x00, 0x01, 0x22, 0x22, 0x22, 0x22]);
+        pkt.extend_from_slice(&au_payload(&au));
+        pkt.extend_from_slice(&[0x00, 0x00, 0x03]);
+
+        let (cnt, timestamp, data) = parse(&mut new_depacketizer(), &pkt);
+        assert_eq!(cnt, 7);
+        assert_eq!(timestamp, to_micros(1000));
+        assert_eq!(&data[ADTS_HEADER_LEN..], &au[..]);
+    }
+
+    #[test]
+    fn wrong_padding_is_rejected() {
+        let mut pkt = rtp_pkt(SSRC, 7, 1000, &au_payload(&[1, 2, 3]));
+        pkt[0] |= 0x20;
+        *pkt.last_mut().unwrap() = 200;
+        let mut pkts = Vec::new();
+        assert!(new_depacketizer().parse(&pkt, &mut pkts).is_err());
+    }
+
+    #[test]
+    fn new_ssrc_starts_over() {
+        let mut depacketizer = new_depacketizer();
+        let payload = au_payload(&[1, 2, 3]);
+
+        parse(&mut depacketizer, &rtp_pkt(SSRC, 100, 5000, &payload));
+        let (cnt, timestamp, _) = parse(&mut depacketizer, &rtp_pkt(SSRC, 101, 6024, &payload));
+        assert_eq!((cnt, timestamp), (101, to_micros(6024)));
+
+        let (cnt, timestamp, _) = parse(&mut depacketizer, &rtp_pkt(!SSRC, 40000, 100, &payload));
+        assert_eq!((cnt, timestamp), (40000, to_micros(100)));
+        let (cnt, _, _) = parse(&mut depacketizer, &rtp_pkt(!SSRC, 40001, 1124, &payload));
+        assert_eq!(cnt, 40001);
+    }
+
+    #[test]
+    fn unpacks_au_with_adts_header() {
+        let au: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let pkt = rtp_pkt(SSRC, 1, 0, &au_payload(&au));
+        let (_, _, data) = parse(&mut new_depacketizer(), &pkt);
+
+        let frame_len = ADTS_HEADER_LEN + au.len();
+        assert_eq!(data.len(), frame_len);
+        // Sync word, MPEG-4, no CRC
+        assert_eq!(&data[..2], &[0xff, 0xf1]);
+        // AAC-LC, 44100 Hz, 2 channels
+        assert_eq!(data[2], 1 << 6 | 4 << 2);
+        assert_eq!(data[3] >> 6, 2);
+        let len_field =
+            (data[3] as usize & 0x03) << 11 | (data[4] as usize) << 3 | (data[5] as usize) >> 5;
+        assert_eq!(len_field, frame_len);
+        assert_eq!(&data[ADTS_HEADER_LEN..], &au[..]);
+    }
+
+    #[test]
+    fn unsupported_aus_are_rejected() {
+        let mut depacketizer = new_depacketizer();
+        let mut pkts = Vec::new();
+
+        // Two AU headers
+        let mut payload = vec![0x00, 0x20, 0x00, 0x08, 0x00, 0x08];
+        payload.extend_from_slice(&[1, 2]);
+        let pkt = rtp_pkt(SSRC, 1, 0, &payload);
+        assert!(depacketizer.parse(&pkt, &mut pkts).is_err());
+
+        // A fragment of the AU
+        let mut payload = au_payload(&[1, 2, 3]);
+        payload.pop();
+        let pkt = rtp_pkt(SSRC, 2, 0, &payload);
+        assert!(depacketizer.parse(&pkt, &mut pkts).is_err());
+
+        let mut pkt = rtp_pkt(SSRC, 3, 0, &au_payload(&[1, 2, 3]));
+        pkt[1] = PAYLOAD_TYPE + 1;
+        assert!(depacketizer.parse(&pkt, &mut pkts).is_err());
+    }
+}
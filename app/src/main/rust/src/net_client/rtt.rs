@@ -0,0 +1,90 @@
+use log::info;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Measures the round-trip time with `Echo` requests answered by `EchoReply`.
+///
+/// The request carries the send time, so no state is needed for the reply but its id,
+/// which protects from stale and forged replies.
+pub struct RttMeter {
+    /// The echo timestamps are measured from it
+    started: Instant,
+    /// `(req_id, timestamp)` of the unanswered requests
+    pending: VecDeque<(u32, u64)>,
+    samples: VecDeque<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttSnapshot {
+    pub min: Duration,
+    pub avg: Duration,
+    pub p95: Duration,
+    /// The qty of the replies the numbers are computed over
+    pub samples: usize,
+}
+
+/// A reply is not awaited after so many newer requests, it's considered lost
+const MAX_PENDING: usize = 8;
+const WINDOW_LEN: usize = 64;
+
+impl RttMeter {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            pending: VecDeque::with_capacity(MAX_PENDING),
+            samples: VecDeque::with_capacity(WINDOW_LEN),
+        }
+    }
+
+    /// The timestamp to send in `Echo`
+    pub fn timestamp(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    pub fn on_sent(&mut self, req_id: u32, timestamp: u64) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((req_id, timestamp));
+    }
+
+    pub fn on_reply(&mut self, req_id: u32, timestamp: u64) {
+        let idx = match self.pending.iter().position(|&p| p == (req_id, timestamp)) {
+            Some(idx) => idx,
+            None => {
+                info!("Unexpected echo reply: {}", req_id);
+                return;
+            }
+        };
+        self.pending.remove(idx);
+
+        let rtt = Duration::from_micros(self.timestamp().saturating_sub(timestamp));
+        if self.samples.len() >= WINDOW_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// Called on a new handshake, the measurements of another server don't count
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.samples.clear();
+    }
+
+    pub fn snapshot(&self) -> RttSnapshot {
+        if self.samples.is_empty() {
+            return RttSnapshot::default();
+        }
+
+        let mut sorted: Vec<_> = self.samples.iter().cloned().collect();
+        sorted.sort();
+        let p95_idx = (sorted.len() * 95 + 99) / 100 - 1;
+
+        RttSnapshot {
+            min: sorted[0],
+            avg: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p95: sorted[p95_idx],
+            samples: sorted.len(),
+        }
+    }
+}
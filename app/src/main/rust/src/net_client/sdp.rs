@@ -0,0 +1,358 @@
+use super::rtp::{AacConfig, RtpFormat, RtpParams};
+use crate::error::Error;
+use log::info;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The RTP session described by SDP (RFC 4566), only the first audio stream is looked at:
+///
+/// ```text
+/// o=- 0 0 IN IP4 127.0.0.1
+/// c=IN IP4 192.168.1.20
+/// m=audio 5004 RTP/AVP 96
+/// a=rtpmap:96 mpeg4-generic/44100/2
+/// a=fmtp:96 streamtype=5; mode=AAC-hbr; config=1210; sizeLength=13; indexLength=3; indexDeltaLength=3
+/// ```
+///
+/// The `o=` line is not the sender, ffmpeg writes 127.0.0.1 there,
+/// the sender address is to be given along with the SDP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionDescription {
+    /// Where the stream is sent to, from the `c=` line
+    pub connection: Option<IpAddr>,
+    pub port: u16,
+    pub rtp: RtpParams,
+}
+
+impl SessionDescription {
+    pub fn parse(sdp: &str) -> Result<Self, Error> {
+        let mut session_connection = None;
+        let mut media_connection = None;
+        let mut media: Option<(u16, u8)> = None;
+        let mut rtpmap = None;
+        let mut fmtp = None;
+        // Set while the lines of another media description are read
+        let mut is_other_media = false;
+
+        for line in sdp.lines() {
+            let line = line.trim();
+            let (kind, value) = match (line.get(..2), line.get(2..)) {
+                (Some(kind), Some(value)) if kind.ends_with('=') => (&kind[..1], value),
+                _ => continue,
+            };
+
+            match kind {
+                "c" if media.is_none() && !is_other_media => {
+                    session_connection = Some(parse_connection(value)?)
+                }
+                "c" if !is_other_media => media_connection = Some(parse_connection(value)?),
+                "m" if media.is_none() && value.starts_with("audio ") => {
+                    media = Some(parse_media(value)?);
+                    is_other_media = false;
+                }
+                "m" => is_other_media = true,
+                "a" if is_other_media => {}
+                "a" => {
+                    let payload_type = media.map(|(_, pt)| pt);
+                    if value.starts_with("rtpmap:") {
+                        let rest = &value["rtpmap:".len()..];
+                        if parse_payload_type(rest) == payload_type {
+                            rtpmap = Some(attribute_value(rest).to_owned());
+                        }
+                    } else if value.starts_with("fmtp:") {
+                        let rest = &value["fmtp:".len()..];
+                        if parse_payload_type(rest) == payload_type {
+                            fmtp = Some(attribute_value(rest).to_owned());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let (port, payload_type) =
+            media.ok_or_else(|| Error::new_wrong_argument("No audio stream in SDP"))?;
+        let rtpmap = rtpmap.ok_or_else(|| {
+            Error::new_wrong_argument(format!("No rtpmap for payload type: {}", payload_type))
+        })?;
+        let fmtp = parse_fmtp(fmtp.as_ref().map_or("", |f| f.as_str()));
+        let rtp = parse_rtpmap(payload_type, &rtpmap, &fmtp)?;
+
+        Ok(Self {
+            connection: media_connection.or(session_connection),
+            port,
+            rtp,
+        })
+    }
+
+    /// The address to bind to, the sender streams to it
+    pub fn local_addr(&self) -> SocketAddr {
+        let ip = match self.connection {
+            Some(IpAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+            _ => Ipv4Addr::UNSPECIFIED.into(),
+        };
+        SocketAddr::new(ip, self.port)
+    }
+}
+
+/// c=IN IP4|IP6 <address>[/<ttl>][/<qty>]
+fn parse_connection(value: &str) -> Result<IpAddr, Error> {
+    let address = value
+        .split_whitespace()
+        .nth(2)
+        .ok_or_else(|| Error::new_wrong_argument(format!("Wrong SDP connection: {}", value)))?;
+    let address = address.split('/').next().unwrap_or(address);
+    address
+        .parse()
+        .map_err(|e| Error::new_net_parse(e, address.to_owned()))
+}
+
+/// m=audio <port> RTP/AVP <payload type>..., only the first payload type is used
+fn parse_media(value: &str) -> Result<(u16, u8), Error> {
+    let wrong_media = || Error::new_wrong_argument(format!("Wrong SDP media: {}", value));
+
+    let mut fields = value.split_whitespace().skip(1);
+    let port = fields.next().ok_or_else(wrong_media)?;
+    let port = port.split('/').next().unwrap_or(port);
+    let port = port.parse().map_err(|_| wrong_media())?;
+    let proto = fields.next().ok_or_else(wrong_media)?;
+    if !proto.starts_with("RTP/") {
+        return Err(Error::new_wrong_argument(format!(
+            "Unsupported SDP transport: {}",
+            proto
+        )));
+    }
+    let payload_type = fields
+        .next()
+        .and_then(|pt| pt.parse().ok())
+        .ok_or_else(wrong_media)?;
+
+    Ok((port, payload_type))
+}
+
+/// `<payload type> <value>` of `rtpmap` and `fmtp`
+fn parse_payload_type(attribute: &str) -> Option<u8> {
+    attribute.split_whitespace().next()?.parse().ok()
+}
+
+fn attribute_value(attribute: &str) -> &str {
+    attribute
+        .find(char::is_whitespace)
+        .map_or("", |idx| attribute[idx..].trim())
+}
+
+/// `key=value; ...`, the keys are case-insensitive
+fn parse_fmtp(fmtp: &str) -> HashMap<String, String> {
+    fmtp.split(';')
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next()?.trim().to_ascii_lowercase();
+            let value = kv.next()?.trim().to_owned();
+            Some((key, value))
+        })
+        .collect()
+}
+
+/// <encoding name>/<clock rate>[/<channels>]
+fn parse_rtpmap(
+    payload_type: u8,
+    rtpmap: &str,
+    fmtp: &HashMap<String, String>,
+) -> Result<RtpParams, Error> {
+    let wrong_rtpmap = || Error::new_wrong_argument(format!("Wrong SDP rtpmap: {}", rtpmap));
+
+    let mut fields = rtpmap.split('/');
+    let encoding = fields.next().ok_or_else(wrong_rtpmap)?.to_ascii_lowercase();
+    let clock_rate = fields
+        .next()
+        .and_then(|rate| rate.parse().ok())
+        .ok_or_else(wrong_rtpmap)?;
+    let channels = match fields.next() {
+        Some(channels) => channels.parse().map_err(|_| wrong_rtpmap())?,
+        None => 1,
+    };
+
+    let format = match encoding.as_str() {
+        "mpeg4-generic" => parse_mpeg4_generic(fmtp, clock_rate, channels)?,
+        _ => {
+            return Err(Error::new_wrong_argument(format!(
+                "Unsupported RTP encoding: {}",
+                encoding
+            )));
+        }
+    };
+
+    Ok(RtpParams {
+        payload_type,
+        format,
+        clock_rate,
+        channels,
+    })
+}
+
+/// RFC 3640, 4.1: `config` is AudioSpecificConfig in hex
+fn parse_mpeg4_generic(
+    fmtp: &HashMap<String, String>,
+    clock_rate: u32,
+    channels: u8,
+) -> Result<RtpFormat, Error> {
+    let read_u8 = |key: &str, default: u8| -> Result<u8, Error> {
+        match fmtp.get(key) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| Error::new_wrong_argument(format!("Wrong fmtp {}: {}", key, value))),
+            None => Ok(default),
+        }
+    };
+
+    let config = match fmtp.get("config") {
+        Some(config) => AacConfig::parse(&decode_hex(config)?)?,
+        None => AacConfig {
+            audio_object_type: 2,
+            sample_rate: clock_rate,
+            output_sample_rate: clock_rate,
+            channels,
+        },
+    };
+    if config.channels != channels {
+        info!(
+            "AAC config channels: {} differ from rtpmap: {}",
+            config.channels, channels
+        );
+    }
+
+    Ok(RtpFormat::Mpeg4Generic {
+        size_length: read_u8("sizelength", 0)?,
+        index_length: read_u8("indexlength", 0)?,
+        index_delta_length: read_u8("indexdeltalength", 0)?,
+        config,
+    })
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(Error::new_wrong_argument(format!("Wrong hex: {}", hex)));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| Error::new_wrong_argument(format!("Wrong hex: {}", hex)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `ffmpeg -f rtp` prints for AAC
+    const FFMPEG_SDP: &str = "v=0\r
+o=- 0 0 IN IP4 127.0.0.1\r
+s=No Name\r
+c=IN IP4 192.168.1.20\r
+t=0 0\r
+a=tool:libavformat 58.29.100\r
+m=audio 5004 RTP/AVP 97\r
+b=AS:128\r
+a=rtpmap:97 MPEG4-GENERIC/48000/2\r
+a=fmtp:97 profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3; config=1190\r
+";
+
+    #[test]
+    fn parses_ffmpeg_sdp() {
+        let sdp = SessionDescription::parse(FFMPEG_SDP).unwrap();
+
+        assert_eq!(sdp.connection, Some("192.168.1.20".parse().unwrap()));
+        assert_eq!(sdp.port, 5004);
+        assert_eq!(sdp.local_addr(), "0.0.0.0:5004".parse().unwrap());
+        assert_eq!(
+            sdp.rtp,
+            RtpParams {
+                payload_type: 97,
+                format: RtpFormat::Mpeg4Generic {
+                    size_length: 13,
+                    index_length: 3,
+                    index_delta_length: 3,
+                    config: AacConfig {
+                        audio_object_type: 2,
+                        sample_rate: 48000,
+                        output_sample_rate: 48000,
+                        channels: 2,
+                    },
+                },
+                clock_rate: 48000,
+                channels: 2,
+            }
+        );
+
+        let info = sdp.rtp.stream_info();
+        assert_eq!((info.sample_rate, info.channels), (48000, 2));
+        assert_eq!(info.frame_size, 1024);
+    }
+
+    #[test]
+    fn stream_info_follows_aac_config() {
+        // HE-AAC: 24000 Hz core, 48000 Hz with SBR, the RTP clock runs at 90000
+        let sdp = "m=audio 5004 RTP/AVP 96\n\
+                   a=rtpmap:96 mpeg4-generic/90000/2\n\
+                   a=fmtp:96 sizeLength=13; indexLength=3; indexDeltaLength=3; config=2B1188\n";
+        let sdp = SessionDescription::parse(sdp).unwrap();
+
+        match &sdp.rtp.format {
+            RtpFormat::Mpeg4Generic { config, .. } => {
+                assert_eq!(config.audio_object_type, 2);
+                assert_eq!(config.sample_rate, 24000);
+                assert_eq!(config.output_sample_rate, 48000);
+            }
+        }
+
+        let info = sdp.rtp.stream_info();
+        assert_eq!((info.sample_rate, info.channels), (48000, 2));
+        assert_eq!(info.frame_size, 2048);
+    }
+
+    #[test]
+    fn takes_first_audio_stream_and_its_connection() {
+        let sdp = "v=0\n\
+                   c=IN IP4 192.168.1.20\n\
+                   m=video 5000 RTP/AVP 96\n\
+                   c=IN IP4 192.168.1.30\n\
+                   a=rtpmap:96 H264/90000\n\
+                   m=audio 5002 RTP/AVP 96\n\
+                   c=IN IP6 ff02::1/3\n\
+                   a=rtpmap:96 mpeg4-generic/44100\n\
+                   a=fmtp:96 sizelength=13;indexlength=3;indexdeltalength=3\n\
+                   m=audio 5004 RTP/AVP 97\n\
+                   a=rtpmap:97 mpeg4-generic/48000/2\n";
+        let sdp = SessionDescription::parse(sdp).unwrap();
+
+        assert_eq!(sdp.connection, Some("ff02::1".parse().unwrap()));
+        assert_eq!(sdp.local_addr(), "[::]:5002".parse().unwrap());
+        assert_eq!(sdp.rtp, RtpParams::new_aac_hbr(96, 44100, 1));
+    }
+
+    #[test]
+    fn rejects_unsupported_sdp() {
+        let no_audio = "m=video 5000 RTP/AVP 96\na=rtpmap:96 H264/90000\n";
+        assert!(SessionDescription::parse(no_audio).is_err());
+
+        let no_rtpmap = "m=audio 5004 RTP/AVP 96\na=rtpmap:97 mpeg4-generic/48000/2\n";
+        assert!(SessionDescription::parse(no_rtpmap).is_err());
+
+        let opus = "m=audio 5004 RTP/AVP 96\na=rtpmap:96 opus/48000/2\n";
+        assert!(SessionDescription::parse(opus).is_err());
+
+        let pcm = "m=audio 5004 RTP/AVP 96\na=rtpmap:96 L16/44100/2\n";
+        assert!(SessionDescription::parse(pcm).is_err());
+
+        let not_rtp = "m=audio 5004 udp 96\na=rtpmap:96 mpeg4-generic/48000/2\n";
+        assert!(SessionDescription::parse(not_rtp).is_err());
+
+        let wrong_config = "m=audio 5004 RTP/AVP 96\n\
+                            a=rtpmap:96 mpeg4-generic/48000/2\n\
+                            a=fmtp:96 config=12x0\n";
+        assert!(SessionDescription::parse(wrong_config).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::error::Error;
+use log::info;
+use mio::net::UdpSocket;
+use net2::UdpBuilder;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+/// Binds a UDP socket able to reach `remote_addr`.
+/// An unspecified `local_addr` of the other family is replaced by the unspecified address
+/// of the remote family. IPv6 wildcard sockets are dual-stack, so they reach IPv4 servers too.
+pub fn bind_udp(remote_addr: &SocketAddr, local_addr: SocketAddr) -> Result<UdpSocket, Error> {
+    let local_addr = match (remote_addr, local_addr.ip()) {
+        (SocketAddr::V6(_), IpAddr::V4(ip)) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local_addr.port())
+        }
+        _ => local_addr,
+    };
+
+    let socket = match local_addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?.bind(&local_addr),
+        SocketAddr::V6(addr) => {
+            let builder = UdpBuilder::new_v6()?;
+            builder.only_v6(!addr.ip().is_unspecified())?;
+            builder.bind(&local_addr)
+        }
+    }
+    .map_err(|e| Error::new_io(e, format!("binding to {}", local_addr)))?;
+
+    let socket = UdpSocket::from_socket(socket)?;
+    info!("Bound to {}", socket.local_addr()?);
+    Ok(socket)
+}
+
+/// Addresses received on a dual-stack socket are IPv4-mapped, turns them back into IPv4
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match to_ipv4_mapped(v6.ip()) {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Same as `SocketAddr::from_str`, but also accepts IPv6 scope ids: `[fe80::1%wlan0]:25204`.
+/// The scope is either an interface index or an interface name.
+pub fn parse_socket_addr(s: &str) -> Result<SocketAddr, Error> {
+    let parse_err = match s.parse() {
+        Ok(addr) => {
+            return Ok(addr);
+        }
+        Err(e) => e,
+    };
+
+    let (ip_and_scope, port) = match split_bracketed(s) {
+        Some(parts) => parts,
+        None => {
+            return Err(Error::new_net_parse(parse_err, s.to_owned()));
+        }
+    };
+    let (ip, scope) = match ip_and_scope.find('%') {
+        Some(idx) => (&ip_and_scope[..idx], &ip_and_scope[idx + 1..]),
+        None => {
+            return Err(Error::new_net_parse(parse_err, s.to_owned()));
+        }
+    };
+
+    let ip: Ipv6Addr = ip
+        .parse()
+        .map_err(|e| Error::new_net_parse(e, s.to_owned()))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| Error::new_net_parse(parse_err, s.to_owned()))?;
+    let scope_id = parse_scope_id(scope)?;
+
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id).into())
+}
+
+/// Splits `[ip]:port` into `ip` and `port`
+fn split_bracketed(s: &str) -> Option<(&str, &str)> {
+    if !s.starts_with('[') {
+        return None;
+    }
+    let end = s.find("]:")?;
+    Some((&s[1..end], &s[end + 2..]))
+}
+
+/// The interface index or name of an IPv6 scope
+pub fn parse_scope_id(scope: &str) -> Result<u32, Error> {
+    if let Ok(idx) = scope.parse() {
+        return Ok(idx);
+    }
+
+    let name = CString::new(scope)
+        .map_err(|_| Error::new_wrong_argument(format!("Wrong interface name: {}", scope)))?;
+    let idx = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if idx == 0 {
+        return Err(Error::new_wrong_argument(format!(
+            "Unknown network interface: {}",
+            scope
+        )));
+    }
+
+    Ok(idx)
+}
+
+fn to_ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(Ipv4Addr::new(
+            (hi >> 8) as u8,
+            hi as u8,
+            (lo >> 8) as u8,
+            lo as u8,
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scoped_link_local_addr() {
+        let lo_idx = unsafe { libc::if_nametoindex(b"lo\0".as_ptr() as *const libc::c_char) };
+        assert_ne!(lo_idx, 0);
+
+        let expected = SocketAddrV6::new("fe80::1".parse().unwrap(), 25204, 0, lo_idx);
+        assert_eq!(
+            parse_socket_addr("[fe80::1%lo]:25204").unwrap(),
+            SocketAddr::V6(expected)
+        );
+
+        let expected = SocketAddrV6::new("fe80::1".parse().unwrap(), 25204, 0, 3);
+        assert_eq!(
+            parse_socket_addr("[fe80::1%3]:25204").unwrap(),
+            SocketAddr::V6(expected)
+        );
+
+        assert!(parse_socket_addr("[fe80::1%no-such-if0]:25204").is_err());
+        assert!(parse_socket_addr("[fe80::1%lo]").is_err());
+        assert_eq!(
+            parse_socket_addr("192.168.1.2:25204").unwrap(),
+            "192.168.1.2:25204".parse().unwrap()
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Counts datagrams rejected because they don't come from the server address
+pub struct SourceFilter {
+    rejected: HashMap<SocketAddr, RejectedCnt>,
+    /// Sources over `MAX_TRACKED_SOURCES` are counted here, not to grow without bound
+    rejected_untracked: RejectedCnt,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct RejectedCnt {
+    pkts: u64,
+    bytes: u64,
+}
+
+const MAX_TRACKED_SOURCES: usize = 64;
+const LOG_EVERY_PKTS: u64 = 1000;
+
+impl SourceFilter {
+    pub fn new() -> Self {
+        Self {
+            rejected: HashMap::new(),
+            rejected_untracked: Default::default(),
+        }
+    }
+
+    pub fn reject(&mut self, from: SocketAddr, len: usize) {
+        let cnt = if self.rejected.len() < MAX_TRACKED_SOURCES || self.rejected.contains_key(&from)
+        {
+            self.rejected.entry(from).or_default()
+        } else {
+            &mut self.rejected_untracked
+        };
+
+        cnt.pkts += 1;
+        cnt.bytes += len as u64;
+
+        if cnt.pkts % LOG_EVERY_PKTS == 1 {
+            warn!(
+                "Rejected {} packets ({} bytes) from unexpected source: {}",
+                cnt.pkts, cnt.bytes, from
+            );
+        }
+    }
+}
+
+impl fmt::Display for SourceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rejected from {} sources", self.rejected.len())?;
+        for (addr, cnt) in &self.rejected {
+            write!(f, ", {}: {} pkts. {} bytes", addr, cnt.pkts, cnt.bytes)?;
+        }
+        if self.rejected_untracked.pkts > 0 {
+            write!(
+                f,
+                ", others: {} pkts. {} bytes",
+                self.rejected_untracked.pkts, self.rejected_untracked.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
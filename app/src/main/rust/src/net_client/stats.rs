@@ -0,0 +1,178 @@
+use crate::util::serial;
+use std::time::{Duration, Instant};
+
+/// Receive statistics of the audio stream, computed the way RTP receivers do (RFC 3550, A.3, A.8).
+///
+/// Only the primary packets are counted: the redundant copies and the parity packets don't
+/// have their own place in the `cnt` sequence.
+pub struct ReceiveStats {
+    /// Arrival times are measured from it
+    started: Instant,
+    base_cnt: u32,
+    max_cnt: u32,
+    /// The count of `cnt` wraparounds, the extended max cnt is `cycles << 32 | max_cnt`
+    cycles: u64,
+    /// Bit `i` is set if `max_cnt - i` has been received
+    history: u64,
+    is_started: bool,
+    received: u64,
+    duplicated: u64,
+    reordered: u64,
+    /// The expected and the received qty at the time of the previous `take_fraction_lost`
+    expected_prior: u64,
+    received_prior: u64,
+    last_transit: Option<i64>,
+    /// In microseconds, scaled by 16 as in RFC 3550 A.8 to keep the precision
+    jitter: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReceiveStatsSnapshot {
+    /// Including the duplicates
+    pub received: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    /// Arrived after a frame with a greater cnt
+    pub reordered: u64,
+    pub max_cnt: u32,
+    /// Interarrival jitter
+    pub jitter: Duration,
+}
+
+/// A bigger jump of `cnt` restarts the sequence, the server has restarted the stream
+const MAX_CNT_JUMP: i32 = 512;
+const HISTORY_LEN: i32 = 64;
+
+impl ReceiveStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            base_cnt: 0,
+            max_cnt: 0,
+            cycles: 0,
+            history: 0,
+            is_started: false,
+            received: 0,
+            duplicated: 0,
+            reordered: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            last_transit: None,
+            jitter: 0,
+        }
+    }
+
+    /// `timestamp` is the sender time of the packet in microseconds
+    pub fn on_pkt(&mut self, cnt: u32, timestamp: u64) {
+        self.received += 1;
+        self.update_jitter(timestamp);
+
+        if !self.is_started {
+            self.restart_sequence(cnt);
+            return;
+        }
+
+        let ahead = serial::diff(cnt, self.max_cnt);
+        if ahead > MAX_CNT_JUMP || ahead < -MAX_CNT_JUMP {
+            self.restart_sequence(cnt);
+        } else if ahead > 0 {
+            if cnt < self.max_cnt {
+                self.cycles += 1;
+            }
+            self.history = if ahead < HISTORY_LEN {
+                self.history << ahead | 1
+            } else {
+                1
+            };
+            self.max_cnt = cnt;
+        } else if ahead == 0 {
+            self.duplicated += 1;
+        } else if -ahead >= HISTORY_LEN {
+            // Too old to tell a duplicate from a reordered one
+            self.reordered += 1;
+        } else {
+            let bit = 1 << -ahead;
+            if self.history & bit != 0 {
+                self.duplicated += 1;
+            } else {
+                self.history |= bit;
+                self.reordered += 1;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn snapshot(&self) -> ReceiveStatsSnapshot {
+        if !self.is_started {
+            return ReceiveStatsSnapshot::default();
+        }
+
+        ReceiveStatsSnapshot {
+            received: self.received,
+            lost: self.expected().saturating_sub(self.unique()),
+            duplicated: self.duplicated,
+            reordered: self.reordered,
+            max_cnt: self.max_cnt,
+            jitter: Duration::from_micros(self.jitter >> 4),
+        }
+    }
+
+    /// The fraction of the packets lost since the previous call, in 1/256
+    pub fn take_fraction_lost(&mut self) -> u8 {
+        let expected = self.expected();
+        let received = self.unique();
+
+        let expected_interval = expected.saturating_sub(self.expected_prior);
+        let received_interval = received.saturating_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = received;
+
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        if expected_interval == 0 {
+            0
+        } else {
+            std::cmp::min((lost_interval << 8) / expected_interval, 255) as u8
+        }
+    }
+
+    fn expected(&self) -> u64 {
+        if !self.is_started {
+            return 0;
+        }
+
+        let extended_max = self.cycles << 32 | self.max_cnt as u64;
+        extended_max - self.base_cnt as u64 + 1
+    }
+
+    fn unique(&self) -> u64 {
+        self.received - self.duplicated
+    }
+
+    fn restart_sequence(&mut self, cnt: u32) {
+        self.is_started = true;
+        self.base_cnt = cnt;
+        self.max_cnt = cnt;
+        self.cycles = 0;
+        self.history = 1;
+        // The packets of the previous sequence don't count
+        self.received = 1;
+        self.duplicated = 0;
+        self.reordered = 0;
+        self.expected_prior = 0;
+        self.received_prior = 0;
+    }
+
+    /// J(i) = J(i-1) + (|D(i-1,i)| - J(i-1)) / 16, where D is the difference of the transit times
+    fn update_jitter(&mut self, timestamp: u64) {
+        let arrival = self.started.elapsed().as_micros() as i64;
+        let transit = arrival.wrapping_sub(timestamp as i64);
+
+        if let Some(last_transit) = self.last_transit.replace(transit) {
+            let d = transit.wrapping_sub(last_transit).wrapping_abs() as u64;
+            self.jitter = self.jitter + d - ((self.jitter + 8) >> 4);
+        }
+    }
+}
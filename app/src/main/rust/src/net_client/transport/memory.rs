@@ -0,0 +1,306 @@
+use super::{Connector, Transport};
+use crate::error::Error;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What happens to a datagram sent over a `MemoryTransport`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fate {
+    Deliver,
+    Lose,
+    /// Delivered after so many datagrams sent later
+    Reorder(usize),
+    /// Delivered after this time, the datagrams sent later may overtake it
+    Delay(Duration),
+}
+
+/// Lets `PollLoop` run without the network: the datagrams go through memory
+/// to a `MemoryPeer` standing in for the server, and meet the fates scripted for them.
+pub struct MemoryTransport {
+    peer_addr: SocketAddr,
+    local_port: u16,
+    is_reliable: bool,
+    registration: mio::Registration,
+    to_peer: Arc<Mutex<Link>>,
+    from_peer: Arc<Mutex<Link>>,
+}
+
+/// The server end of a `MemoryTransport`, driven by the test
+pub struct MemoryPeer {
+    addr: SocketAddr,
+    to_client: Arc<Mutex<Link>>,
+    from_client: Arc<Mutex<Link>>,
+}
+
+/// Hands the transports out to `PollLoop` in the order they are added, for UDP and TCP alike,
+/// whatever address is connected to. Fails once they are exhausted, like an unreachable server.
+#[derive(Clone)]
+pub struct MemoryConnector {
+    transports: Arc<Mutex<VecDeque<MemoryTransport>>>,
+}
+
+/// One direction of the transport
+struct Link {
+    /// Applied to the datagrams in turn, the rest are delivered
+    script: VecDeque<Fate>,
+    /// `(deliver at, sender, datagram)` in the order of sending
+    queue: VecDeque<(Instant, SocketAddr, Vec<u8>)>,
+    /// `(datagrams to wait for, sender, datagram)` held by `Fate::Reorder`
+    held: Vec<(usize, SocketAddr, Vec<u8>)>,
+    is_closed: bool,
+    /// Makes `MemoryTransport` readable, `None` on the way to `MemoryPeer`
+    set_readiness: Option<mio::SetReadiness>,
+}
+
+impl MemoryTransport {
+    /// With `is_reliable` it stands in for TCP, `Fate::Lose` must not be scripted then
+    pub fn pair(peer_addr: SocketAddr, local_port: u16, is_reliable: bool) -> (Self, MemoryPeer) {
+        let (registration, set_readiness) = mio::Registration::new2();
+        let to_peer = Arc::new(Mutex::new(Link::new(None)));
+        let from_peer = Arc::new(Mutex::new(Link::new(Some(set_readiness))));
+
+        let transport = Self {
+            peer_addr,
+            local_port,
+            is_reliable,
+            registration,
+            to_peer: to_peer.clone(),
+            from_peer: from_peer.clone(),
+        };
+        let peer = MemoryPeer {
+            addr: peer_addr,
+            to_client: from_peer,
+            from_client: to_peer,
+        };
+        (transport, peer)
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.local_port)
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let mut link = self.to_peer.lock().unwrap();
+        if link.is_closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        link.push(self.local_addr(), datagram.to_vec());
+        Ok(())
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        let mut link = self.from_peer.lock().unwrap();
+        match link.pop() {
+            Some((from, datagram)) => {
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                Ok(Some((datagram.len(), from)))
+            }
+            None if link.is_closed => Err(io::ErrorKind::UnexpectedEof.into()),
+            None => {
+                // To be notified on the next datagram. A delayed one may have got due meanwhile,
+                // its readiness is set without the lock
+                link.set_ready(mio::Ready::empty());
+                if link.is_due() {
+                    link.set_ready(mio::Ready::readable());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()> {
+        poll.register(
+            &self.registration,
+            token,
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn set_peer_addr(&mut self, addr: SocketAddr) {
+        if !self.is_reliable {
+            self.peer_addr = addr;
+        }
+    }
+
+    fn local_port(&self) -> io::Result<u16> {
+        Ok(self.local_port)
+    }
+
+    fn is_reliable(&self) -> bool {
+        self.is_reliable
+    }
+}
+
+impl MemoryPeer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Scripts the fates of the next datagrams sent to the client
+    pub fn script_to_client(&self, fates: &[Fate]) {
+        self.to_client.lock().unwrap().script.extend(fates);
+    }
+
+    /// Scripts the fates of the next datagrams sent by the client
+    pub fn script_from_client(&self, fates: &[Fate]) {
+        self.from_client.lock().unwrap().script.extend(fates);
+    }
+
+    pub fn send(&self, datagram: &[u8]) {
+        self.send_from(self.addr, datagram);
+    }
+
+    /// As if sent by another host or from another port
+    pub fn send_from(&self, from: SocketAddr, datagram: &[u8]) {
+        self.to_client.lock().unwrap().push(from, datagram.to_vec());
+    }
+
+    /// The next datagram from the client, `None` if none has arrived yet
+    pub fn receive(&self) -> Option<Vec<u8>> {
+        let mut link = self.from_client.lock().unwrap();
+        link.pop().map(|(_, datagram)| datagram)
+    }
+
+    /// Waits for the next datagram from the client, `PollLoop` runs in its own thread
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(datagram) = self.receive() {
+                return Some(datagram);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Closes both directions, as the server closing a TCP connection.
+    /// What's already on the way is still delivered
+    pub fn close(&self) {
+        self.from_client.lock().unwrap().is_closed = true;
+        let mut link = self.to_client.lock().unwrap();
+        link.is_closed = true;
+        link.set_ready(mio::Ready::readable());
+    }
+}
+
+impl MemoryConnector {
+    pub fn new() -> Self {
+        Self {
+            transports: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn add(&self, transport: MemoryTransport) {
+        self.transports.lock().unwrap().push_back(transport);
+    }
+
+    fn next(&self, descr: String) -> Result<Box<dyn Transport>, Error> {
+        match self.transports.lock().unwrap().pop_front() {
+            Some(transport) => Ok(Box::new(transport)),
+            None => Err(Error::new_io(
+                io::ErrorKind::ConnectionRefused.into(),
+                descr,
+            )),
+        }
+    }
+}
+
+impl Connector for MemoryConnector {
+    fn bind_udp(
+        &mut self,
+        _remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Result<Box<dyn Transport>, Error> {
+        self.next(format!("binding to {}", local_addr))
+    }
+
+    fn connect_tcp(&mut self, remote_addr: SocketAddr) -> Result<Box<dyn Transport>, Error> {
+        self.next(format!("connecting to {}", remote_addr))
+    }
+}
+
+impl Link {
+    fn new(set_readiness: Option<mio::SetReadiness>) -> Self {
+        Self {
+            script: VecDeque::new(),
+            queue: VecDeque::new(),
+            held: Vec::new(),
+            is_closed: false,
+            set_readiness,
+        }
+    }
+
+    fn push(&mut self, from: SocketAddr, datagram: Vec<u8>) {
+        let now = Instant::now();
+        match self.script.pop_front().unwrap_or(Fate::Deliver) {
+            Fate::Deliver | Fate::Reorder(0) => self.deliver_at(now, from, datagram),
+            Fate::Lose => {}
+            Fate::Reorder(n) => self.held.push((n, from, datagram)),
+            Fate::Delay(delay) => {
+                self.queue.push_back((now + delay, from, datagram));
+                if let Some(set_readiness) = self.set_readiness.clone() {
+                    thread::spawn(move || {
+                        thread::sleep(delay);
+                        log_and_ignore_err!(set_readiness.set_readiness(mio::Ready::readable()));
+                    });
+                }
+            }
+        }
+    }
+
+    /// Also releases the held datagrams which have waited for enough others
+    fn deliver_at(&mut self, at: Instant, from: SocketAddr, datagram: Vec<u8>) {
+        self.queue.push_back((at, from, datagram));
+
+        let mut idx = 0;
+        while idx < self.held.len() {
+            self.held[idx].0 -= 1;
+            if self.held[idx].0 == 0 {
+                let (_, from, datagram) = self.held.remove(idx);
+                self.queue.push_back((at, from, datagram));
+            } else {
+                idx += 1;
+            }
+        }
+
+        self.set_ready(mio::Ready::readable());
+    }
+
+    /// The first datagram due
+    fn pop(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        let now = Instant::now();
+        let idx = self.queue.iter().position(|&(at, _, _)| at <= now)?;
+        self.queue
+            .remove(idx)
+            .map(|(_, from, datagram)| (from, datagram))
+    }
+
+    fn is_due(&self) -> bool {
+        let now = Instant::now();
+        self.queue.iter().any(|&(at, _, _)| at <= now)
+    }
+
+    fn set_ready(&self, ready: mio::Ready) {
+        if let Some(set_readiness) = &self.set_readiness {
+            log_and_ignore_err!(set_readiness.set_readiness(ready));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+#[cfg(test)]
+mod memory;
+mod tcp;
+mod udp;
+
+#[cfg(test)]
+pub use self::memory::{Fate, MemoryConnector, MemoryPeer, MemoryTransport};
+pub use self::tcp::FramedStream;
+pub use self::udp::UdpTransport;
+
+use crate::error::Error;
+use std::io;
+use std::net::SocketAddr;
+
+/// Carries the datagrams between `PollLoop` and the server
+pub trait Transport: Send {
+    /// Sends one datagram to the peer
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()>;
+
+    /// Copies the next received datagram to `buf` and returns its length and sender,
+    /// `None` if there's none until the transport becomes readable again
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>>;
+
+    /// Registers with the readiness the transport needs, edge-triggered
+    fn register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()>;
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()>;
+
+    fn peer_addr(&self) -> SocketAddr;
+
+    /// Follows the server restarted on another port.
+    /// Connection-based transports ignore it, their peer can't move
+    fn set_peer_addr(&mut self, addr: SocketAddr);
+
+    fn local_port(&self) -> io::Result<u16>;
+
+    /// Nothing is lost or reordered, the requests are not retransmitted.
+    /// An error receiving from it means the connection is lost
+    fn is_reliable(&self) -> bool;
+}
+
+/// Creates the transports for every server address `PollLoop` tries
+pub trait Connector: Send {
+    fn bind_udp(
+        &mut self,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Result<Box<dyn Transport>, Error>;
+
+    fn connect_tcp(&mut self, remote_addr: SocketAddr) -> Result<Box<dyn Transport>, Error>;
+}
+
+/// The real network
+pub struct NetConnector;
+
+impl Connector for NetConnector {
+    fn bind_udp(
+        &mut self,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> Result<Box<dyn Transport>, Error> {
+        Ok(Box::new(UdpTransport::bind(remote_addr, local_addr)?))
+    }
+
+    fn connect_tcp(&mut self, remote_addr: SocketAddr) -> Result<Box<dyn Transport>, Error> {
+        Ok(Box::new(FramedStream::connect(remote_addr)?))
+    }
+}
@@ -0,0 +1,290 @@
+use super::Transport;
+use crate::error::Error;
+use crate::net_client::is_try_again;
+use log::info;
+use mio::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+/// Carries the same datagrams as the UDP socket over TCP, for the networks blocking UDP.
+/// Every datagram is prefixed by its length:
+///
+/// | len: u16 | datagram |
+pub struct FramedStream {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    /// The received bytes, the datagrams before `read_pos` are already taken
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// The framed datagrams not yet accepted by the socket
+    write_buf: Vec<u8>,
+}
+
+const LEN_SIZE: usize = 2;
+const READ_CHUNK: usize = 16 * 1024;
+/// The datagrams are dropped beyond this, the same as with a full UDP send buffer
+const MAX_WRITE_BUF_LEN: usize = 64 * 1024;
+
+impl FramedStream {
+    /// Doesn't wait for the connection, the datagrams sent before it's established are buffered
+    pub fn connect(addr: SocketAddr) -> Result<Self, Error> {
+        let stream = TcpStream::connect(&addr)
+            .map_err(|e| Error::new_io(e, format!("connecting to {}", addr)))?;
+        stream.set_nodelay(true)?;
+        info!("Connecting over TCP to {}", addr);
+
+        Ok(Self {
+            stream,
+            peer_addr: addr,
+            read_buf: Vec::with_capacity(READ_CHUNK),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        })
+    }
+
+    /// Writes out what's buffered, called again when the stream becomes writable
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(ref e) if is_try_again(e) => break,
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_datagram(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let pending = &self.read_buf[self.read_pos..];
+        if pending.len() < LEN_SIZE {
+            return None;
+        }
+        let len = u16::from_be_bytes([pending[0], pending[1]]) as usize;
+        if pending.len() < LEN_SIZE + len {
+            return None;
+        }
+
+        buf[..len].copy_from_slice(&pending[LEN_SIZE..LEN_SIZE + len]);
+        self.read_pos += LEN_SIZE + len;
+        Some(len)
+    }
+}
+
+impl Transport for FramedStream {
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if datagram.len() > u16::max_value() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Datagram is too long",
+            ));
+        }
+        if self.write_buf.len() + LEN_SIZE + datagram.len() > MAX_WRITE_BUF_LEN {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        self.write_buf
+            .extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+        self.write_buf.extend_from_slice(datagram);
+        self.flush()
+    }
+
+    /// Also writes out what's left from `send`.
+    /// The connection closed by the server is `UnexpectedEof`
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        self.flush()?;
+
+        loop {
+            if let Some(n) = self.take_datagram(buf) {
+                return Ok(Some((n, self.peer_addr)));
+            }
+
+            self.read_buf.drain(..self.read_pos);
+            self.read_pos = 0;
+
+            let len = self.read_buf.len();
+            self.read_buf.resize(len + READ_CHUNK, 0);
+            let res = self.stream.read(&mut self.read_buf[len..]);
+            self.read_buf.truncate(len + *res.as_ref().unwrap_or(&0));
+            match res {
+                Ok(0) => {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(_) => {}
+                Err(ref e) if is_try_again(e) => {
+                    return Ok(None);
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()> {
+        poll.register(
+            &self.stream,
+            token,
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        poll.deregister(&self.stream)
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn set_peer_addr(&mut self, _addr: SocketAddr) {}
+
+    fn local_port(&self) -> io::Result<u16> {
+        Ok(self.stream.local_addr()?.port())
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn connect() -> (FramedStream, std::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = FramedStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        peer.set_nodelay(true).unwrap();
+        peer.set_read_timeout(Some(TIMEOUT)).unwrap();
+        (stream, peer)
+    }
+
+    /// Receives until `is_done` is true for the stream, returns the last result
+    fn receive_until<F>(
+        stream: &mut FramedStream,
+        buf: &mut [u8],
+        is_done: F,
+    ) -> io::Result<Option<usize>>
+    where
+        F: Fn(&FramedStream, &io::Result<Option<usize>>) -> bool,
+    {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let res = stream.receive(buf).map(|r| r.map(|(n, _)| n));
+            if is_done(stream, &res) || Instant::now() > deadline {
+                return res;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn receive(stream: &mut FramedStream, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        receive_until(stream, buf, |_, res| match res {
+            Ok(None) => false,
+            _ => true,
+        })
+    }
+
+    /// Waits until `len` bytes are buffered, none of them make a whole datagram yet
+    fn receive_partial(stream: &mut FramedStream, buf: &mut [u8], len: usize) {
+        let res = receive_until(stream, buf, |s, _| s.read_buf.len() == len);
+        assert!(res.unwrap().is_none());
+        assert_eq!(stream.read_buf.len(), len);
+    }
+
+    #[test]
+    fn reassembles_split_length_prefix() {
+        let (mut stream, mut peer) = connect();
+        let mut buf = [0; 64];
+
+        peer.write_all(&[0]).unwrap();
+        receive_partial(&mut stream, &mut buf, 1);
+
+        peer.write_all(&[3, 7, 8, 9]).unwrap();
+        assert_eq!(receive(&mut stream, &mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], &[7, 8, 9]);
+    }
+
+    #[test]
+    fn reassembles_split_datagram() {
+        let (mut stream, mut peer) = connect();
+        let mut buf = [0; 64];
+
+        peer.write_all(&[0, 4, 1, 2]).unwrap();
+        receive_partial(&mut stream, &mut buf, 4);
+
+        // The rest of the first datagram, a whole one and the prefix of the third
+        peer.write_all(&[3, 4, 0, 1, 5, 0]).unwrap();
+        assert_eq!(receive(&mut stream, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+        assert_eq!(receive(&mut stream, &mut buf).unwrap(), Some(1));
+        assert_eq!(buf[0], 5);
+        receive_partial(&mut stream, &mut buf, 1);
+
+        peer.write_all(&[0]).unwrap();
+        assert_eq!(receive(&mut stream, &mut buf).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn buffers_writes_up_to_limit() {
+        let (mut stream, mut peer) = connect();
+        let datagram = vec![0x5a; 30 * 1024];
+
+        // The peer doesn't read, the socket buffers fill up first
+        let mut sent = 0;
+        let err = loop {
+            match stream.send(&datagram) {
+                Ok(()) => sent += 1,
+                Err(e) => break e,
+            }
+            assert!(sent < 10_000, "Send never blocks");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(!stream.write_buf.is_empty());
+        assert!(stream.write_buf.len() <= MAX_WRITE_BUF_LEN);
+
+        // What's accepted is delivered once the peer reads
+        let reader = std::thread::spawn(move || {
+            let mut frame = vec![0; LEN_SIZE + datagram.len()];
+            for _ in 0..sent {
+                peer.read_exact(&mut frame).unwrap();
+                assert_eq!(&frame[..LEN_SIZE], &(datagram.len() as u16).to_be_bytes());
+            }
+        });
+        let mut buf = [0; 64];
+        receive_until(&mut stream, &mut buf, |s, _| s.write_buf.is_empty()).unwrap();
+        assert!(stream.write_buf.is_empty());
+        reader.join().unwrap();
+
+        let too_long = vec![0; std::u16::MAX as usize + 1];
+        let err = stream.send(&too_long).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn closed_connection_in_middle_of_datagram_is_eof() {
+        let (mut stream, mut peer) = connect();
+        let mut buf = [0; 64];
+
+        peer.write_all(&[0, 1, 9, 0, 10, 1, 2]).unwrap();
+        assert_eq!(receive(&mut stream, &mut buf).unwrap(), Some(1));
+        drop(peer);
+
+        let err = receive(&mut stream, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,63 @@
+use super::Transport;
+use crate::error::Error;
+use crate::net_client::{is_try_again, socket};
+use mio::net::UdpSocket;
+use std::io;
+use std::net::SocketAddr;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer_addr: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn bind(peer_addr: SocketAddr, local_addr: SocketAddr) -> Result<Self, Error> {
+        let socket = socket::bind_udp(&peer_addr, local_addr)?;
+        Ok(Self { socket, peer_addr })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.socket.send_to(datagram, &self.peer_addr)?;
+        Ok(())
+    }
+
+    /// Receives from anyone, the sender is to be checked by the caller
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buf) {
+            Ok((n, from)) => Ok(Some((n, socket::normalize_addr(from)))),
+            Err(ref e) if is_try_again(e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()> {
+        poll.register(
+            &self.socket,
+            token,
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        poll.deregister(&self.socket)
+    }
+
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = addr;
+    }
+
+    fn local_port(&self) -> io::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    fn is_reliable(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,326 @@
+use crate::error::Error;
+use crate::net_client::Pkt;
+use crate::util::byte_reader::ByteReader;
+use log::{info, warn};
+use std::collections::VecDeque;
+
+/// Recovers one lost frame per group from XOR parity packets.
+///
+/// A parity packet is an audio packet with `PKT_FLAG_FEC` set. Its `cnt` is the first cnt of
+/// the protected group, its timestamp is the XOR of the group timestamps and its payload is:
+/// | group len: u8 | XOR of the payload lengths: u16 | XOR of the payloads padded with zeroes |
+pub struct FecDecoder {
+    /// Copies of the recently received frames, in arrival order
+    history: VecDeque<Pkt<'static>>,
+    groups: VecDeque<ParityGroup>,
+}
+
+struct ParityGroup {
+    first_cnt: u32,
+    len: u8,
+    pkt: Pkt<'static>,
+    len_xor: u16,
+}
+
+/// Frames older than that can't be used to recover anything
+const HISTORY_LEN: usize = 64;
+const MAX_PENDING_GROUPS: usize = 16;
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self {
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            groups: VecDeque::new(),
+        }
+    }
+
+    /// Returns a frame recovered with the help of the new one
+    pub fn on_frame(&mut self, pkt: &Pkt) -> Option<Pkt<'static>> {
+        if pkt.is_empty() || self.history.iter().any(|p| p.cnt == pkt.cnt) {
+            return None;
+        }
+
+        self.remember(pkt);
+        let idx = self.groups.iter().position(|g| g.contains(pkt.cnt))?;
+        self.try_recover(idx)
+    }
+
+    /// Returns a frame recovered with the help of the parity packet
+    pub fn on_parity(&mut self, pkt: &Pkt) -> Result<Option<Pkt<'static>>, Error> {
+        let group = ParityGroup::parse(pkt)?;
+        if self.groups.iter().any(|g| g.first_cnt == group.first_cnt) {
+            return Ok(None);
+        }
+
+        if self.groups.len() >= MAX_PENDING_GROUPS {
+            self.groups.pop_front();
+        }
+        self.groups.push_back(group);
+
+        let idx = self.groups.len() - 1;
+        Ok(self.try_recover(idx))
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.groups.clear();
+    }
+
+    fn remember(&mut self, pkt: &Pkt) {
+        let mut copy = if self.history.len() >= HISTORY_LEN {
+            self.history.pop_front().unwrap()
+        } else {
+            Pkt::new_owner(0)
+        };
+        copy.copy_from(pkt);
+        self.history.push_back(copy);
+    }
+
+    fn try_recover(&mut self, idx: usize) -> Option<Pkt<'static>> {
+        let group = &self.groups[idx];
+
+        let mut missing = None;
+        for i in 0..group.len {
+            let cnt = group.first_cnt.wrapping_add(i as u32);
+            if self.history.iter().all(|p| p.cnt != cnt) {
+                if missing.is_some() {
+                    // Can't recover more than one frame, maybe the others arrive later
+                    return None;
+                }
+                missing = Some(cnt);
+            }
+        }
+
+        let group = self.groups.remove(idx).unwrap();
+        let cnt = missing?;
+        let res = self.recover(group, cnt);
+        match res {
+            Ok(pkt) => {
+                info!("Recovered frame {} from parity", cnt);
+                self.remember(&pkt);
+                Some(pkt)
+            }
+            Err(e) => {
+                warn!("Cannot recover frame {}: {}", cnt, e);
+                None
+            }
+        }
+    }
+
+    fn recover(&self, group: ParityGroup, cnt: u32) -> Result<Pkt<'static>, Error> {
+        let ParityGroup {
+            first_cnt,
+            len,
+            mut pkt,
+            len_xor,
+        } = group;
+        let is_member = |p: &&Pkt| p.cnt.wrapping_sub(first_cnt) < len as u32;
+
+        let mut data_len = len_xor;
+        {
+            let data = pkt.data.as_mut().unwrap().to_mut();
+            for member in self.history.iter().filter(is_member) {
+                let member_data = member.data.as_ref().unwrap();
+                if member_data.len() > data.len() {
+                    return Err(Error::new_malformed_pkt(
+                        "Parity is shorter than a protected frame",
+                    ));
+                }
+
+                for (d, m) in data.iter_mut().zip(member_data.iter()) {
+                    *d ^= m;
+                }
+                data_len ^= member_data.len() as u16;
+                pkt.timestamp ^= member.timestamp;
+            }
+
+            if data_len as usize > data.len() {
+                return Err(Error::new_malformed_pkt(
+                    "Recovered length exceeds the parity length",
+                ));
+            }
+            data.truncate(data_len as usize);
+        }
+
+        pkt.cnt = cnt;
+        pkt.flags = 0;
+        Ok(pkt)
+    }
+}
+
+impl ParityGroup {
+    fn parse(pkt: &Pkt) -> Result<Self, Error> {
+        let mut reader = ByteReader::new(pkt.data.as_ref().map_or(&[], |d| d.as_ref()));
+        let len = reader.read_u8()?;
+        let len_xor = reader.read_u16()?;
+        if len == 0 {
+            return Err(Error::new_malformed_pkt("Empty parity group"));
+        }
+
+        let mut copy = Pkt::new_borrower(pkt.cnt, reader.read_rest());
+        copy.codec = pkt.codec;
+        copy.timestamp = pkt.timestamp;
+        let mut owned = Pkt::new_owner(0);
+        owned.copy_from(&copy);
+
+        Ok(Self {
+            first_cnt: pkt.cnt,
+            len,
+            pkt: owned,
+            len_xor,
+        })
+    }
+
+    fn contains(&self, cnt: u32) -> bool {
+        cnt.wrapping_sub(self.first_cnt) < self.len as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(cnt: u32) -> Vec<u8> {
+        vec![cnt as u8; 10 + cnt as usize % 7]
+    }
+
+    fn timestamp(cnt: u32) -> u64 {
+        cnt as u64 * 21333
+    }
+
+    fn frame(cnt: u32, data: &[u8]) -> Pkt<'_> {
+        let mut pkt = Pkt::new_borrower(cnt, data);
+        pkt.timestamp = timestamp(cnt);
+        pkt
+    }
+
+    fn parity(first_cnt: u32, len: u8) -> Vec<u8> {
+        let payloads: Vec<_> = (0..len as u32)
+            .map(|i| payload(first_cnt.wrapping_add(i)))
+            .collect();
+        let max_len = payloads.iter().map(|p| p.len()).max().unwrap();
+
+        let mut len_xor = 0u16;
+        let mut data_xor = vec![0u8; max_len];
+        for p in &payloads {
+            len_xor ^= p.len() as u16;
+            for (d, b) in data_xor.iter_mut().zip(p.iter()) {
+                *d ^= b;
+            }
+        }
+
+        let mut buf = vec![len];
+        buf.extend_from_slice(&len_xor.to_be_bytes());
+        buf.extend_from_slice(&data_xor);
+        buf
+    }
+
+    fn on_parity(fec: &mut FecDecoder, first_cnt: u32, len: u8) -> Option<Pkt<'static>> {
+        let data = parity(first_cnt, len);
+        let mut pkt = Pkt::new_borrower(first_cnt, &data);
+        pkt.timestamp = (0..len as u32).fold(0, |ts, i| ts ^ timestamp(first_cnt.wrapping_add(i)));
+        fec.on_parity(&pkt).unwrap()
+    }
+
+    fn on_frame(fec: &mut FecDecoder, cnt: u32) -> Option<Pkt<'static>> {
+        let data = payload(cnt);
+        fec.on_frame(&frame(cnt, &data))
+    }
+
+    fn assert_recovered(pkt: Option<Pkt>, cnt: u32) {
+        let pkt = pkt.expect("Frame not recovered");
+        assert_eq!(pkt.cnt, cnt);
+        assert_eq!(pkt.flags, 0);
+        assert_eq!(pkt.timestamp, timestamp(cnt));
+        assert_eq!(pkt.data.unwrap().as_ref(), &payload(cnt)[..]);
+    }
+
+    #[test]
+    fn recovers_one_lost_frame_per_group() {
+        let mut fec = FecDecoder::new();
+
+        for lost in 0..4 {
+            let first_cnt = lost * 4;
+            for cnt in (first_cnt..first_cnt + 4).filter(|&cnt| cnt != first_cnt + lost) {
+                assert!(on_frame(&mut fec, cnt).is_none());
+            }
+            assert_recovered(on_parity(&mut fec, first_cnt, 4), first_cnt + lost);
+        }
+    }
+
+    #[test]
+    fn does_not_recover_two_lost_frames() {
+        let mut fec = FecDecoder::new();
+
+        assert!(on_frame(&mut fec, 0).is_none());
+        assert!(on_frame(&mut fec, 3).is_none());
+        assert!(on_parity(&mut fec, 0, 4).is_none());
+        assert!(on_frame(&mut fec, 4).is_none());
+        assert!(on_frame(&mut fec, 7).is_none());
+    }
+
+    #[test]
+    fn recovers_with_parity_received_before_frames() {
+        let mut fec = FecDecoder::new();
+
+        assert!(on_parity(&mut fec, 0, 3).is_none());
+        assert!(on_frame(&mut fec, 0).is_none());
+        assert_recovered(on_frame(&mut fec, 2), 1);
+
+        // The recovered frame is a known one now
+        assert!(on_frame(&mut fec, 1).is_none());
+    }
+
+    #[test]
+    fn recovers_across_cnt_wraparound() {
+        let mut fec = FecDecoder::new();
+        let first_cnt = std::u32::MAX - 1;
+
+        assert!(on_frame(&mut fec, first_cnt).is_none());
+        assert!(on_frame(&mut fec, 0).is_none());
+        assert_recovered(on_parity(&mut fec, first_cnt, 3), std::u32::MAX);
+    }
+
+    #[test]
+    fn rejects_malformed_parity() {
+        let mut fec = FecDecoder::new();
+
+        for data in &[&[][..], &[1, 0][..], &[0, 0, 0, 1][..]] {
+            let pkt = Pkt::new_borrower(0, data);
+            assert!(fec.on_parity(&pkt).is_err());
+        }
+
+        // Shorter than the protected frame
+        let pkt = Pkt::new_borrower(0, &[2, 0, 0, 0]);
+        assert!(fec.on_parity(&pkt).unwrap().is_none());
+        assert!(on_frame(&mut fec, 0).is_none());
+    }
+
+    #[test]
+    fn drops_oldest_pending_group() {
+        for &(extra_groups, is_recovered) in
+            &[(MAX_PENDING_GROUPS - 1, true), (MAX_PENDING_GROUPS, false)]
+        {
+            let mut fec = FecDecoder::new();
+            assert!(on_parity(&mut fec, 0, 2).is_none());
+            for i in 0..extra_groups as u32 {
+                assert!(on_parity(&mut fec, 100 + i * 2, 2).is_none());
+            }
+
+            assert_eq!(on_frame(&mut fec, 0).is_some(), is_recovered);
+        }
+    }
+
+    #[test]
+    fn forgets_frames_beyond_history() {
+        for &(later_frames, is_recovered) in &[(HISTORY_LEN - 1, true), (HISTORY_LEN, false)] {
+            let mut fec = FecDecoder::new();
+            assert!(on_frame(&mut fec, 0).is_none());
+            for i in 0..later_frames as u32 {
+                assert!(on_frame(&mut fec, 1000 + i).is_none());
+            }
+
+            assert_eq!(on_parity(&mut fec, 0, 2).is_some(), is_recovered);
+        }
+    }
+}
@@ -1,10 +1,13 @@
+mod fec;
 mod output_buffer;
 
-use self::output_buffer::{OutputBuffer, PostWriteAction};
+pub use self::output_buffer::BufferStats;
+pub use self::output_buffer::OutputBuffer;
+pub use self::output_buffer::PostWriteAction;
 use crate::android_audio::{self, AudioPlayer, Engine, OutputMix};
 use crate::error::Error;
 use crate::jni_ffi::ToJavaMsg;
-use crate::net_client::Pkt;
+use crate::net_client::{Pkt, StreamInfo};
 use log::{info, warn};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
@@ -12,8 +15,8 @@ use std::time::Duration;
 #[derive(Clone)]
 pub struct Player {
     player: Arc<Mutex<AudioPlayer>>,
-    _mix: Arc<Mutex<OutputMix>>,
-    _engine: Arc<Mutex<Engine>>,
+    mix: Arc<Mutex<OutputMix>>,
+    engine: Arc<Mutex<Engine>>,
     buffer: Arc<Mutex<OutputBuffer>>,
 }
 
@@ -25,15 +28,45 @@ impl Player {
         let output_mix = engine.create_output_mix()?;
         output_mix.realize()?;
 
-        let settings = android_audio::Settings {
-            rate: android_audio::SampleRate::Rate44100,
-            format: android_audio::SampleFormat::S16LE,
-        };
+        let info = StreamInfo::default();
+        let settings = settings_for(&info)?;
 
         let player = engine.create_buffer_player(&output_mix, settings.clone())?;
         player.realize()?;
 
-        Self::construct(to_java_send, settings, engine, output_mix, player)
+        Self::construct(to_java_send, settings, &info, engine, output_mix, player)
+    }
+
+    /// Adjusts the decoder and the audio output to the stream announced by the server
+    pub fn configure(&self, info: &StreamInfo) -> Result<(), Error> {
+        info!("Configuring player for: {:?}", info);
+        let settings = settings_for(info)?;
+
+        {
+            let mut buffer = self.buffer.lock()?;
+            buffer.configure(settings.clone(), info)?;
+        }
+
+        let is_same_output = self.player.lock()?.get_settings() == &settings;
+        if is_same_output {
+            return Ok(());
+        }
+
+        let mut player = {
+            let engine = self.engine.lock()?;
+            let mix = self.mix.lock()?;
+            engine.create_buffer_player(&mix, settings)?
+        };
+        player.realize()?;
+
+        let cb_buffer = self.buffer.clone();
+        player.register_callback(move |to| Player::on_read(&cb_buffer, to))?;
+
+        let old_player = std::mem::replace(&mut *self.player.lock()?, player);
+        // Dropped outside of the lock, as destroying may wait for the running callback
+        drop(old_player);
+
+        Ok(())
     }
 
     pub fn start_playing(&self) -> Result<(), Error> {
@@ -84,6 +117,24 @@ impl Player {
         buffer.unfix_delay();
     }
 
+    /// Collects the lost frames that can still be played if they arrive in `min_lead`.
+    /// Returns the jitter buffer depth in frames
+    pub fn collect_missing(&self, min_lead: Duration, to: &mut Vec<u32>) -> usize {
+        let buffer = self.buffer.lock().unwrap();
+        buffer.collect_missing(min_lead, to)
+    }
+
+    pub fn get_stats(&self) -> BufferStats {
+        let buffer = self.buffer.lock().unwrap();
+        buffer.get_stats()
+    }
+
+    /// Drops all the buffered audio, the playback restarts with the next enqueued packet
+    pub fn flush(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.flush();
+    }
+
     pub fn enqueue(&self, pkt: &Pkt) -> Result<(), Error> {
         let mut buffer = self.buffer.lock().unwrap();
 
@@ -104,6 +155,14 @@ impl Player {
         Ok(())
     }
 
+    /// Plays the next buffer, as OpenSL ES would call the callback
+    #[cfg(test)]
+    pub fn pull(&self) -> Result<Vec<u8>, Error> {
+        // Released first, `enqueue` locks the buffer and then the player
+        let callback = self.player.lock()?.get_callback();
+        callback.pull()
+    }
+
     fn on_read(output_buffer: &Arc<Mutex<OutputBuffer>>, to: &mut Vec<u8>) -> Result<usize, Error> {
         let mut output_buffer = output_buffer.lock()?;
 
@@ -115,19 +174,20 @@ impl Player {
     fn construct(
         to_java_send: mpsc::Sender<ToJavaMsg>,
         settings: android_audio::Settings,
+        info: &StreamInfo,
         engine: Engine,
         mix: OutputMix,
         mut player: AudioPlayer,
     ) -> Result<Self, Error> {
-        let buffer = Arc::new(Mutex::new(OutputBuffer::new(to_java_send, settings)?));
+        let buffer = Arc::new(Mutex::new(OutputBuffer::new(to_java_send, settings, info)?));
 
         let cb_buffer = buffer.clone();
         player.register_callback(move |to| Player::on_read(&cb_buffer, to))?;
 
         Ok(Self {
             player: Arc::new(Mutex::new(player)),
-            _mix: Arc::new(Mutex::new(mix)),
-            _engine: Arc::new(Mutex::new(engine)),
+            mix: Arc::new(Mutex::new(mix)),
+            engine: Arc::new(Mutex::new(engine)),
             buffer,
         })
     }
@@ -141,3 +201,15 @@ impl Drop for Player {
         }
     }
 }
+
+fn settings_for(info: &StreamInfo) -> Result<android_audio::Settings, Error> {
+    let rate = android_audio::SampleRate::from_hz(info.sample_rate).ok_or_else(|| {
+        Error::new_wrong_argument(format!("Unsupported sample rate: {}", info.sample_rate))
+    })?;
+
+    Ok(android_audio::Settings {
+        rate,
+        format: android_audio::SampleFormat::S16LE,
+        channels: info.channels,
+    })
+}
@@ -1,7 +1,9 @@
+use super::fec::FecDecoder;
 use crate::android_audio;
 use crate::error::Error;
 use crate::jni_ffi::ToJavaMsg;
-use crate::net_client::Pkt;
+use crate::net_client::{Codec, Pkt, StreamInfo};
+use crate::util::serial;
 use crate::util::window_avg_calc::WindowAvgCalc;
 use log::{error, info, warn};
 use std::collections::VecDeque;
@@ -16,7 +18,15 @@ pub struct OutputBuffer {
     /// The packets qty to wait before start playing again
     que_packets: usize,
     is_first_packet: bool,
-    total_missing: usize,
+    fec: FecDecoder,
+    /// Lost frames rebuilt from the parity before they were due
+    total_recovered: usize,
+    /// Lost frames replaced by replaying the last played one
+    total_concealed: usize,
+    /// Frames dropped unplayed because `to_send` was full
+    total_overflowed: usize,
+    /// Frames arrived after their turn to be played
+    total_late: usize,
 
     to_java_send: mpsc::Sender<ToJavaMsg>,
     decoder: AudioDecoder,
@@ -26,6 +36,35 @@ pub struct OutputBuffer {
     delay_went_over_small_margin: DelayWentOverSmallMargin,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct BufferStats {
+    pub late: usize,
+    pub recovered: usize,
+    pub concealed: usize,
+    pub overflowed: usize,
+    pub memory: MemoryUsage,
+}
+
+/// The audio data held by the buffer, the frames of `to_send` and the recycled ones
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryUsage {
+    pub buffered_frames: usize,
+    pub free_frames: usize,
+    pub bytes: usize,
+}
+
+impl std::fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "{} buffered, {} free frames, {} KiB",
+            self.buffered_frames,
+            self.free_frames,
+            self.bytes / 1024
+        )
+    }
+}
+
 #[must_use]
 pub enum PostWriteAction {
     Nothing,
@@ -40,8 +79,8 @@ struct Frame {
 struct AudioDecoder {
     resampler: ffmpeg::Resampler,
     decoder: ffmpeg::Decoder,
-    settings: android_audio::Settings,
-    frame_duration: Option<Duration>,
+    codec: Codec,
+    frame_duration: Duration,
 }
 
 enum DelayWentOverSmallMargin {
@@ -51,6 +90,17 @@ enum DelayWentOverSmallMargin {
 }
 
 const JITTER_BUFFER_LEN: usize = 3;
+/// A bigger jump of `cnt` either way means the server has restarted the stream
+const MAX_CNT_JUMP: u32 = 512;
+/// Overflow policy: the oldest frames are dropped to keep `to_send` within this size.
+/// The primary frames restart the stream on a gap over `MAX_CNT_JUMP` before it's reached,
+/// only a frame recovered from the parity may come after a bigger one, see `append_block`
+const MAX_BUFFERED_FRAMES: usize = 1024;
+/// The frames of the supported codecs and sample rates fit this range
+const MIN_FRAME_DURATION: Duration = Duration::from_millis(2);
+const MAX_FRAME_DURATION: Duration = Duration::from_millis(150);
+/// Frames recycled over this qty are deallocated
+const MAX_FREE_FRAMES: usize = 64;
 const AVG_OVER: usize = 50;
 const DELAY_CHANGE: Duration = Duration::from_millis(50);
 const FIX_DELAY_SMALL_MARGIN: Duration = Duration::from_millis(50);
@@ -61,6 +111,7 @@ impl OutputBuffer {
     pub fn new(
         to_java_send: mpsc::Sender<ToJavaMsg>,
         settings: android_audio::Settings,
+        info: &StreamInfo,
     ) -> Result<Self, Error> {
         Ok(Self {
             to_send: VecDeque::new(),
@@ -70,27 +121,66 @@ impl OutputBuffer {
             is_first_packet: true,
             avg_to_send_delay: WindowAvgCalc::new(AVG_OVER).unwrap(),
             to_java_send,
-            decoder: AudioDecoder::new(settings)?,
-            total_missing: 0,
+            decoder: AudioDecoder::new(settings, info)?,
+            fec: FecDecoder::new(),
+            total_recovered: 0,
+            total_concealed: 0,
+            total_overflowed: 0,
+            total_late: 0,
             delay_fixed_at: None,
             delay_went_over_small_margin: DelayWentOverSmallMargin::None,
         })
     }
 
+    pub fn configure(
+        &mut self,
+        settings: android_audio::Settings,
+        info: &StreamInfo,
+    ) -> Result<(), Error> {
+        self.decoder = AudioDecoder::new(settings, info)?;
+        Ok(())
+    }
+
     pub fn write(&mut self, pkt: &Pkt) -> PostWriteAction {
+        // The decoder is switched by `configure` only, not by a stray packet
+        if pkt.codec != self.decoder.codec {
+            warn!(
+                "Dropping packet {} of {:?}, the stream is {:?}",
+                pkt.cnt, pkt.codec, self.decoder.codec
+            );
+            return PostWriteAction::Nothing;
+        }
+
+        if pkt.is_fec() {
+            return self.write_parity(pkt);
+        }
+
+        let recovered = self.fec.on_frame(pkt);
+        if pkt.is_redundant() {
+            self.write_redundant(pkt);
+            if let Some(recovered) = recovered {
+                self.add_recovered(&recovered);
+            }
+            return PostWriteAction::Nothing;
+        }
+
+        if self.is_stream_restarted(pkt.cnt) {
+            self.restart_stream(pkt.cnt);
+        }
+
         let block = if pkt.is_empty() {
             warn!("Adding empty packet to buffer");
             Frame::new_empty(pkt.cnt)
         } else {
-            let mut block = match self.free.pop() {
-                Some(block) => block,
-                None => Frame::new(),
-            };
-            block.copy_from_pkt(pkt);
-            block
+            self.new_block(pkt)
         };
 
-        self.add_block(block);
+        if !self.add_block(block) && self.is_late(pkt.cnt) {
+            self.total_late += 1;
+        }
+        if let Some(recovered) = recovered {
+            self.add_recovered(&recovered);
+        }
         self.choose_post_write_action()
     }
 
@@ -123,13 +213,67 @@ impl OutputBuffer {
         self.decoder.decode(&block, to)?;
 
         if let Some(last_played) = self.last_played.take() {
-            self.free.push(last_played);
+            self.recycle(last_played);
         }
 
         self.last_played = Some(block);
         Ok(true)
     }
 
+    pub fn flush(&mut self) {
+        info!("Flushing {} packets", self.to_send.len());
+        while let Some(block) = self.to_send.pop_front() {
+            self.recycle(block);
+        }
+        if let Some(last_played) = self.last_played.take() {
+            self.recycle(last_played);
+        }
+
+        self.que_packets = 0;
+        self.is_first_packet = true;
+        self.fec.reset();
+    }
+
+    /// Collects the missing frames which would be played not sooner than `min_lead`.
+    /// Returns the jitter buffer depth in frames
+    pub fn collect_missing(&self, min_lead: Duration, to: &mut Vec<u32>) -> usize {
+        to.clear();
+
+        let frame_duration = self.decoder.get_frame_duration();
+
+        // Frames before the block at `idx` and the queued ones are played first
+        let lead = |idx: usize| frame_duration * (idx + self.que_packets) as u32;
+        for (idx, block) in self.to_send.iter().enumerate() {
+            if block.is_empty() && lead(idx) >= min_lead {
+                to.push(block.get_cnt());
+            }
+        }
+
+        self.to_send.len()
+    }
+
+    pub fn get_stats(&self) -> BufferStats {
+        BufferStats {
+            late: self.total_late,
+            recovered: self.total_recovered,
+            concealed: self.total_concealed,
+            overflowed: self.total_overflowed,
+            memory: self.get_memory_usage(),
+        }
+    }
+
+    pub fn get_memory_usage(&self) -> MemoryUsage {
+        let to_send_bytes: usize = self.to_send.iter().map(Frame::mem_size).sum();
+        let free_bytes: usize = self.free.iter().map(Frame::mem_size).sum();
+        let last_played_bytes = self.last_played.as_ref().map_or(0, Frame::mem_size);
+
+        MemoryUsage {
+            buffered_frames: self.to_send.len(),
+            free_frames: self.free.len(),
+            bytes: to_send_bytes + free_bytes + last_played_bytes,
+        }
+    }
+
     pub fn get_avg_delay(&self) -> Duration {
         self.avg_to_send_delay.get_avg()
     }
@@ -138,12 +282,7 @@ impl OutputBuffer {
     pub fn increase_delay(&mut self) -> Duration {
         info!("Increasing delay");
         let cur_delay = self.get_avg_delay();
-        let frame_duration = match self.decoder.get_frame_duration() {
-            None => {
-                return cur_delay;
-            }
-            Some(frame_duration) => frame_duration,
-        };
+        let frame_duration = self.decoder.get_frame_duration();
 
         let frames_to_add = (DELAY_CHANGE.as_micros() / frame_duration.as_micros()) as u32;
         self.que_packets += frames_to_add as usize;
@@ -158,12 +297,7 @@ impl OutputBuffer {
     pub fn decrease_delay(&mut self) -> Duration {
         info!("Decreasing delay");
         let cur_delay = self.get_avg_delay();
-        let frame_duration = match self.decoder.get_frame_duration() {
-            None => {
-                return cur_delay;
-            }
-            Some(frame_duration) => frame_duration,
-        };
+        let frame_duration = self.decoder.get_frame_duration();
 
         let mut frames_to_remove = (DELAY_CHANGE.as_micros() / frame_duration.as_micros()) as usize;
 
@@ -192,37 +326,165 @@ impl OutputBuffer {
         self.delay_fixed_at = None;
     }
 
-    fn add_block(&mut self, block: Frame) {
-        if self.to_send.is_empty() {
-            self.to_send.push_back(block);
+    fn write_parity(&mut self, pkt: &Pkt) -> PostWriteAction {
+        let res = self.fec.on_parity(pkt);
+        match res {
+            Ok(Some(recovered)) => {
+                if self.add_recovered(&recovered) {
+                    return self.choose_post_write_action();
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Error parsing parity packet {}: {}", pkt.cnt, e),
+        }
+
+        PostWriteAction::Nothing
+    }
+
+    /// A redundant copy is used only to fill a gap, the frames after it are still to come
+    fn write_redundant(&mut self, pkt: &Pkt) {
+        let is_waiting = match (self.to_send.front(), self.to_send.back()) {
+            (Some(first), Some(last)) => {
+                !serial::is_newer(first.get_cnt(), pkt.cnt)
+                    && !serial::is_newer(pkt.cnt, last.get_cnt())
+            }
+            _ => false,
+        };
+        if !is_waiting {
             return;
         }
 
+        let block = self.new_block(pkt);
+        if self.add_block(block) {
+            info!("Block {} is restored from a redundant copy", pkt.cnt);
+        }
+    }
+
+    /// Returns true if the recovered frame arrived in time to be played
+    fn add_recovered(&mut self, pkt: &Pkt) -> bool {
+        let block = self.new_block(pkt);
+
+        let is_added = self.add_block(block);
+        if is_added {
+            self.total_recovered += 1;
+            info!(
+                "Block {} is recovered. Total recovered: {}",
+                pkt.cnt, self.total_recovered
+            );
+        }
+        is_added
+    }
+
+    fn new_block(&mut self, pkt: &Pkt) -> Frame {
+        let mut block = match self.free.pop() {
+            Some(block) => block,
+            None => Frame::new(),
+        };
+        block.copy_from_pkt(pkt);
+        block
+    }
+
+    /// Returns false if the block came too late or duplicates an existing one,
+    /// it's recycled then
+    fn add_block(&mut self, block: Frame) -> bool {
         let new_cnt = block.data.cnt;
+        if self.to_send.is_empty() {
+            if self.is_late(new_cnt) {
+                self.recycle(block);
+                return false;
+            }
+
+            self.to_send.push_back(block);
+            return true;
+        }
+
         let first_cnt = self.to_send.front().unwrap().data.cnt;
         let last_cnt = self.to_send.back().unwrap().data.cnt;
 
-        if last_cnt < new_cnt {
+        if serial::is_newer(new_cnt, last_cnt) {
             self.append_block(block, last_cnt);
-            return;
-        } else if new_cnt < first_cnt {
-            return;
-        } else if block.is_empty() {
-            return;
+            return true;
+        } else if serial::is_newer(first_cnt, new_cnt) || block.is_empty() {
+            self.recycle(block);
+            return false;
         }
 
-        let idx = (new_cnt - first_cnt) as usize;
-        if self.to_send[idx].is_empty() {
+        // `to_send` should hold consecutive cnts from `first_cnt`, never index past its end
+        let idx = new_cnt.wrapping_sub(first_cnt) as usize;
+        if idx < self.to_send.len() && self.to_send[idx].is_empty() {
             self.to_send[idx] = block;
+            true
+        } else {
+            self.recycle(block);
+            false
+        }
+    }
+
+    /// The frame's turn to be played has passed
+    fn is_late(&self, cnt: u32) -> bool {
+        match (self.to_send.front(), &self.last_played) {
+            (Some(first), _) => serial::is_newer(first.get_cnt(), cnt),
+            (None, Some(last_played)) => !serial::is_newer(cnt, last_played.get_cnt()),
+            (None, None) => false,
         }
     }
 
     fn append_block(&mut self, block: Frame, last_cnt: u32) {
-        let new_cnt = block.data.cnt;
-        for cnt in (last_cnt + 1)..new_cnt {
-            self.to_send.push_back(Frame::new_empty(cnt))
+        let gap = serial::diff(block.data.cnt, last_cnt) as u32;
+        if gap as usize >= MAX_BUFFERED_FRAMES {
+            // Only a recovered frame gets here, `is_stream_restarted` isn't checked for it.
+            // All the buffered frames would be dropped anyway
+            self.drop_oldest(self.to_send.len());
+        } else {
+            for i in 1..gap {
+                self.to_send
+                    .push_back(Frame::new_empty(last_cnt.wrapping_add(i)))
+            }
         }
         self.to_send.push_back(block);
+
+        if self.to_send.len() > MAX_BUFFERED_FRAMES {
+            self.drop_oldest(self.to_send.len() - MAX_BUFFERED_FRAMES);
+        }
+    }
+
+    fn drop_oldest(&mut self, qty: usize) {
+        for _ in 0..qty {
+            if let Some(block) = self.to_send.pop_front() {
+                self.recycle(block);
+            }
+        }
+
+        self.total_overflowed += qty;
+        warn!(
+            "Buffer overflow, dropped {} frames. Total dropped: {}, memory usage: {}",
+            qty,
+            self.total_overflowed,
+            self.get_memory_usage()
+        );
+    }
+
+    fn recycle(&mut self, block: Frame) {
+        if !block.is_empty() && self.free.len() < MAX_FREE_FRAMES {
+            self.free.push(block);
+        }
+    }
+
+    /// Compares with the newest frame seen, `to_send` may be empty if the buffer has run dry
+    fn is_stream_restarted(&self, new_cnt: u32) -> bool {
+        let last = self.to_send.back().or(self.last_played.as_ref());
+        match last {
+            // A jump of exactly 2^31 is `i32::MIN`, its absolute value doesn't fit `i32`
+            Some(last) => serial::diff(new_cnt, last.get_cnt()).unsigned_abs() > MAX_CNT_JUMP,
+            None => false,
+        }
+    }
+
+    fn restart_stream(&mut self, new_cnt: u32) {
+        warn!("Stream restarted at cnt {}, flushing", new_cnt);
+
+        self.flush();
+        log_and_ignore_err!(self.decoder.reset(), "resetting decoder on stream restart");
     }
 
     fn choose_post_write_action(&mut self) -> PostWriteAction {
@@ -298,11 +560,12 @@ impl OutputBuffer {
     }
 
     fn read_empty_block(&mut self, block: &Frame, to: &mut Vec<u8>) -> Result<bool, Error> {
-        self.total_missing += 1;
+        self.total_concealed += 1;
         warn!(
-            "Block {} is missing. Total missing: {}",
+            "Block {} is missing. Total concealed: {}, recovered: {}",
             block.get_cnt(),
-            self.total_missing
+            self.total_concealed,
+            self.total_recovered
         );
         self.read_last_played_block(to)
     }
@@ -315,7 +578,8 @@ impl OutputBuffer {
             }
             None => {
                 error!("No Last Packet");
-                self.is_first_packet = false;
+                // Nothing is enqueued, so the playback is restarted by the next write
+                self.is_first_packet = true;
                 Ok(false)
             }
         }
@@ -355,34 +619,39 @@ impl Frame {
         self.data.copy_to_vec(to)
     }
 
-    #[allow(dead_code)]
     fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// Approximate, the payload capacity may exceed its length
+    fn mem_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.len()
+    }
+
     fn elapsed(&self) -> Duration {
         self.created.elapsed()
     }
 }
 
 impl AudioDecoder {
-    fn new(settings: android_audio::Settings) -> Result<Self, Error> {
+    fn new(settings: android_audio::Settings, info: &StreamInfo) -> Result<Self, Error> {
         let from_params = ffmpeg::AudioParams {
-            rate: 44100,
+            rate: info.sample_rate as _,
             format: ffmpeg::AudioSampleFormat::FloatLe,
         };
         let to_params = ffmpeg::AudioParams {
-            rate: 44100,
+            rate: settings.rate.to_hz() as _,
             format: ffmpeg::AudioSampleFormat::S16Le,
         };
+        let frame_duration = calc_frame_duration(info)?;
         let resampler = ffmpeg::Resampler::new(from_params, to_params)?;
-        let decoder = ffmpeg::Decoder::new(ffmpeg::Codec::Aac)?;
+        let decoder = ffmpeg::Decoder::new(to_ffmpeg_codec(info.codec)?)?;
 
         Ok(Self {
             resampler,
             decoder,
-            settings,
-            frame_duration: None,
+            codec: info.codec,
+            frame_duration,
         })
     }
 
@@ -396,24 +665,194 @@ impl AudioDecoder {
             to.extend_from_slice(data);
         }
 
-        if self.frame_duration.is_none() {
-            self.frame_duration = Some(self.calc_pkt_duration(to.len()));
-        }
+        Ok(())
+    }
 
+    /// Drops the decoder state left from the previous stream
+    fn reset(&mut self) -> Result<(), Error> {
+        self.decoder = ffmpeg::Decoder::new(to_ffmpeg_codec(self.codec)?)?;
         Ok(())
     }
 
-    fn get_frame_duration(&self) -> Option<Duration> {
+    fn get_frame_duration(&self) -> Duration {
         self.frame_duration
     }
+}
+
+/// The stream info comes from the network, a zero or absurd frame size is rejected
+fn calc_frame_duration(info: &StreamInfo) -> Result<Duration, Error> {
+    let micros = match info.sample_rate {
+        0 => std::u64::MAX,
+        rate => info.frame_size as u64 * 1000000 / rate as u64,
+    };
+    let duration = Duration::from_micros(micros);
+    if duration < MIN_FRAME_DURATION || duration > MAX_FRAME_DURATION {
+        return Err(Error::new_wrong_frame_duration(duration));
+    }
+
+    Ok(duration)
+}
+
+fn to_ffmpeg_codec(codec: Codec) -> Result<ffmpeg::Codec, Error> {
+    match codec {
+        Codec::Aac => Ok(ffmpeg::Codec::Aac),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_info(frame_size: u16, sample_rate: u32) -> StreamInfo {
+        StreamInfo {
+            frame_size,
+            sample_rate,
+            ..StreamInfo::default()
+        }
+    }
+
+    fn new_buffer() -> OutputBuffer {
+        let (to_java_send, _) = mpsc::channel();
+        let settings = android_audio::Settings {
+            rate: android_audio::SampleRate::Rate44100,
+            format: android_audio::SampleFormat::S16LE,
+            channels: 2,
+        };
+        OutputBuffer::new(to_java_send, settings, &StreamInfo::default()).unwrap()
+    }
+
+    fn write(buffer: &mut OutputBuffer, cnt: u32) {
+        let data = [0x5a; 200];
+        let _ = buffer.write(&Pkt::new_borrower(cnt, &data));
+    }
+
+    fn buffered_cnts(buffer: &OutputBuffer) -> Vec<u32> {
+        buffer.to_send.iter().map(Frame::get_cnt).collect()
+    }
+
+    #[test]
+    fn continues_stream_across_cnt_wraparound() {
+        let mut buffer = new_buffer();
+        let max = std::u32::MAX;
+
+        for &cnt in &[max - 1, max, 1] {
+            write(&mut buffer, cnt);
+        }
+        assert_eq!(buffered_cnts(&buffer), [max - 1, max, 0, 1]);
+        assert!(buffer.to_send[2].is_empty());
+
+        write(&mut buffer, 0);
+        assert!(!buffer.to_send[2].is_empty());
+        write(&mut buffer, max - 2);
+        assert_eq!(buffered_cnts(&buffer), [max - 1, max, 0, 1]);
+        assert_eq!(buffer.get_stats().late, 1);
+    }
+
+    #[test]
+    fn restarts_stream_on_cnt_jump_over_max() {
+        let start = std::u32::MAX - 100;
+
+        let mut buffer = new_buffer();
+        write(&mut buffer, start);
+        write(&mut buffer, start.wrapping_add(MAX_CNT_JUMP));
+        assert_eq!(buffer.to_send.len(), MAX_CNT_JUMP as usize + 1);
+        assert_eq!(buffer.to_send[0].get_cnt(), start);
+
+        let mut buffer = new_buffer();
+        write(&mut buffer, start);
+        write(&mut buffer, start.wrapping_add(MAX_CNT_JUMP + 1));
+        assert_eq!(
+            buffered_cnts(&buffer),
+            [start.wrapping_add(MAX_CNT_JUMP + 1)]
+        );
+    }
+
+    #[test]
+    fn restarts_stream_on_cnt_jump_back() {
+        let mut buffer = new_buffer();
+        write(&mut buffer, 1000);
+        write(&mut buffer, 1000 - MAX_CNT_JUMP);
+        assert_eq!(buffered_cnts(&buffer), [1000]);
+        assert_eq!(buffer.get_stats().late, 1);
+
+        write(&mut buffer, 1000 - MAX_CNT_JUMP - 1);
+        assert_eq!(buffered_cnts(&buffer), [1000 - MAX_CNT_JUMP - 1]);
+    }
 
-    fn calc_pkt_duration(&self, bytes: usize) -> Duration {
-        let samples = bytes / self.settings.format.get_sample_size() / 2;
-        let rate = self.settings.rate.to_hz();
+    #[test]
+    fn restarts_stream_on_half_range_jump() {
+        // The jump is as far forward as backward, neither cnt is newer
+        for &cnt in &[0, 1 << 31] {
+            let mut buffer = new_buffer();
+            write(&mut buffer, cnt);
+            write(&mut buffer, cnt.wrapping_add(1 << 31));
+            assert_eq!(buffered_cnts(&buffer), [cnt.wrapping_add(1 << 31)]);
+        }
+    }
+
+    #[test]
+    fn drops_oldest_frames_on_overflow() {
+        let mut buffer = new_buffer();
+        let total = 3 * MAX_BUFFERED_FRAMES as u32;
+
+        for cnt in 0..total {
+            write(&mut buffer, cnt);
+        }
+
+        let stats = buffer.get_stats();
+        assert_eq!(stats.overflowed, total as usize - MAX_BUFFERED_FRAMES);
+        assert_eq!(stats.memory.buffered_frames, MAX_BUFFERED_FRAMES);
+        assert!(stats.memory.free_frames <= MAX_FREE_FRAMES);
+        assert_eq!(
+            buffer.to_send.front().unwrap().get_cnt(),
+            total - MAX_BUFFERED_FRAMES as u32
+        );
+
+        let max_frames = MAX_BUFFERED_FRAMES + MAX_FREE_FRAMES + 1;
+        let max_frame_size = std::mem::size_of::<Frame>() + 200;
+        assert!(stats.memory.bytes <= max_frames * max_frame_size);
+    }
+
+    #[test]
+    fn recovered_frame_far_ahead_replaces_buffered_ones() {
+        let mut buffer = new_buffer();
+        for cnt in 0..10 {
+            write(&mut buffer, cnt);
+        }
+
+        let data = [0x5a; 200];
+        let far_ahead = 9 + MAX_BUFFERED_FRAMES as u32;
+        assert!(buffer.add_recovered(&Pkt::new_borrower(far_ahead, &data)));
+
+        assert_eq!(buffered_cnts(&buffer), [far_ahead]);
+        assert_eq!(buffer.get_stats().overflowed, 10);
+    }
+
+    #[test]
+    fn keeps_limited_free_frames() {
+        let mut buffer = new_buffer();
+        for cnt in 0..MAX_FREE_FRAMES as u32 * 2 {
+            write(&mut buffer, cnt);
+        }
+
+        buffer.flush();
+        let memory = buffer.get_memory_usage();
+        assert_eq!(memory.buffered_frames, 0);
+        assert_eq!(memory.free_frames, MAX_FREE_FRAMES);
+
+        // The recycled frames are reused
+        write(&mut buffer, 0);
+        assert_eq!(buffer.get_memory_usage().free_frames, MAX_FREE_FRAMES - 1);
+    }
 
-        let micros = (samples as f64 / rate as f64) * 1000000.;
-        assert!(15000. <= micros && micros <= 43000.);
+    #[test]
+    fn rejects_frame_duration_out_of_range() {
+        let duration = calc_frame_duration(&stream_info(1024, 48000)).unwrap();
+        assert_eq!(duration, Duration::from_micros(21333));
+        assert!(calc_frame_duration(&stream_info(2048, 44100)).is_ok());
 
-        Duration::from_micros(micros as u64)
+        assert!(calc_frame_duration(&stream_info(0, 48000)).is_err());
+        assert!(calc_frame_duration(&stream_info(1024, 0)).is_err());
+        assert!(calc_frame_duration(&stream_info(std::u16::MAX, 8000)).is_err());
     }
 }
@@ -0,0 +1,60 @@
+use crate::error::Error;
+use std::convert::TryInto;
+
+/// Bounds-checked big-endian reader over a received datagram.
+/// Every read returns `ErrorRepr::MalformedPkt` instead of panicking if the buffer is too short.
+pub struct ByteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < n {
+            return Err(Error::new_malformed_pkt(format!(
+                "Cannot read {} bytes at offset {}, packet length is {}",
+                n,
+                self.pos,
+                self.buf.len()
+            )));
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// Consumes and returns everything that is left
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+}
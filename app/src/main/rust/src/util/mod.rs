@@ -1,2 +1,5 @@
+pub mod byte_reader;
 pub mod interval_measure;
+pub mod serial;
+pub mod stopper;
 pub mod window_avg_calc;
@@ -0,0 +1,41 @@
+//! Serial number arithmetic (RFC 1982) for the wrapping `u32` counters
+
+/// The signed distance from `b` to `a`, positive if `a` is newer.
+/// Valid while the counters are less than 2^31 apart.
+pub fn diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+pub fn is_newer(a: u32, b: u32) -> bool {
+    diff(a, b) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF: u32 = 1 << 31;
+
+    #[test]
+    fn compares_across_wraparound() {
+        assert!(is_newer(0, std::u32::MAX));
+        assert!(!is_newer(std::u32::MAX, 0));
+        assert_eq!(diff(0, std::u32::MAX), 1);
+        assert_eq!(diff(std::u32::MAX, 0), -1);
+        assert_eq!(diff(5, std::u32::MAX - 4), 10);
+        assert!(!is_newer(7, 7));
+    }
+
+    #[test]
+    fn half_range_apart_is_ambiguous() {
+        assert!(is_newer(HALF - 1, 0));
+        assert_eq!(diff(HALF - 1, 0), std::i32::MAX);
+        assert!(is_newer(0, HALF + 1));
+
+        // Neither is newer, the distance is `i32::MIN` both ways
+        assert_eq!(diff(HALF, 0), std::i32::MIN);
+        assert_eq!(diff(0, HALF), std::i32::MIN);
+        assert!(!is_newer(HALF, 0));
+        assert!(!is_newer(0, HALF));
+    }
+}
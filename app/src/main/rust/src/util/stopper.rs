@@ -0,0 +1,72 @@
+use crate::error::Error;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Registered in a `mio::Poll` running in another thread,
+/// becomes readable once `StopHandle::stop` is called
+pub struct Stopper {
+    registration: mio::Registration,
+    flag: Arc<AtomicBool>,
+}
+
+pub struct StopHandle {
+    set_readiness: mio::SetReadiness,
+    flag: Arc<AtomicBool>,
+}
+
+impl Stopper {
+    pub fn new() -> (Self, StopHandle) {
+        let (registration, set_readiness) = mio::Registration::new2();
+        let flag = Arc::new(AtomicBool::new(false));
+
+        let stopper = Self {
+            registration,
+            flag: flag.clone(),
+        };
+        let handle = StopHandle {
+            set_readiness,
+            flag,
+        };
+        (stopper, handle)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+impl StopHandle {
+    pub fn stop(&self) -> Result<(), Error> {
+        self.flag.store(true, Ordering::SeqCst);
+        self.set_readiness.set_readiness(mio::Ready::readable())?;
+        Ok(())
+    }
+}
+
+impl mio::Evented for Stopper {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> Result<(), std::io::Error> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> Result<(), std::io::Error> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> Result<(), std::io::Error> {
+        poll.deregister(&self.registration)
+    }
+}